@@ -14,27 +14,33 @@ WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 See the License for the specific language governing permissions and
 limitations under the License.
 **/
-use crate::sender::Message;
+use crate::eligibility::{self, Eligibility};
+use crate::sender::{DeadLetter, FileKind, FileSource, Message, Outbox, Priority};
+use crate::snapshot;
+use crate::storage;
+use crate::storage::{DEFAULT_EVENT, InviteRedeem, Storage, TaskWrite};
+use crate::text::{Format, KEEP_FLAGS, UPSTREAM_RECOVERED};
+use crate::upstream::{Breaker, NegativeCache, Transition};
 use anyhow::bail;
-use log::info;
-use redis::AsyncCommands;
-use redis::aio::MultiplexedConnection;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime};
+use hmac::{Hmac, Mac};
+use log::{error, info, warn};
 use reqwest::Client;
 use reqwest::header::HeaderMap;
-use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::error::Error;
 use std::fmt::{Display, Formatter};
-use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
-use teloxide::types::ReplyMarkup;
-use tokio::sync::Mutex;
-use tokio::sync::mpsc::Sender;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use teloxide::types::{ReplyMarkup, User};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Vas3kUser {
     #[serde(skip)]
-    telegram_id: i64,
+    pub telegram_id: i64,
     id: String,
     slug: String,
     full_name: String,
@@ -51,39 +57,107 @@ pub struct Vas3kUser {
     city: Option<String>,
     country: Option<String>,
     is_active_member: bool,
+    /** When the copy was taken from vas3k.club, 0 for caches from before expiry **/
+    #[serde(default)]
+    fetched_at: u64,
+    /** Made from Telegram data for a player let in without vas3k.club **/
+    #[serde(default)]
+    local: bool,
 }
 
-trait FillId {
-    fn fill_id<S: AsRef<str>>(&mut self, key: S);
-}
-
-impl FillId for String {
-    fn fill_id<S: AsRef<str>>(&mut self, _key: S) {}
-}
-
-impl FillId for Vas3kUser {
-    fn fill_id<S: AsRef<str>>(&mut self, key: S) {
-        if let Some(rest) = key
-            .as_ref()
-            .split(':')
-            .next_back()
-            .and_then(|x| x.parse::<i64>().ok())
-        {
-            self.telegram_id = rest;
+impl Vas3kUser {
+    /** `membership_expires_at` as unixtime, None if vas3k.club sent something unreadable **/
+    fn membership_expires(&self) -> Option<u64> {
+        let value = self.membership_expires_at.trim();
+        if value.is_empty() {
+            return None;
+        }
+        let time = DateTime::parse_from_rfc3339(value)
+            .map(|x| x.timestamp())
+            .or_else(|_| {
+                NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%.f")
+                    .map(|x| x.and_utc().timestamp())
+            })
+            .or_else(|_| {
+                NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                    .map(|x| x.and_time(NaiveTime::MIN).and_utc().timestamp())
+            });
+        match time {
+            Ok(time) => Some(time.max(0) as u64),
+            Err(_) => {
+                warn!("Unreadable membership_expires_at of {}: {value}", self.slug);
+                None
+            }
         }
     }
-}
 
-impl FillId for Task {
-    fn fill_id<S: AsRef<str>>(&mut self, key: S) {
-        if let Some(rest) = key.as_ref().split(':').next_back() {
-            self.id = rest.to_owned();
+    /** Profile of a player who is let in without vas3k.club, made from what Telegram knows **/
+    fn local(user: &User) -> Self {
+        Self {
+            telegram_id: user.id.0 as i64,
+            id: user.id.0.to_string(),
+            slug: user
+                .username
+                .clone()
+                .unwrap_or_else(|| user.id.0.to_string()),
+            full_name: user.full_name(),
+            avatar: String::new(),
+            bio: String::new(),
+            upvotes: 0,
+            created_at: String::new(),
+            membership_started_at: String::new(),
+            membership_expires_at: String::new(),
+            moderation_status: String::new(),
+            payment_status: String::new(),
+            company: None,
+            position: None,
+            city: None,
+            country: None,
+            is_active_member: false,
+            fetched_at: unix_now(),
+            local: true,
         }
     }
+
+    pub fn is_member(&self, now: u64) -> bool {
+        self.is_active_member && self.membership_expires().is_none_or(|x| x > now)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct MembershipConfig {
+    /** Base URL of vas3k.club, a `mock` run can stand in for it **/
+    pub url: String,
+    /** Seconds a cached vas3k.club profile is trusted for **/
+    pub ttl: u64,
+    /** Seconds between background refreshes of stale profiles, 0 turns them off **/
+    pub refresh_interval: u64,
+    /** Seconds a request to vas3k.club may take **/
+    pub timeout: u64,
+    /** Extra tries after a network error or a 5xx, with a doubling delay **/
+    pub retries: u32,
+    /** Seconds a non-member or an error reply is remembered, 0 turns it off **/
+    pub negative_ttl: u64,
+    /** Failed requests in a row after which vas3k.club is left alone **/
+    pub breaker_threshold: u32,
+    /** Seconds vas3k.club is left alone for, cached profiles are used meanwhile **/
+    pub breaker_cooldown: u64,
 }
 
-impl FillId for Solve {
-    fn fill_id<S: AsRef<str>>(&mut self, _: S) {}
+impl Default for MembershipConfig {
+    fn default() -> Self {
+        Self {
+            url: String::from("https://vas3k.club"),
+            ttl: 3600,
+            refresh_interval: 600,
+            timeout: 5,
+            retries: 2,
+            negative_ttl: 60,
+            breaker_threshold: 5,
+            breaker_cooldown: 60,
+        }
+    }
 }
 
 impl Display for Vas3kUser {
@@ -92,12 +166,13 @@ impl Display for Vas3kUser {
     }
 }
 
+/** What vas3k.club answers, also the fixture format of the mock **/
 #[derive(Serialize, Deserialize)]
-struct Vas3kUserReply {
+pub struct Vas3kUserReply {
     user: Option<Vas3kUser>,
     error: Option<Vas3kError>,
 }
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct Vas3kError {
     code: String,
     title: String,
@@ -119,28 +194,291 @@ impl Display for Vas3kError {
 
 impl Error for Vas3kError {}
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(untagged)]
 pub enum FlagType {
     Single(String),
     Multi(Vec<String>),
 }
 
+impl FlagType {
+    pub fn iter(&self) -> impl Iterator<Item = &String> {
+        match self {
+            Self::Single(s) => std::slice::from_ref(s).iter(),
+            Self::Multi(vs) => vs.iter(),
+        }
+    }
+}
+
 impl Default for FlagType {
     fn default() -> Self {
         Self::Single(String::new())
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Task {
     pub name: String,
+    /** Salted hashes once `hashed` is set, older records keep plaintext until the startup upgrade **/
     pub flag: FlagType,
     pub hint: String,
     #[serde(skip)]
     pub id: String,
     #[serde(default)]
     pub hidden: bool,
+    #[serde(default)]
+    pub hashed: bool,
+}
+
+/** One CTF hosted by the bot, its tasks and solves are kept apart from other events **/
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Event {
+    #[serde(skip)]
+    pub id: String,
+    pub name: String,
+    pub start: u64,
+    pub end: u64,
+    /** Archived events are read-only and cannot become current again **/
+    #[serde(default)]
+    pub archived: bool,
+    /** Flags are not accepted while paused, the tasks stay visible **/
+    #[serde(default)]
+    pub paused: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clock {
+    NotStarted,
+    Running,
+    Paused,
+    Finished,
+}
+
+impl Event {
+    pub fn clock(&self, now: u64) -> Clock {
+        if now <= self.start {
+            Clock::NotStarted
+        } else if now >= self.end {
+            Clock::Finished
+        } else if self.paused {
+            Clock::Paused
+        } else {
+            Clock::Running
+        }
+    }
+}
+
+/** Lets guests in whatever the eligibility provider says **/
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Invite {
+    #[serde(skip)]
+    pub code: String,
+    /** 0 for no limit **/
+    pub max_uses: u64,
+    pub uses: u64,
+    /** Unixtime, 0 for never **/
+    pub expires: u64,
+    pub created: u64,
+    /** Revoked codes let nobody new in, those who joined stay **/
+    #[serde(default)]
+    pub revoked: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum SanctionKind {
+    /** Every message is dropped **/
+    Ban,
+    /** /contact is refused **/
+    Mute,
+    /** Left out of the board, solves are kept **/
+    Disqualify,
+}
+
+impl SanctionKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Ban => "ban",
+            Self::Mute => "mute",
+            Self::Disqualify => "disqualify",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /** Every admin command **/
+    Admin,
+    /** The board and answering contacts, no task edits **/
+    Moderator,
+    /** Plays outside of the event window and stays out of the board **/
+    Tester,
+    /** Gets solves, questions and failures, usually a group chat **/
+    Notify,
+}
+
+impl Role {
+    pub const ALL: [Role; 4] = [Self::Admin, Self::Moderator, Self::Tester, Self::Notify];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Admin => "admin",
+            Self::Moderator => "moderator",
+            Self::Tester => "tester",
+            Self::Notify => "notify",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|x| x.as_str() == value)
+    }
+}
+
+/** One sanction of a kind per user, a new one replaces it **/
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Sanction {
+    pub kind: SanctionKind,
+    pub reason: String,
+    /** Unixtime, 0 for never **/
+    pub until: u64,
+    pub admin: u64,
+    pub time: u64,
+}
+
+impl Sanction {
+    pub fn is_active(&self, now: u64) -> bool {
+        self.until == 0 || self.until > now
+    }
+}
+
+/** What an admin did to whom and why **/
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEntry {
+    pub time: u64,
+    pub admin: u64,
+    /** Telegram ID, negative for chats given a role **/
+    pub user_id: i64,
+    pub action: String,
+    pub reason: String,
+    /** Unixtime the action runs out, 0 for never **/
+    #[serde(default)]
+    pub until: u64,
+}
+
+/** A /message to every user, its messages report back how they went **/
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Broadcast {
+    /** Set from the storage key **/
+    #[serde(skip)]
+    pub id: u64,
+    pub admin: u64,
+    pub text: String,
+    pub time: u64,
+    /** `Segment` it went to, empty for older broadcasts sent to everyone **/
+    #[serde(default)]
+    pub segment: String,
+    /** Unixtime the final report was posted, 0 while it was not **/
+    #[serde(default)]
+    pub reported: u64,
+}
+
+/** Who a broadcast goes to, kept in the user state between the steps of /message **/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    All,
+    /** No solves in the current event **/
+    NoSolves,
+    /** Has not solved the task with this ID in the current event **/
+    Unsolved(String),
+    /** The first N of the board with at least one solve **/
+    Top(usize),
+    Testers,
+}
+
+impl Segment {
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut parts = text.split_whitespace();
+        let segment = match (parts.next(), parts.next()) {
+            (Some("all"), None) => Self::All,
+            (Some("zero"), None) => Self::NoSolves,
+            (Some("unsolved"), Some(task)) => Self::Unsolved(String::from(task)),
+            (Some("top"), Some(count)) => match count.parse::<usize>() {
+                Ok(count) if count > 0 => Self::Top(count),
+                _ => bail!(r"После top нужно число больше нуля"),
+            },
+            (Some("testers"), None) => Self::Testers,
+            _ => bail!(
+                r"Неизвестный сегмент, нужен один из: all, zero, unsolved <ID задания>, top <N>, testers"
+            ),
+        };
+        if parts.next().is_some() {
+            bail!(r"Лишние слова после сегмента")
+        }
+        Ok(segment)
+    }
+}
+
+impl Display for Segment {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::All => write!(f, "all"),
+            Self::NoSolves => write!(f, "zero"),
+            Self::Unsolved(task) => write!(f, "unsolved {task}"),
+            Self::Top(count) => write!(f, "top {count}"),
+            Self::Testers => write!(f, "testers"),
+        }
+    }
+}
+
+/** A broadcast waiting for its time **/
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Schedule {
+    /** Set from the storage key **/
+    #[serde(skip)]
+    pub id: u64,
+    pub admin: u64,
+    /** Unixtime to send at **/
+    pub time: u64,
+    /** `Segment` it goes to **/
+    pub segment: String,
+    pub text: String,
+    pub created: u64,
+}
+
+/** How a broadcast message to one chat went **/
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Delivery {
+    Pending,
+    Sent,
+    Failed(String),
+}
+
+/** Totals of a broadcast, failures are counted by reason **/
+#[derive(Default)]
+pub struct DeliveryStats {
+    pub sent: usize,
+    pub pending: usize,
+    pub failed: BTreeMap<String, usize>,
+}
+
+impl DeliveryStats {
+    pub fn count(deliveries: &HashMap<i64, Delivery>) -> Self {
+        let mut ret = Self::default();
+        for delivery in deliveries.values() {
+            match delivery {
+                Delivery::Pending => ret.pending += 1,
+                Delivery::Sent => ret.sent += 1,
+                Delivery::Failed(reason) => *ret.failed.entry(reason.clone()).or_default() += 1,
+            }
+        }
+        ret
+    }
+
+    pub fn failed_total(&self) -> usize {
+        self.failed.values().sum()
+    }
 }
 
 pub enum SubmissionResult {
@@ -149,338 +487,969 @@ pub enum SubmissionResult {
     Solved(String),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Solve {
-    solves: Vec<String>,
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AttemptKind {
+    Wrong,
+    Solved,
+    Repeated,
+}
+
+/** One flag submission as written to the attempt log **/
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Attempt {
+    pub user_id: u64,
+    pub time: u64,
+    pub kind: AttemptKind,
+    #[serde(default)]
+    pub task: Option<String>,
+    /** Whether it goes to the counters, older log entries were all counted **/
+    #[serde(default = "counted_default")]
+    pub counted: bool,
+}
+
+fn counted_default() -> bool {
+    true
 }
 
-impl From<Solve> for u64 {
-    fn from(value: Solve) -> Self {
-        value.solves.len() as u64
+pub struct FirstBlood {
+    pub user: String,
+    pub time: u64,
+}
+
+pub struct TaskStats {
+    pub task: Task,
+    pub solves: u64,
+    pub first_blood: Option<FirstBlood>,
+}
+
+impl TaskStats {
+    pub fn percent(&self, participants: u64) -> u64 {
+        (self.solves * 100).checked_div(participants).unwrap_or(0)
     }
 }
 
-pub struct Api {
-    client: Client,
-    conn: MultiplexedConnection,
-    mutex: Mutex<bool>,
-    sender: Sender<Message>,
+pub struct Stats {
+    pub tasks: Vec<TaskStats>,
+    pub participants: u64,
+    pub attempts: u64,
+    pub wrong_attempts: u64,
+    pub top_wrong: Vec<(String, u64)>,
+}
+
+/** Read errors are logged and replaced with an empty value, like a missing key **/
+fn or_log<T: Default>(what: &str, result: anyhow::Result<T>) -> T {
+    result.unwrap_or_else(|e| {
+        error!("Failed to {what}: {e}");
+        T::default()
+    })
+}
+
+/** How many entries /audit and /undelivered show **/
+const AUDIT_SIZE: usize = 20;
+/** How many broadcasts /broadcasts shows **/
+const BROADCASTS_SHOWN: usize = 10;
+/** How often due schedules are looked for **/
+const SCHEDULE_TICK: Duration = Duration::from_secs(10);
+
+/** Milliseconds before the first retry of a vas3k.club request **/
+const RETRY_DELAY: u64 = 500;
+
+/** The form flags are compared in, both on creation and on submission **/
+fn normalize_flag(flag: &str) -> String {
+    flag.trim().to_lowercase()
+}
+
+/** Hex HMAC-SHA256 of the normalized flag keyed with `flag_salt` **/
+fn hash_flag(salt: &str, flag: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(salt.as_bytes()).expect("HMAC takes any key");
+    mac.update(normalize_flag(flag).as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/** Hex HMAC-SHA256 of a fixed text keyed with `flag_salt`, tells snapshots of another salt apart.
+Not normalized, so it never equals the hash of a flag **/
+pub fn salt_fingerprint(salt: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(salt.as_bytes()).expect("HMAC takes any key");
+    mac.update(b"Snapshot flag_salt");
+    hex::encode(mac.finalize().into_bytes())
+}
+
+fn hash_flags(salt: &str, flag: &FlagType) -> FlagType {
+    match flag {
+        FlagType::Single(s) => FlagType::Single(hash_flag(salt, s)),
+        FlagType::Multi(vs) => FlagType::Multi(vs.iter().map(|x| hash_flag(salt, x)).collect()),
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or_else(|_| 0, |t| t.as_secs())
+}
+
+/** Everything built from the config, swapped as a whole by `reload_config` **/
+struct Settings {
     config: Arc<Config>,
+    client: Client,
+    eligibility: Arc<dyn Eligibility>,
+}
+
+impl Settings {
+    fn new(config: Arc<Config>) -> anyhow::Result<Self> {
+        let mut headers = HeaderMap::new();
+        if let Ok(token) = config.vas3k_token.parse() {
+            headers.insert("X-Service-Token", token);
+        }
+        Ok(Self {
+            client: Client::builder()
+                .default_headers(headers)
+                .timeout(Duration::from_secs(config.membership.timeout))
+                .build()?,
+            eligibility: eligibility::open(&config.eligibility),
+            config,
+        })
+    }
+}
+
+pub struct Api {
+    storage: Arc<dyn Storage>,
+    outbox: Outbox,
+    settings: RwLock<Arc<Settings>>,
+    /** Copy of the current event, only changed through `switch_event` **/
+    event: RwLock<Event>,
+    /** Copy of the roles in the storage, only changed through `grant_role` and `revoke_role` **/
+    roles: RwLock<HashSet<(i64, Role)>>,
+    /** Guards vas3k.club, kept across config reloads **/
+    breaker: Breaker,
+    /** The last error for a user, None for a profile that is not a member **/
+    negative: NegativeCache<Option<Vas3kError>>,
 }
 
 impl Api {
     pub async fn send_notification<S: AsRef<str>>(&self, message: S) -> anyhow::Result<()> {
-        for i in &self.config.notify_group {
-            self.sender.send((*i, message.as_ref()).into()).await?;
+        for i in self.role_ids(Role::Notify) {
+            self.outbox.push((i, message.as_ref())).await?;
         }
         Ok(())
     }
     pub async fn send_message<S: AsRef<str>>(&self, to: i64, message: S) -> anyhow::Result<()> {
-        self.sender
-            .send((to, message).into())
+        self.outbox.push((to, message)).await
+    }
+
+    pub async fn send_file<S: AsRef<str>>(
+        &self,
+        to: i64,
+        kind: FileKind,
+        file: FileSource,
+        caption: S,
+    ) -> anyhow::Result<()> {
+        self.outbox
+            .push(Message::file(to, kind, file, caption))
             .await
-            .map_err(|e| e.into())
+    }
+
+    fn settings(&self) -> Arc<Settings> {
+        self.settings
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    fn config(&self) -> Arc<Config> {
+        self.settings().config.clone()
+    }
+
+    fn has_role(&self, id: i64, role: Role) -> bool {
+        self.roles
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains(&(id, role))
+    }
+
+    fn role_ids(&self, role: Role) -> Vec<i64> {
+        let mut ids = self
+            .roles
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .filter(|x| x.1 == role)
+            .map(|x| x.0)
+            .collect::<Vec<i64>>();
+        ids.sort();
+        ids
     }
 
     fn is_test_user(&self, user_id: u64) -> bool {
-        self.config.test_group.iter().any(|x| *x == user_id as i64)
+        self.has_role(user_id as i64, Role::Tester)
+    }
+
+    /** Listed in `super_admins`, or in `admin_group` if there are none, cannot lose their rights **/
+    pub fn is_super_admin(&self, user_id: u64) -> bool {
+        let config = self.config();
+        let ids = if config.super_admins.is_empty() {
+            &config.admin_group
+        } else {
+            &config.super_admins
+        };
+        ids.contains(&(user_id as i64))
     }
 
     pub fn is_admin(&self, user_id: u64) -> bool {
-        self.config.admin_group.iter().any(|x| *x == user_id as i64)
+        self.is_super_admin(user_id) || self.has_role(user_id as i64, Role::Admin)
+    }
+
+    /** Admins can do whatever a moderator can **/
+    pub fn is_moderator(&self, user_id: u64) -> bool {
+        self.is_admin(user_id) || self.has_role(user_id as i64, Role::Moderator)
+    }
+
+    fn is_staff(&self, user_id: u64) -> bool {
+        self.is_test_user(user_id) || self.is_moderator(user_id)
     }
 
+    /** For the sender that delivers what the bot queues **/
+    pub fn outbox(&self) -> Outbox {
+        self.outbox.clone()
+    }
+
+    pub fn snapshot_interval(&self) -> u64 {
+        self.config().snapshot.interval
+    }
+
+    pub fn current_event(&self) -> Event {
+        self.event.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn event_id(&self) -> String {
+        self.event
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .id
+            .clone()
+    }
+
+    pub fn event_start(&self) -> u64 {
+        self.event.read().unwrap_or_else(|e| e.into_inner()).start
+    }
+
+    /** Tasks, score and stats are open while the event runs or is paused **/
     pub fn can_process_command(&self, user_id: u64) -> bool {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .map_or_else(|_| 0, |t| t.as_secs());
-        if now > self.config.event_start && now < self.config.event_end {
-            true
-        } else {
-            self.is_test_user(user_id) || self.is_admin(user_id)
+        match self.current_event().clock(unix_now()) {
+            Clock::Running | Clock::Paused => true,
+            Clock::NotStarted | Clock::Finished => self.is_staff(user_id),
         }
     }
 
+    /** Staff keep testing flags during a pause **/
+    pub fn is_paused_for(&self, user_id: u64) -> bool {
+        self.current_event().clock(unix_now()) == Clock::Paused && !self.is_staff(user_id)
+    }
+
     pub async fn send_message_with_markup<S: AsRef<str>>(
         &self,
         to: i64,
         message: S,
         kbd: ReplyMarkup,
     ) -> anyhow::Result<()> {
-        self.sender
-            .send((to, message, kbd).into())
-            .await
-            .map_err(|e| e.into())
+        self.outbox.push((to, message, kbd)).await
     }
-    pub async fn new(config: Arc<Config>, sender: Sender<Message>) -> Arc<Api> {
-        let mut headers = HeaderMap::new();
-        if let Ok(token) = config.vas3k_token.parse() {
-            headers.insert("X-Service-Token", token);
-        }
-        if let Ok(cli) = redis::Client::open("redis://127.0.0.1/") {
-            if let Ok(conn) = cli.get_multiplexed_async_connection().await {
-                Arc::new(Self {
-                    client: Client::builder()
-                        .default_headers(headers)
-                        .build()
-                        .expect("Client::build"),
-                    conn,
-                    sender,
-                    config,
-                    mutex: Mutex::new(false),
-                })
-            } else {
-                panic!("Failed to obtain async Redis connection");
-            }
-        } else {
-            panic!("Failed to connect to Redis")
-        }
+    pub async fn new(config: Arc<Config>) -> anyhow::Result<Arc<Api>> {
+        config.validate()?;
+        let storage: Arc<dyn Storage> = storage::open(&config.storage).await?.into();
+        let event = Self::load_event(storage.as_ref(), &config).await?;
+        Self::hash_plain_flags(storage.as_ref(), &config.flag_salt).await?;
+        let roles = Self::load_roles(storage.as_ref(), &config).await?;
+        Ok(Arc::new(Self {
+            outbox: Outbox::open(storage.clone()).await?,
+            storage,
+            settings: RwLock::new(Arc::new(Settings::new(config)?)),
+            event: RwLock::new(event),
+            roles: RwLock::new(roles),
+            breaker: Breaker::default(),
+            negative: NegativeCache::new(),
+        }))
     }
 
-    async fn collect_from_cache<T>(&self, key: &str) -> Option<T>
-    where
-        T: DeserializeOwned + FillId,
-    {
-        let mut conn = self.conn.clone();
-        if let Ok(value) = conn.get::<&str, Vec<u8>>(key).await {
-            if let Ok(mut ttype) = serde_json::from_slice::<T>(&value[..]) {
-                ttype.fill_id(key);
-                return Some(ttype);
-            }
+    /** Validates the new config and swaps it in at once, returns what changed **/
+    pub async fn reload_config(&self, config: Arc<Config>) -> anyhow::Result<Vec<String>> {
+        config.validate()?;
+        let old = self.config();
+        if config.flag_salt != old.flag_salt {
+            bail!(r"flag_salt менять нельзя, сохранённые флаги перестанут подходить")
         }
-        None
-    }
-
-    async fn put_into_cache<T>(&self, key: &str, value: &T)
-    where
-        T: Serialize,
-    {
-        let mut conn = self.conn.clone();
-        if let Ok(serialized) = serde_json::to_vec(value) {
-            if let Err(e) = conn.set::<&str, &Vec<u8>, ()>(key, &serialized).await {
-                info!("Failed to set Redis key {}: {e}", key);
-            }
+        let mut changes = old.changes(&config);
+        if changes.is_empty() {
+            return Ok(changes);
         }
-    }
-
-    async fn del_from_cache(&self, key: &str) {
-        let mut conn = self.conn.clone();
-        if let Err(e) = conn.del::<_, String>(key).await {
-            info!("Failed to delete Redis key {}: {e}", key);
+        let settings = Settings::new(config.clone())?;
+        self.apply_role_groups(&old, &config).await?;
+        if let Some(note) = self.apply_event_times(&old, &config).await? {
+            changes.push(note);
         }
+        *self.settings.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(settings);
+        info!(
+            "Reloaded {}: {}",
+            crate::text::CONFIG_NAME,
+            changes.join(", ")
+        );
+        Ok(changes)
     }
 
-    pub async fn get_user_state(&self, user_id: u64) -> Option<String> {
-        let key = format!("user_state:{}", user_id);
-        self.collect_from_cache::<String>(&key)
-            .await
-            .filter(|x| !x.is_empty())
-    }
-
-    pub async fn set_user_state<S: AsRef<str>>(&self, user_id: u64, state: S) {
-        let key = format!("user_state:{}", user_id);
-        let value = String::from(state.as_ref());
-        self.put_into_cache(&key, &value).await;
-    }
-
-    pub async fn receive_user_by_telegram(&self, user_id: u64) -> anyhow::Result<Vas3kUser> {
-        let key = format!("user:{}", user_id);
-        if let Some(user) = self.collect_from_cache(&key).await {
-            return Ok(user);
-        }
-        let url = format!("https://vas3k.club/user/by_telegram_id/{}.json", user_id);
-        let reply = self
-            .client
-            .get(url)
-            .send()
-            .await?
-            .json::<Vas3kUserReply>()
-            .await?;
-        if let Some(error) = reply.error {
-            Err(error.into())
-        } else if let Some(user) = reply.user {
-            self.put_into_cache(&key, &user).await;
-            Ok(user)
-        } else {
-            unreachable!()
+    /** Ids added to or removed from a config group get or lose its role, /grant ones are kept **/
+    async fn apply_role_groups(&self, old: &Config, new: &Config) -> anyhow::Result<()> {
+        let groups = [
+            (&old.admin_group, &new.admin_group, Role::Admin),
+            (&old.test_group, &new.test_group, Role::Tester),
+            (&old.notify_group, &new.notify_group, Role::Notify),
+        ];
+        let seeded: HashSet<(i64, Role)> = self.storage.config_roles().await?.into_iter().collect();
+        for (before, after, role) in groups {
+            for id in after.iter().filter(|x| !before.contains(x)) {
+                // a role already granted with /grant stays unmarked and outlives the group
+                if self.storage.grant_role(*id, role).await? {
+                    self.storage.set_config_role(*id, role, true).await?;
+                }
+                self.roles
+                    .write()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .insert((*id, role));
+            }
+            for id in before.iter().filter(|x| !after.contains(x)) {
+                if !seeded.contains(&(*id, role)) {
+                    continue;
+                }
+                self.storage.revoke_role(*id, role).await?;
+                self.roles
+                    .write()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .remove(&(*id, role));
+            }
         }
+        Ok(())
     }
 
-    pub async fn check_user_is_in_scope(&self, user_id: u64) -> bool {
-        if let Ok(user) = self.receive_user_by_telegram(user_id).await {
-            user.is_active_member
-        } else {
-            false
+    /** New `event_start` and `event_end` move the current event while it still has the old ones,
+    one moved at runtime is left alone and a note about it is returned **/
+    async fn apply_event_times(
+        &self,
+        old: &Config,
+        config: &Config,
+    ) -> anyhow::Result<Option<String>> {
+        if old.event_start == config.event_start && old.event_end == config.event_end {
+            return Ok(None);
+        }
+        let event = self.current_event();
+        if event.start != old.event_start || event.end != old.event_end {
+            return Ok(Some(Format::format_event_times_kept(&event)));
         }
+        self.update_current_event(|event, _| {
+            event.start = config.event_start;
+            event.end = config.event_end;
+            Ok(())
+        })
+        .await?;
+        Ok(None)
     }
 
-    async fn get_keys<S: AsRef<str>>(&self, pattern: S) -> Vec<String> {
-        let mut conn = self.conn.clone();
-        conn.keys::<&str, Vec<String>>(pattern.as_ref())
-            .await
-            .unwrap_or_default()
-    }
-
-    pub async fn try_submit_flag<S: AsRef<str>>(&self, user_id: u64, text: S) -> SubmissionResult {
-        let try_flag = text.as_ref().trim().to_lowercase();
-        for task_key in self.get_keys("task:*").await {
-            if let Some(task) = self.collect_from_cache::<Task>(&task_key).await {
-                if match task.flag {
-                    FlagType::Single(s) => s.as_str().eq(try_flag.as_str()),
-                    FlagType::Multi(vs) => vs.iter().any(|s| s.as_str().eq(try_flag.as_str())),
-                } {
-                    let mut val = self.mutex.lock().await;
-                    *val = true;
-                    let ret = if self.is_solved(user_id, &task_key).await {
-                        SubmissionResult::AlreadySolved
-                    } else {
-                        self.set_solved(user_id, &task_key).await;
-                        SubmissionResult::Solved(task.name)
-                    };
-                    *val = false;
-                    drop(val);
-                    return ret;
+    /** Reads the roles, while there are none they are seeded from the config groups **/
+    async fn load_roles(
+        storage: &dyn Storage,
+        config: &Config,
+    ) -> anyhow::Result<HashSet<(i64, Role)>> {
+        let mut roles = storage.roles().await?;
+        if roles.is_empty() {
+            let groups = [
+                (&config.admin_group, Role::Admin),
+                (&config.test_group, Role::Tester),
+                (&config.notify_group, Role::Notify),
+            ];
+            for (ids, role) in groups {
+                for id in ids {
+                    storage.grant_role(*id, role).await?;
+                    storage.set_config_role(*id, role, true).await?;
                 }
             }
+            roles = storage.roles().await?;
+            info!(
+                "Seeded {} roles from {}",
+                roles.len(),
+                crate::text::CONFIG_NAME
+            );
         }
-        SubmissionResult::NotAFlag
+        Ok(roles.into_iter().collect())
     }
 
-    async fn is_solved<S: AsRef<str>>(&self, user_id: u64, task_key: S) -> bool {
-        let key = format!("solve:{}", user_id);
-        if let Some(solve) = self.collect_from_cache::<Solve>(&key).await {
-            if solve
-                .solves
-                .iter()
-                .any(|s| s.as_str().eq(task_key.as_ref()))
-            {
-                return true;
-            }
+    /** Reads the current event, the first start creates it from `event_start` and `event_end` **/
+    async fn load_event(storage: &dyn Storage, config: &Config) -> anyhow::Result<Event> {
+        if let Some(id) = storage.current_event().await?
+            && let Some(event) = storage.get_event(&id).await?
+        {
+            return Ok(event);
         }
-        false
-    }
-
-    async fn set_solved<S: AsRef<str>>(&self, user_id: u64, task_key: S) {
-        let key = format!("solve:{}", user_id);
-        let solve = if let Some(mut solve) = self.collect_from_cache::<Solve>(&key).await {
-            solve.solves.push(String::from(task_key.as_ref()));
-            solve
-        } else {
-            Solve {
-                solves: vec![String::from(task_key.as_ref())],
+        let event = match storage.get_event(DEFAULT_EVENT).await? {
+            Some(event) => event,
+            None => {
+                let event = Event {
+                    id: String::from(DEFAULT_EVENT),
+                    name: String::from(DEFAULT_EVENT),
+                    start: config.event_start,
+                    end: config.event_end,
+                    ..Default::default()
+                };
+                storage.create_event(&event).await?;
+                event
             }
         };
-        self.put_into_cache(&key, &solve).await;
+        storage.set_current_event(&event.id).await?;
+        Ok(event)
     }
 
-    pub async fn get_score(&self, user_id: u64) -> (u64, u64) {
-        let mut data = Vec::new();
-        let user_key = format!("solve:{}", user_id);
-        let hidden = self.is_test_user(user_id) || self.is_admin(user_id);
-        for key in self.get_keys("solve:*").await {
-            let score = self
-                .collect_from_cache::<Solve>(&key)
-                .await
-                .map(|x| x.into())
-                .unwrap_or(0u64);
-            data.push((key, score));
-        }
-        let size = data.len() as u64;
-        data.sort_by(|x, y| y.1.cmp(&x.1));
-        let (place, score) = || -> (u64, u64) {
-            for (i, (key, score)) in data.into_iter().enumerate() {
-                if key.eq(&user_key) {
-                    return ((i + 1) as u64, score);
+    /** Replaces flags still kept in plaintext with their hashes, once per task **/
+    async fn hash_plain_flags(storage: &dyn Storage, salt: &str) -> anyhow::Result<()> {
+        for event in storage.event_ids().await? {
+            for id in storage.task_ids(&event).await? {
+                let Some(mut task) = storage.get_task(&event, &id).await? else {
+                    continue;
+                };
+                if task.hashed {
+                    continue;
+                }
+                task.flag = hash_flags(salt, &task.flag);
+                task.hashed = true;
+                if let TaskWrite::FlagTaken(_, owner) = storage.update_task(&event, &task).await? {
+                    bail!("Flag of task {id} in event {event} is already used by task {owner}");
                 }
+                info!("Hashed flags of task {id} in event {event}");
             }
-            (size + 1, 0)
-        }();
-        if hidden {
-            (u64::MAX, score)
-        } else {
-            (place, score)
         }
+        Ok(())
     }
 
-    pub async fn create_task<S: AsRef<str>>(&self, text: S) -> anyhow::Result<String> {
-        let task = Self::string_to_task(text)?;
-        let mut key = format!(
-            "task:{}",
-            uuid::Uuid::new_v4().to_string().split('-').next().unwrap()
-        );
-        // lock
-        let mut val = self.mutex.lock().await;
-        *val = true;
-        while self.collect_from_cache::<Task>(&key).await.is_some() {
-            key = format!(
-                "task:{}",
-                uuid::Uuid::new_v4().to_string().split('-').next().unwrap()
-            );
-        }
-        self.put_into_cache(&key, &task).await;
-        *val = false;
-        drop(val);
-        Ok(key)
+    /** Writes the whole store into the snapshot directory, returns the file and how many
+    records were left out because they did not decode **/
+    pub async fn take_snapshot(&self) -> anyhow::Result<(PathBuf, usize)> {
+        let path = snapshot::snapshot_path(&self.config().snapshot.dir, unix_now());
+        let skipped =
+            snapshot::save(self.storage.as_ref(), &path, &self.config().flag_salt).await?;
+        Ok((path, skipped))
     }
 
-    pub async fn list_tasks(&self, user_id: u64) -> Vec<Task> {
-        let mut tasks = Vec::new();
-        for task_key in self.get_keys("task:*").await {
-            if user_id == 0 || !self.is_solved(user_id, &task_key).await {
-                if let Some(task) = self.collect_from_cache::<Task>(&task_key).await {
-                    if !task.hidden {
-                        tasks.push(task);
-                    }
+    /** Periodic snapshots, spawned only when `snapshot.interval` is set **/
+    pub async fn run_snapshots(self: Arc<Self>) {
+        // the interval is reread every round, so a reload changes it, 0 stops the loop
+        loop {
+            let period = self.config().snapshot.interval;
+            if period == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs(period)).await;
+            match self.take_snapshot().await {
+                Ok((path, 0)) => info!("Snapshot written to {}", path.display()),
+                Ok((path, skipped)) => {
+                    let e = anyhow::anyhow!(
+                        "{skipped} records did not decode and are not in {}",
+                        path.display()
+                    );
+                    self.report_failure("take snapshot", e).await;
                 }
+                Err(e) => self.report_failure("take snapshot", e).await,
             }
         }
-        tasks.sort_by(|x, y| x.name.cmp(&y.name));
-        tasks
     }
 
-    pub async fn get_task<S: AsRef<str>>(&self, name: S) -> Option<Task> {
-        let key = format!("task:{}", name.as_ref());
-        self.collect_from_cache::<Task>(&key).await
+    /** Background writes have nobody to answer to, so their failures go to the notify group **/
+    async fn report_failure(&self, what: &str, e: anyhow::Error) {
+        error!("Failed to {what}: {e}");
+        if let Err(e) = self
+            .send_notification(Format::format_storage_error(what, e))
+            .await
+        {
+            error!("Failed to report storage failure: {e}");
+        }
     }
 
-    pub async fn append_to_contact<S: AsRef<str>>(&self, user_id: u64, text: S) {
-        let key = format!("contact:{}", user_id);
-        let message = if let Some(mut old) = self.collect_from_cache::<String>(&key).await {
-            old.push('\n');
-            old.push_str(text.as_ref());
-            old
-        } else {
-            String::from(text.as_ref())
-        };
-        self.put_into_cache(&key, &message).await;
+    pub async fn get_user_state(&self, user_id: u64) -> Option<String> {
+        or_log("get user state", self.storage.get_state(user_id).await).filter(|x| !x.is_empty())
     }
 
-    pub async fn retrieve_and_erase_contact(&self, user_id: u64) -> String {
-        let key = format!("contact:{}", user_id);
-        let message = self
-            .collect_from_cache::<String>(&key)
-            .await
-            .unwrap_or_default();
-        self.put_into_cache(&key, &String::new()).await;
-        message
+    pub async fn set_user_state<S: AsRef<str>>(
+        &self,
+        user_id: u64,
+        state: S,
+    ) -> anyhow::Result<()> {
+        self.storage.set_state(user_id, state.as_ref()).await
     }
 
-    pub async fn get_all_users(&self) -> Vec<u64> {
-        self.get_keys("user:*")
-            .await
-            .into_iter()
-            .map(|key| {
-                key.split(':')
-                    .next_back()
-                    .unwrap_or("0")
-                    .parse()
-                    .unwrap_or(0)
-            })
-            .collect()
+    pub fn membership_refresh_interval(&self) -> u64 {
+        self.config().refresh_interval()
     }
 
-    fn string_to_task<S: AsRef<str>>(text: S) -> anyhow::Result<Task> {
-        let lines = text
+    /** A cached profile is used until its TTL runs out or the membership it shows expires **/
+    fn is_fresh(&self, user: &Vas3kUser, now: u64) -> bool {
+        now < user.fetched_at.saturating_add(self.config().membership.ttl) && user.is_member(now)
+    }
+
+    pub async fn receive_user_by_telegram(&self, user_id: u64) -> anyhow::Result<Vas3kUser> {
+        let cached = match self.storage.get_user(user_id).await {
+            Ok(cached) => cached,
+            // a broken cache entry is replaced by a fresh copy below
+            Err(e) => {
+                self.report_failure("read cached user", e).await;
+                None
+            }
+        };
+        let now = unix_now();
+        match cached {
+            // guests have nothing to refresh from, /refresh_user replaces them by hand
+            Some(user) if user.local || self.is_fresh(&user, now) => Ok(user),
+            Some(user) => match self.negative.get(user_id, now) {
+                // asked a moment ago, still not a member
+                Some(None) => Ok(user),
+                Some(Some(error)) => Err(error.into()),
+                None => match self.fetch_user(user_id).await {
+                    Ok(user) => Ok(user),
+                    Err(e) if e.is::<Vas3kError>() => Err(e),
+                    // a stale copy is better than locking everyone out while vas3k.club is down
+                    Err(e) => {
+                        warn!("Failed to refresh user {user_id}, using the cached copy: {e}");
+                        Ok(user)
+                    }
+                },
+            },
+            None => match self.negative.get(user_id, now) {
+                Some(Some(error)) => Err(error.into()),
+                _ => self.fetch_user(user_id).await,
+            },
+        }
+    }
+
+    /** Asks vas3k.club for the profile and caches it, whatever is cached now **/
+    pub async fn fetch_user(&self, user_id: u64) -> anyhow::Result<Vas3kUser> {
+        let config = self.config();
+        let reply = self.request_user(&config.membership, user_id).await?;
+        let now = unix_now();
+        let ttl = config.membership.negative_ttl;
+        if let Some(error) = reply.error {
+            self.negative.insert(user_id, Some(error.clone()), now, ttl);
+            Err(error.into())
+        } else if let Some(mut user) = reply.user {
+            user.telegram_id = user_id as i64;
+            user.fetched_at = now;
+            if user.is_member(now) {
+                self.negative.remove(user_id);
+            } else {
+                self.negative.insert(user_id, None, now, ttl);
+            }
+            if let Err(e) = self.storage.put_user(user_id, &user).await {
+                self.report_failure("cache user", e).await;
+            }
+            Ok(user)
+        } else {
+            bail!(r"vas3k.club не вернул ни профиля, ни ошибки")
+        }
+    }
+
+    /** Retries network errors and 5xx with a doubling delay, the breaker counts what is left **/
+    async fn request_user(
+        &self,
+        config: &MembershipConfig,
+        user_id: u64,
+    ) -> anyhow::Result<Vas3kUserReply> {
+        let now = unix_now();
+        if !self.breaker.allow(now) {
+            bail!(
+                r"vas3k.club недоступен, следующая попытка через {} с",
+                self.breaker.retry_in(now)
+            )
+        }
+        let url = format!(
+            "{}/user/by_telegram_id/{}.json",
+            config.url.trim_end_matches('/'),
+            user_id
+        );
+        let client = self.settings().client.clone();
+        let mut delay = Duration::from_millis(RETRY_DELAY);
+        let mut attempt = 0;
+        loop {
+            let result = match client.get(&url).send().await {
+                Ok(response) if response.status().is_server_error() => {
+                    Err(anyhow::anyhow!("vas3k.club answered {}", response.status()))
+                }
+                Ok(response) => response
+                    .json::<Vas3kUserReply>()
+                    .await
+                    .map_err(|e| e.into()),
+                Err(e) => Err(e.into()),
+            };
+            match result {
+                Ok(reply) => {
+                    if self.breaker.success() == Transition::Closed {
+                        info!("vas3k.club is back");
+                        let _ = self.send_notification(UPSTREAM_RECOVERED).await;
+                    }
+                    return Ok(reply);
+                }
+                Err(e) if attempt < config.retries => {
+                    warn!("Request {attempt} for user {user_id} failed: {e}");
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    let transition = self.breaker.failure(
+                        unix_now(),
+                        config.breaker_threshold,
+                        config.breaker_cooldown,
+                    );
+                    if transition == Transition::Opened {
+                        error!("vas3k.club is degraded: {e}");
+                        let message = Format::format_upstream_degraded(&e, config.breaker_cooldown);
+                        let _ = self.send_notification(message).await;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /** Refetches every cached profile that is no longer fresh **/
+    pub async fn run_membership_refresh(self: Arc<Self>) {
+        loop {
+            let period = self.membership_refresh_interval();
+            if period == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs(period)).await;
+            let mut failed = 0;
+            for user_id in self.get_all_users().await {
+                let stale = match self.storage.get_user(user_id).await {
+                    Ok(Some(user)) => !user.local && !self.is_fresh(&user, unix_now()),
+                    _ => false,
+                };
+                if stale && let Err(e) = self.fetch_user(user_id).await {
+                    warn!("Failed to refresh user {user_id}: {e}");
+                    failed += 1;
+                }
+            }
+            if failed > 0 {
+                let e = anyhow::anyhow!("{failed} profiles were not refreshed");
+                self.report_failure("refresh users", e).await;
+            }
+        }
+    }
+
+    /** Invited guests are let in whatever the provider says **/
+    pub async fn check_user_is_in_scope(&self, user: &User) -> bool {
+        self.settings().eligibility.is_eligible(self, user).await
+            || (self.is_registered(user.id.0).await && self.ensure_local_profile(user).await)
+    }
+
+    /** `/start <code>` or `/join <code>`, codes from /invite_create go first **/
+    pub async fn redeem_code(&self, user: &User, code: &str) -> anyhow::Result<bool> {
+        // someone the provider lets in anyway leaves the invite use to others
+        if self.settings().eligibility.is_eligible(self, user).await {
+            return Ok(true);
+        }
+        match self
+            .storage
+            .redeem_invite(code, user.id.0, unix_now())
+            .await?
+        {
+            InviteRedeem::Done => Ok(self.ensure_local_profile(user).await),
+            InviteRedeem::Unknown => self.settings().eligibility.redeem(self, user, code).await,
+            InviteRedeem::Revoked => bail!(r"Этот код приглашения отозван"),
+            InviteRedeem::Expired => bail!(r"Срок действия кода приглашения истёк"),
+            InviteRedeem::Exhausted => bail!(r"Код приглашения больше нельзя использовать"),
+        }
+    }
+
+    pub async fn is_registered(&self, user_id: u64) -> bool {
+        or_log("get registration", self.storage.registration(user_id).await).is_some()
+    }
+
+    pub async fn register(&self, user_id: u64, code: &str) -> anyhow::Result<()> {
+        self.storage.register(user_id, code).await
+    }
+
+    /** Stores a profile from Telegram data once, so the board and broadcasts see the user **/
+    pub async fn ensure_local_profile(&self, user: &User) -> bool {
+        match self.storage.get_user(user.id.0).await {
+            Ok(Some(_)) => true,
+            Ok(None) | Err(_) => match self
+                .storage
+                .put_user(user.id.0, &Vas3kUser::local(user))
+                .await
+            {
+                Ok(_) => true,
+                Err(e) => {
+                    self.report_failure("store user", e).await;
+                    false
+                }
+            },
+        }
+    }
+
+    pub async fn try_submit_flag<S: AsRef<str>>(
+        &self,
+        user_id: u64,
+        text: S,
+    ) -> anyhow::Result<SubmissionResult> {
+        let try_flag = hash_flag(&self.config().flag_salt, text.as_ref());
+        let event = self.event_id();
+        let task = match self.storage.task_by_flag(&event, &try_flag).await? {
+            Some(id) => self.storage.get_task(&event, &id).await?,
+            None => None,
+        };
+        if let Some(task) = task {
+            // testers stay out of the board and the per-task stats
+            let solved = self
+                .storage
+                .add_solve(
+                    &event,
+                    user_id,
+                    &task.id,
+                    unix_now(),
+                    !self.is_test_user(user_id),
+                )
+                .await?;
+            let (ret, kind) = if solved {
+                (SubmissionResult::Solved(task.name), AttemptKind::Solved)
+            } else {
+                (SubmissionResult::AlreadySolved, AttemptKind::Repeated)
+            };
+            self.log_attempt(&event, user_id, kind, Some(task.id)).await;
+            return Ok(ret);
+        }
+        self.log_attempt(&event, user_id, AttemptKind::Wrong, None)
+            .await;
+        Ok(SubmissionResult::NotAFlag)
+    }
+
+    async fn log_attempt(
+        &self,
+        event: &str,
+        user_id: u64,
+        kind: AttemptKind,
+        task: Option<String>,
+    ) {
+        let attempt = Attempt {
+            user_id,
+            time: unix_now(),
+            kind,
+            task,
+            // staff tries are kept in the log but not in the counters behind /stats
+            counted: !self.is_staff(user_id),
+        };
+        if let Err(e) = self.storage.log_attempt(event, &attempt).await {
+            self.report_failure("log attempt", e).await;
+        }
+    }
+
+    async fn display_user(&self, user_id: u64) -> String {
+        match or_log("get user", self.storage.get_user(user_id).await) {
+            Some(user) => user.to_string(),
+            None => user_id.to_string(),
+        }
+    }
+
+    /** Per-task solve counts and first blood come from solve records, wrong tries from the attempt counters **/
+    pub async fn get_stats(&self) -> Stats {
+        let event = self.event_id();
+        let disqualified = self.disqualified().await;
+        let mut tasks = Vec::new();
+        for task_id in self.get_task_ids().await {
+            let Some(task) = self.get_task(&task_id).await else {
+                continue;
+            };
+            let (solves, first) = or_log(
+                "count task solves",
+                self.storage
+                    .task_solves(&event, &task_id, &disqualified)
+                    .await,
+            );
+            let first_blood = match first {
+                Some((user_id, time)) => Some(FirstBlood {
+                    user: self.display_user(user_id).await,
+                    time,
+                }),
+                None => None,
+            };
+            tasks.push(TaskStats {
+                task,
+                solves,
+                first_blood,
+            });
+        }
+        tasks.sort_by(|x, y| y.solves.cmp(&x.solves).then(x.task.name.cmp(&y.task.name)));
+
+        let counters = or_log(
+            "count attempts",
+            self.storage.attempt_counters(&event).await,
+        );
+        let wrong_attempts = counters.wrong.iter().map(|x| x.1).sum();
+        let mut top_wrong = Vec::new();
+        for (user_id, count) in counters.wrong.into_iter().take(5) {
+            top_wrong.push((self.display_user(user_id).await, count));
+        }
+
+        Stats {
+            tasks,
+            participants: counters.participants,
+            attempts: counters.attempts,
+            wrong_attempts,
+            top_wrong,
+        }
+    }
+
+    async fn is_solved<S: AsRef<str>>(&self, user_id: u64, task_id: S) -> bool {
+        or_log(
+            "check solve",
+            self.storage
+                .is_solved(&self.event_id(), user_id, task_id.as_ref())
+                .await,
+        )
+    }
+
+    /** The place is counted on the same list `get_scoreboard` shows **/
+    pub async fn get_score(&self, user_id: u64) -> (u64, u64) {
+        let event = self.event_id();
+        let score = or_log(
+            "count solves",
+            self.storage.solves_count(&event, user_id).await,
+        );
+        if self.is_test_user(user_id) {
+            return (u64::MAX, score);
+        }
+        let disqualified = self.disqualified().await;
+        let ranked = or_log("get scores", self.storage.scores(&event).await)
+            .into_iter()
+            .filter(|(id, _)| !self.is_test_user(*id) && !disqualified.contains(id))
+            .map(|(id, _)| id)
+            .collect::<Vec<u64>>();
+        let place = match ranked.iter().position(|x| *x == user_id) {
+            Some(rank) => rank as u64 + 1,
+            None => ranked.len() as u64 + 1,
+        };
+        (place, score)
+    }
+
+    /** Users with an active disqualification, they keep their solves but stay off the board **/
+    async fn disqualified(&self) -> HashSet<u64> {
+        self.sanctioned(SanctionKind::Disqualify).await
+    }
+
+    /** Users with an active sanction of the kind **/
+    async fn sanctioned(&self, kind: SanctionKind) -> HashSet<u64> {
+        let now = unix_now();
+        or_log("list sanctions", self.storage.all_sanctions().await)
+            .into_iter()
+            .filter(|(_, x)| x.kind == kind && x.is_active(now))
+            .map(|(user_id, _)| user_id)
+            .collect()
+    }
+
+    fn new_task_id() -> String {
+        uuid::Uuid::new_v4()
+            .to_string()
+            .split('-')
+            .next()
+            .unwrap()
+            .to_owned()
+    }
+
+    /** `plain` are the flags as the admin typed them, the storage only reports hashes **/
+    fn check_task_write(&self, write: TaskWrite, plain: &FlagType) -> anyhow::Result<()> {
+        match write {
+            TaskWrite::FlagTaken(hash, id) => {
+                let flag = plain
+                    .iter()
+                    .find(|x| hash_flag(&self.config().flag_salt, x) == hash)
+                    .cloned()
+                    .unwrap_or(hash);
+                bail!(r"Флаг {flag} уже используется в задании {id}")
+            }
+            TaskWrite::IdTaken => bail!(r"Задание с таким ID уже существует"),
+            TaskWrite::Done => Ok(()),
+        }
+    }
+
+    /** Returns the stored task and its flags in plaintext, the only time they are shown **/
+    pub async fn create_task<S: AsRef<str>>(&self, text: S) -> anyhow::Result<(Task, FlagType)> {
+        let mut task = Self::string_to_task(text)?;
+        let plain = task.flag.clone();
+        if matches!(plain, FlagType::Single(ref s) if s == KEEP_FLAGS) {
+            bail!(r"{KEEP_FLAGS} оставляет прежние флаги при редактировании, флагом быть не может")
+        }
+        task.flag = hash_flags(&self.config().flag_salt, &plain);
+        task.hashed = true;
+        loop {
+            task.id = Self::new_task_id();
+            match self.storage.create_task(&self.event_id(), &task).await? {
+                TaskWrite::IdTaken => continue,
+                write => self.check_task_write(write, &plain)?,
+            }
+            return Ok((task, plain));
+        }
+    }
+
+    /** Task of the current event the flag belongs to, for admins who have the original **/
+    pub async fn reveal_flag<S: AsRef<str>>(&self, text: S) -> anyhow::Result<Option<Task>> {
+        let event = self.event_id();
+        let hash = hash_flag(&self.config().flag_salt, text.as_ref());
+        match self.storage.task_by_flag(&event, &hash).await? {
+            Some(id) => self.storage.get_task(&event, &id).await,
+            None => Ok(None),
+        }
+    }
+
+    async fn get_task_ids(&self) -> Vec<String> {
+        or_log("list tasks", self.storage.task_ids(&self.event_id()).await)
+    }
+
+    pub async fn list_tasks(&self, user_id: u64) -> Vec<Task> {
+        let mut tasks = Vec::new();
+        for task_id in self.get_task_ids().await {
+            if (user_id == 0 || !self.is_solved(user_id, &task_id).await)
+                && let Some(task) = self.get_task(&task_id).await
+                && !task.hidden
+            {
+                tasks.push(task);
+            }
+        }
+        tasks.sort_by(|x, y| x.name.cmp(&y.name));
+        tasks
+    }
+
+    pub async fn get_task<S: AsRef<str>>(&self, name: S) -> Option<Task> {
+        or_log(
+            "get task",
+            self.storage.get_task(&self.event_id(), name.as_ref()).await,
+        )
+    }
+
+    pub async fn append_to_contact<S: AsRef<str>>(
+        &self,
+        user_id: u64,
+        text: S,
+    ) -> anyhow::Result<()> {
+        self.storage.append_contact(user_id, text.as_ref()).await
+    }
+
+    pub async fn retrieve_and_erase_contact(&self, user_id: u64) -> anyhow::Result<String> {
+        self.storage.take_contact(user_id).await
+    }
+
+    pub async fn retrieve_contact(&self, user_id: u64) -> anyhow::Result<String> {
+        self.storage.get_contact(user_id).await
+    }
+
+    pub async fn get_all_users(&self) -> Vec<u64> {
+        or_log("list users", self.storage.user_ids().await)
+    }
+
+    fn string_to_task<S: AsRef<str>>(text: S) -> anyhow::Result<Task> {
+        let lines = text
             .as_ref()
             .lines()
             .map(|x| x.trim().to_string())
@@ -491,7 +1460,7 @@ impl Api {
         let flag = {
             let flag_str = lines[1]
                 .split(',')
-                .map(|x| x.trim().to_lowercase())
+                .map(normalize_flag)
                 .collect::<Vec<String>>();
             if flag_str.len() == 1 {
                 FlagType::Single(flag_str.into_iter().next().unwrap())
@@ -514,6 +1483,7 @@ impl Api {
             hidden,
             hint: hint.trim().to_owned(),
             id: String::new(),
+            hashed: false,
         })
     }
 
@@ -522,46 +1492,883 @@ impl Api {
         task_id: S1,
         text: S2,
     ) -> anyhow::Result<()> {
-        let task = Self::string_to_task(text)?;
-        let key = format!("task:{}", task_id.as_ref());
-        // lock
-        let mut val = self.mutex.lock().await;
-        *val = true;
-        self.put_into_cache(&key, &task).await;
-        *val = false;
-        drop(val);
-        Ok(())
+        let event = self.event_id();
+        let mut task = Self::string_to_task(text)?;
+        task.id = String::from(task_id.as_ref());
+        let plain = task.flag.clone();
+        if matches!(plain, FlagType::Single(ref s) if s == KEEP_FLAGS) {
+            let Some(old) = self.storage.get_task(&event, &task.id).await? else {
+                bail!(r"Задание {} не найдено", task.id)
+            };
+            task.flag = old.flag;
+        } else {
+            task.flag = hash_flags(&self.config().flag_salt, &plain);
+        }
+        task.hashed = true;
+        let write = self.storage.update_task(&event, &task).await?;
+        self.check_task_write(write, &plain)
     }
     pub async fn delete_task<S1: AsRef<str>>(&self, task_id: S1) -> anyhow::Result<()> {
-        let key = format!("task:{}", task_id.as_ref());
-        // lock
-        let mut val = self.mutex.lock().await;
-        *val = true;
-        self.del_from_cache(&key).await;
-        *val = false;
-        drop(val);
-        Ok(())
+        self.storage
+            .delete_task(&self.event_id(), task_id.as_ref())
+            .await
     }
 
-    pub async fn get_scoreboard(&self) -> Vec<(Vas3kUser, u64)> {
+    /** Board of any event, users without solves are listed only for the current one **/
+    pub async fn get_scoreboard(&self, event: &str) -> Vec<(Vas3kUser, u64)> {
+        let mut seen = self.disqualified().await;
         let mut ret: Vec<(Vas3kUser, u64)> = Vec::new();
-        for key in self.get_keys("user:*").await {
-            let Some(user) = self.collect_from_cache::<Vas3kUser>(&key).await else {
+        for (user_id, score) in or_log("get scores", self.storage.scores(event).await) {
+            if self.is_test_user(user_id) || seen.contains(&user_id) {
                 continue;
-            };
-            let solv_key = format!("solve:{}", key.strip_prefix("user:").unwrap());
-            let score = match self.collect_from_cache::<Solve>(&solv_key).await {
-                Some(solve) => solve.into(),
-                None => 0u64,
-            };
-            if self.is_test_user(user.telegram_id as u64) {
-                ret.push((user, 0));
-            } else {
+            }
+            seen.insert(user_id);
+            if let Some(user) = or_log("get user", self.storage.get_user(user_id).await) {
                 ret.push((user, score));
             }
         }
-        ret.sort_by(|x, y| y.1.cmp(&x.1));
+        if event != self.event_id() {
+            return ret;
+        }
+        // users without solves and the test group go to the bottom with zero
+        for user_id in self.get_all_users().await {
+            if seen.contains(&user_id) {
+                continue;
+            }
+            if let Some(user) = or_log("get user", self.storage.get_user(user_id).await) {
+                ret.push((user, 0));
+            }
+        }
+        ret
+    }
+
+    pub async fn list_events(&self) -> Vec<Event> {
+        let mut events = Vec::new();
+        for id in or_log("list events", self.storage.event_ids().await) {
+            if let Some(event) = or_log("get event", self.storage.get_event(&id).await) {
+                events.push(event);
+            }
+        }
+        events.sort_by(|x, y| x.start.cmp(&y.start).then(x.id.cmp(&y.id)));
+        events
+    }
+
+    pub async fn get_event<S: AsRef<str>>(&self, id: S) -> Option<Event> {
+        or_log("get event", self.storage.get_event(id.as_ref()).await)
+    }
+
+    fn string_to_event<S: AsRef<str>>(text: S) -> anyhow::Result<Event> {
+        let lines = text
+            .as_ref()
+            .lines()
+            .map(|x| x.trim())
+            .collect::<Vec<&str>>();
+        if lines.len() != 4 {
+            bail!(r"Должно быть 4 строки: ID, название, начало и конец (unixtime).")
+        }
+        if lines[0].is_empty()
+            || !lines[0]
+                .chars()
+                .all(|x| x.is_ascii_lowercase() || x.is_ascii_digit() || x == '-')
+        {
+            bail!(r"ID события может содержать только a-z, 0-9 и -")
+        }
+        let (Ok(start), Ok(end)) = (lines[2].parse::<u64>(), lines[3].parse::<u64>()) else {
+            bail!(r"Начало и конец должны быть в unixtime")
+        };
+        if start >= end {
+            bail!(r"Начало должно быть раньше конца")
+        }
+        Ok(Event {
+            id: lines[0].to_owned(),
+            name: lines[1].to_owned(),
+            start,
+            end,
+            ..Default::default()
+        })
+    }
+
+    /** Active sanction of the kind, expired ones are ignored rather than removed **/
+    pub async fn active_sanction(&self, user_id: u64, kind: SanctionKind) -> Option<Sanction> {
+        or_log("get sanctions", self.storage.sanctions(user_id).await)
+            .into_iter()
+            .find(|x| x.kind == kind && x.is_active(unix_now()))
+    }
+
+    /** Active sanctions with the user they are on, newest first **/
+    pub async fn list_sanctions(&self) -> Vec<(String, Sanction)> {
+        let now = unix_now();
+        let mut ret = Vec::new();
+        for (user_id, sanction) in or_log("list sanctions", self.storage.all_sanctions().await) {
+            if sanction.is_active(now) {
+                ret.push((self.display_user(user_id).await, sanction));
+            }
+        }
+        ret.sort_by_key(|x| std::cmp::Reverse(x.1.time));
+        ret
+    }
+
+    /** The last admin actions with the user they were about **/
+    pub async fn audit_log(&self) -> Vec<(String, AuditEntry)> {
+        let mut ret = Vec::new();
+        for entry in or_log("read audit", self.storage.audit(AUDIT_SIZE).await) {
+            ret.push((self.display_id(entry.user_id).await, entry));
+        }
+        ret
+    }
+
+    /** The last messages Telegram refused with the chat they were for **/
+    pub async fn dead_letters(&self) -> Vec<(String, DeadLetter)> {
+        let mut ret = Vec::new();
+        for letter in or_log(
+            "read dead letters",
+            self.outbox.dead_letters(AUDIT_SIZE).await,
+        ) {
+            ret.push((self.display_id(letter.message.chat).await, letter));
+        }
+        ret
+    }
+
+    /** A chat that writes to the bot gets messages again after it was unreachable **/
+    pub async fn mark_reachable(&self, chat: i64) -> anyhow::Result<()> {
+        self.outbox.mark_reachable(chat).await
+    }
+
+    /** Chats have negative IDs and no profile **/
+    async fn display_id(&self, id: i64) -> String {
+        if id > 0 {
+            self.display_user(id as u64).await
+        } else {
+            id.to_string()
+        }
+    }
+
+    async fn audit(&self, admin: u64, user_id: i64, action: &str, reason: &str, until: u64) {
+        let entry = AuditEntry {
+            time: unix_now(),
+            admin,
+            user_id,
+            action: String::from(action),
+            reason: String::from(reason),
+            until,
+        };
+        if let Err(e) = self.storage.log_audit(&entry).await {
+            self.report_failure("write audit", e).await;
+        }
+    }
+
+    fn parse_user_id(line: &str) -> anyhow::Result<u64> {
+        match line.parse::<u64>() {
+            Ok(user_id) if user_id != 0 => Ok(user_id),
+            _ => bail!(r"Первая строка - Telegram ID участника"),
+        }
+    }
+
+    /** Lines: user, reason and an optional expiry in unixtime **/
+    pub async fn sanction_user<S: AsRef<str>>(
+        &self,
+        admin: u64,
+        kind: SanctionKind,
+        text: S,
+    ) -> anyhow::Result<(u64, Sanction)> {
+        let lines = text
+            .as_ref()
+            .lines()
+            .map(|x| x.trim())
+            .collect::<Vec<&str>>();
+        if lines.len() != 2 && lines.len() != 3 {
+            bail!(r"Должно быть 2 или 3 строки: Telegram ID, причина и срок (unixtime).")
+        }
+        let user_id = Self::parse_user_id(lines[0])?;
+        if lines[1].is_empty() {
+            bail!(r"Нужна причина")
+        }
+        let until = match lines.get(2) {
+            None => 0,
+            Some(line) => match line.parse::<u64>() {
+                Ok(until) if until == 0 || until > unix_now() => until,
+                _ => bail!(r"Срок должен быть в будущем, в unixtime, 0 - навсегда"),
+            },
+        };
+        if self.is_admin(user_id) {
+            bail!(r"Администратора наказать нельзя")
+        }
+        let sanction = Sanction {
+            kind,
+            reason: lines[1].to_owned(),
+            until,
+            admin,
+            time: unix_now(),
+        };
+        self.storage.put_sanction(user_id, &sanction).await?;
+        self.audit(
+            admin,
+            user_id as i64,
+            kind.as_str(),
+            &sanction.reason,
+            until,
+        )
+        .await;
+        Ok((user_id, sanction))
+    }
+
+    /** Lines: user, ban, mute or disqualify, and the reason **/
+    pub async fn lift_sanction<S: AsRef<str>>(
+        &self,
+        admin: u64,
+        text: S,
+    ) -> anyhow::Result<(u64, SanctionKind)> {
+        let lines = text
+            .as_ref()
+            .lines()
+            .map(|x| x.trim())
+            .collect::<Vec<&str>>();
+        if lines.len() != 3 {
+            bail!(r"Должно быть 3 строки: Telegram ID, ban, mute или disqualify, причина.")
+        }
+        let user_id = Self::parse_user_id(lines[0])?;
+        let kind = match lines[1] {
+            "ban" => SanctionKind::Ban,
+            "mute" => SanctionKind::Mute,
+            "disqualify" => SanctionKind::Disqualify,
+            _ => bail!(r"Вторая строка - ban, mute или disqualify"),
+        };
+        if !self.storage.remove_sanction(user_id, kind).await? {
+            bail!(r"Такого наказания у участника нет")
+        }
+        self.audit(
+            admin,
+            user_id as i64,
+            &format!("lift {}", kind.as_str()),
+            lines[2],
+            0,
+        )
+        .await;
+        Ok((user_id, kind))
+    }
+
+    /** Every role with whom it is given to, sorted by role **/
+    pub async fn list_roles(&self) -> Vec<(Role, String)> {
+        let mut ret = Vec::new();
+        for role in Role::ALL {
+            for id in self.role_ids(role) {
+                ret.push((role, self.display_id(id).await));
+            }
+        }
+        ret
+    }
+
+    /** Lines: Telegram ID (negative for a chat) and admin, moderator, tester or notify **/
+    fn parse_role(text: &str) -> anyhow::Result<(i64, Role)> {
+        let lines = text.lines().map(|x| x.trim()).collect::<Vec<&str>>();
+        if lines.len() != 2 {
+            bail!(r"Должно быть 2 строки: Telegram ID и роль.")
+        }
+        let id = match lines[0].parse::<i64>() {
+            Ok(id) if id != 0 => id,
+            _ => bail!(r"Первая строка - Telegram ID пользователя или чата"),
+        };
+        match Role::parse(lines[1]) {
+            Some(role) => Ok((id, role)),
+            None => bail!(r"Вторая строка - admin, moderator, tester или notify"),
+        }
+    }
+
+    pub async fn grant_role<S: AsRef<str>>(
+        &self,
+        admin: u64,
+        text: S,
+    ) -> anyhow::Result<(i64, Role)> {
+        if !self.is_super_admin(admin) {
+            bail!(r"Роли выдаёт только главный администратор")
+        }
+        let (id, role) = Self::parse_role(text.as_ref())?;
+        if !self.storage.grant_role(id, role).await? {
+            // granting a role seeded from the config keeps it when the ID leaves the group
+            let seeded = self.storage.config_roles().await?;
+            if !seeded.contains(&(id, role)) {
+                bail!(r"Эта роль уже выдана")
+            }
+            self.storage.set_config_role(id, role, false).await?;
+        }
+        self.roles
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert((id, role));
+        self.audit(admin, id, &format!("grant {}", role.as_str()), "", 0)
+            .await;
+        Ok((id, role))
+    }
+
+    pub async fn revoke_role<S: AsRef<str>>(
+        &self,
+        admin: u64,
+        text: S,
+    ) -> anyhow::Result<(i64, Role)> {
+        if !self.is_super_admin(admin) {
+            bail!(r"Роли отзывает только главный администратор")
+        }
+        let (id, role) = Self::parse_role(text.as_ref())?;
+        if !self.storage.revoke_role(id, role).await? {
+            bail!(r"Такой роли нет")
+        }
+        self.roles
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&(id, role));
+        self.audit(admin, id, &format!("revoke {}", role.as_str()), "", 0)
+            .await;
+        Ok((id, role))
+    }
+
+    /** Lines: Telegram ID of the user and the answer **/
+    pub async fn reply_to_user<S: AsRef<str>>(&self, text: S) -> anyhow::Result<u64> {
+        let Some((id, answer)) = text.as_ref().split_once('\n') else {
+            bail!(r"Должно быть 2+ строки: Telegram ID и ответ.")
+        };
+        let user_id = Self::parse_user_id(id.trim())?;
+        if answer.trim().is_empty() {
+            bail!(r"Нужен ответ")
+        }
+        self.send_message(user_id as i64, Format::format_reply(answer.trim()))
+            .await?;
+        Ok(user_id)
+    }
+
+    /** Invites sorted by creation, each with the users who joined with it **/
+    pub async fn list_invites(&self) -> Vec<(Invite, Vec<String>)> {
+        let mut invites = Vec::new();
+        for code in or_log("list invites", self.storage.invite_codes().await) {
+            let Some(invite) = or_log("get invite", self.storage.get_invite(&code).await) else {
+                continue;
+            };
+            let mut users = Vec::new();
+            for user_id in or_log("list invite users", self.storage.code_users(&code).await) {
+                users.push(self.display_user(user_id).await);
+            }
+            invites.push((invite, users));
+        }
+        invites.sort_by_key(|x| x.0.created);
+        invites
+    }
+
+    /** Lines: max uses (0 for no limit), expiry in unixtime (0 for never) and an optional code **/
+    fn string_to_invite<S: AsRef<str>>(text: S) -> anyhow::Result<Invite> {
+        let lines = text
+            .as_ref()
+            .lines()
+            .map(|x| x.trim())
+            .collect::<Vec<&str>>();
+        if lines.len() != 2 && lines.len() != 3 {
+            bail!(r"Должно быть 2 или 3 строки: число использований, срок (unixtime) и код.")
+        }
+        let (Ok(max_uses), Ok(expires)) = (lines[0].parse::<u64>(), lines[1].parse::<u64>()) else {
+            bail!(r"Число использований и срок должны быть числами, 0 - без ограничений")
+        };
+        let now = unix_now();
+        if expires != 0 && expires <= now {
+            bail!(r"Срок уже прошёл")
+        }
+        let code = match lines.get(2) {
+            Some(code) => code.to_string(),
+            None => Self::new_task_id(),
+        };
+        if code.is_empty()
+            || code.len() > 32
+            || !code
+                .chars()
+                .all(|x| x.is_ascii_alphanumeric() || x == '-' || x == '_')
+        {
+            bail!(r"Код - до 32 символов a-z, A-Z, 0-9, - и _")
+        }
+        Ok(Invite {
+            code,
+            max_uses,
+            uses: 0,
+            expires,
+            created: now,
+            revoked: false,
+        })
+    }
+
+    pub async fn create_invite<S: AsRef<str>>(&self, text: S) -> anyhow::Result<Invite> {
+        let invite = Self::string_to_invite(text)?;
+        if !self.storage.create_invite(&invite).await? {
+            bail!(r"Такой код приглашения уже существует")
+        }
+        Ok(invite)
+    }
+
+    pub async fn revoke_invite<S: AsRef<str>>(&self, code: S) -> anyhow::Result<Invite> {
+        let Some(mut invite) = self.storage.get_invite(code.as_ref()).await? else {
+            bail!(r"Код приглашения не найден")
+        };
+        invite.revoked = true;
+        self.storage.update_invite(&invite).await?;
+        Ok(invite)
+    }
+
+    pub async fn create_event<S: AsRef<str>>(&self, text: S) -> anyhow::Result<Event> {
+        let event = Self::string_to_event(text)?;
+        if !self.storage.create_event(&event).await? {
+            bail!(r"Событие с таким ID уже существует")
+        }
+        Ok(event)
+    }
+
+    /** New tasks, solves and the event clock all follow the current event **/
+    pub async fn switch_event<S: AsRef<str>>(&self, id: S) -> anyhow::Result<Event> {
+        let Some(event) = self.storage.get_event(id.as_ref()).await? else {
+            bail!(r"Событие не найдено")
+        };
+        if event.archived {
+            bail!(r"Событие в архиве")
+        }
+        self.storage.set_current_event(&event.id).await?;
+        *self.event.write().unwrap_or_else(|e| e.into_inner()) = event.clone();
+        Ok(event)
+    }
+
+    /** Changes the current event in the storage and in the copy **/
+    async fn update_current_event<F>(&self, change: F) -> anyhow::Result<Event>
+    where
+        F: FnOnce(&mut Event, u64) -> anyhow::Result<()>,
+    {
+        let mut event = self.current_event();
+        change(&mut event, unix_now())?;
+        self.storage.update_event(&event).await?;
+        *self.event.write().unwrap_or_else(|e| e.into_inner()) = event.clone();
+        info!(
+            "Event {} clock: {} - {}, paused: {}",
+            event.id, event.start, event.end, event.paused
+        );
+        Ok(event)
+    }
+
+    pub async fn start_event_now(&self) -> anyhow::Result<Event> {
+        self.update_current_event(|event, now| {
+            if event.clock(now) != Clock::NotStarted {
+                bail!(r"Событие уже началось")
+            }
+            if now + 1 >= event.end {
+                bail!(r"Конец события уже прошёл, сначала перенеси его")
+            }
+            event.start = now;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn pause_event(&self) -> anyhow::Result<Event> {
+        self.update_current_event(|event, now| {
+            if event.clock(now) != Clock::Running {
+                bail!(r"Событие сейчас не идёт")
+            }
+            event.paused = true;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn resume_event(&self) -> anyhow::Result<Event> {
+        self.update_current_event(|event, _| {
+            if !event.paused {
+                bail!(r"Событие не на паузе")
+            }
+            event.paused = false;
+            Ok(())
+        })
+        .await
+    }
+
+    /** Unixtime, or +N / -N minutes from the current end **/
+    pub async fn set_event_end<S: AsRef<str>>(&self, text: S) -> anyhow::Result<(Event, u64)> {
+        let text = text.as_ref().trim();
+        let mut old = 0;
+        let event = self
+            .update_current_event(|event, now| {
+                let end = if let Some(minutes) = text.strip_prefix('+') {
+                    minutes
+                        .parse::<u64>()
+                        .ok()
+                        .map(|x| event.end.saturating_add(x * 60))
+                } else if let Some(minutes) = text.strip_prefix('-') {
+                    minutes
+                        .parse::<u64>()
+                        .ok()
+                        .map(|x| event.end.saturating_sub(x * 60))
+                } else {
+                    text.parse::<u64>().ok()
+                };
+                let Some(end) = end else {
+                    bail!(r"Конец - unixtime, или +N / -N минут")
+                };
+                if end <= now || end <= event.start {
+                    bail!(r"Конец должен быть в будущем и после начала")
+                }
+                old = event.end;
+                event.end = end;
+                Ok(())
+            })
+            .await?;
+        Ok((event, old))
+    }
+
+    /** Sends the same text to every user who has ever written to the bot **/
+    pub async fn announce<S: AsRef<str>>(&self, message: S) -> anyhow::Result<()> {
+        for chat in self.segment_users(&Segment::All).await? {
+            let mut message = Message::from((chat, message.as_ref()));
+            message.priority = Priority::Bulk;
+            self.outbox.push(message).await?;
+        }
+        Ok(())
+    }
+
+    /** Users of a segment in the current event, banned users and unreachable chats left out **/
+    pub async fn segment_users(&self, segment: &Segment) -> anyhow::Result<Vec<i64>> {
+        let event = self.event_id();
+        let users = self.get_all_users().await.into_iter().filter(|x| *x != 0);
+        let mut ret: Vec<i64> = Vec::new();
+        match segment {
+            Segment::All => ret.extend(users.map(|x| x as i64)),
+            Segment::NoSolves => {
+                for user_id in users {
+                    if self.storage.solves_count(&event, user_id).await? == 0 {
+                        ret.push(user_id as i64);
+                    }
+                }
+            }
+            Segment::Unsolved(task) => {
+                if self.storage.get_task(&event, task).await?.is_none() {
+                    bail!(r"Задание не найдено")
+                }
+                for user_id in users {
+                    if !self.storage.is_solved(&event, user_id, task).await? {
+                        ret.push(user_id as i64);
+                    }
+                }
+            }
+            Segment::Top(count) => ret.extend(
+                self.get_scoreboard(&event)
+                    .await
+                    .into_iter()
+                    .filter(|(_, score)| *score > 0)
+                    .take(*count)
+                    .map(|(user, _)| user.telegram_id),
+            ),
+            Segment::Testers => ret.extend(self.role_ids(Role::Tester)),
+        }
+        let banned = self.sanctioned(SanctionKind::Ban).await;
+        ret.retain(|x| !banned.contains(&(*x as u64)) && !self.outbox.is_unreachable(*x));
+        Ok(ret)
+    }
+
+    /** Queues a /message to a segment as a broadcast, returns it with the number of recipients **/
+    pub async fn broadcast<S: AsRef<str>>(
+        &self,
+        admin: u64,
+        text: S,
+        segment: &Segment,
+    ) -> anyhow::Result<(Broadcast, usize)> {
+        let chats = self.segment_users(segment).await?;
+        if chats.is_empty() {
+            bail!(r"Некому отправлять, в сегменте никого нет")
+        }
+        let mut broadcast = Broadcast {
+            id: 0,
+            admin,
+            text: String::from(text.as_ref()),
+            time: unix_now(),
+            segment: segment.to_string(),
+            reported: 0,
+        };
+        broadcast.id = self.storage.create_broadcast(&broadcast, &chats).await?;
+        for chat in chats.iter() {
+            self.push_broadcast(&broadcast, *chat).await?;
+        }
+        Ok((broadcast, chats.len()))
+    }
 
+    async fn push_broadcast(&self, broadcast: &Broadcast, chat: i64) -> anyhow::Result<()> {
+        let text = Format::format_message_broadcast(&broadcast.text);
+        let mut message = Message::from((chat, text));
+        message.broadcast = Some(broadcast.id);
+        message.priority = Priority::Bulk;
+        self.outbox.push(message).await
+    }
+
+    /** The last broadcasts with their totals **/
+    pub async fn broadcasts(&self) -> Vec<(Broadcast, DeliveryStats)> {
+        let mut ret = Vec::new();
+        for broadcast in or_log(
+            "list broadcasts",
+            self.storage.broadcasts(BROADCASTS_SHOWN).await,
+        ) {
+            let deliveries = or_log(
+                "read deliveries",
+                self.storage.deliveries(broadcast.id).await,
+            );
+            ret.push((broadcast, DeliveryStats::count(&deliveries)));
+        }
         ret
     }
+
+    /** Unixtime, +N minutes from now, or HH:MM (the next one) and YYYY-MM-DD HH:MM in UTC **/
+    fn parse_send_time(text: &str, now: u64) -> anyhow::Result<u64> {
+        let text = text.trim();
+        let time = if let Some(minutes) = text.strip_prefix('+') {
+            minutes
+                .parse::<u64>()
+                .ok()
+                .map(|x| now.saturating_add(x * 60))
+        } else if let Ok(time) = text.parse::<u64>() {
+            Some(time)
+        } else if let Ok(time) = NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M") {
+            Some(time.and_utc().timestamp().max(0) as u64)
+        } else if let Ok(time) = NaiveTime::parse_from_str(text, "%H:%M") {
+            DateTime::from_timestamp(now as i64, 0).map(|today| {
+                let time = today
+                    .date_naive()
+                    .and_time(time)
+                    .and_utc()
+                    .timestamp()
+                    .max(0) as u64;
+                if time <= now { time + 24 * 3600 } else { time }
+            })
+        } else {
+            None
+        };
+        let Some(time) = time else {
+            bail!(r"Время - unixtime, +N минут, ЧЧ:ММ или ГГГГ-ММ-ДД ЧЧ:ММ по UTC")
+        };
+        if time <= now {
+            bail!(r"Время должно быть в будущем")
+        }
+        Ok(time)
+    }
+
+    /** Schedules a broadcast from 3+ lines: send time, segment and the message **/
+    pub async fn add_schedule<S: AsRef<str>>(
+        &self,
+        admin: u64,
+        text: S,
+    ) -> anyhow::Result<Schedule> {
+        let lines = text.as_ref().lines().collect::<Vec<&str>>();
+        if lines.len() < 3 {
+            bail!(r"Должно быть 3+ строки: время, сегмент и сообщение")
+        }
+        let now = unix_now();
+        let time = Self::parse_send_time(lines[0], now)?;
+        let segment = Segment::parse(lines[1].trim())?;
+        // an unknown task is refused now rather than at send time
+        self.segment_users(&segment).await?;
+        let message = lines[2..].join("\n");
+        if message.trim().is_empty() {
+            bail!(r"Сообщение пустое")
+        }
+        let mut schedule = Schedule {
+            id: 0,
+            admin,
+            time,
+            segment: segment.to_string(),
+            text: message,
+            created: now,
+        };
+        schedule.id = self.storage.add_schedule(&schedule).await?;
+        Ok(schedule)
+    }
+
+    /** Pending schedules, the nearest first **/
+    pub async fn schedules(&self) -> Vec<Schedule> {
+        let mut ret = or_log("list schedules", self.storage.schedules().await);
+        ret.sort_by_key(|x| (x.time, x.id));
+        ret
+    }
+
+    pub async fn cancel_schedule(&self, id: u64) -> anyhow::Result<()> {
+        if !self.storage.remove_schedule(id).await? {
+            bail!(r"Запланированная рассылка не найдена, возможно, она уже отправлена")
+        }
+        Ok(())
+    }
+
+    /** Sends due schedules as broadcasts, those missed while the bot was down go out at start **/
+    pub async fn run_schedules(self: Arc<Self>) {
+        loop {
+            tokio::time::sleep(SCHEDULE_TICK).await;
+            if let Err(e) = self.dispatch_schedules().await {
+                self.report_failure("send scheduled broadcasts", e).await;
+            }
+        }
+    }
+
+    async fn dispatch_schedules(&self) -> anyhow::Result<()> {
+        let now = unix_now();
+        for schedule in self.storage.schedules().await? {
+            // removed before sending, so a failure is reported once instead of every tick
+            if schedule.time > now || !self.storage.remove_schedule(schedule.id).await? {
+                continue;
+            }
+            let sent = match Segment::parse(&schedule.segment) {
+                Ok(segment) => {
+                    self.broadcast(schedule.admin, &schedule.text, &segment)
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            let reply = match sent {
+                Ok((broadcast, total)) => {
+                    info!(
+                        "Schedule {} sent as broadcast {}",
+                        schedule.id, broadcast.id
+                    );
+                    Format::format_schedule_sent(&schedule, &broadcast, total)
+                }
+                Err(e) => {
+                    warn!("Schedule {} failed: {e}", schedule.id);
+                    Format::format_schedule_failed(&schedule, e)
+                }
+            };
+            self.send_message(schedule.admin as i64, reply).await?;
+        }
+        Ok(())
+    }
+
+    /** Queues the failed messages of a broadcast again, a new report follows, returns how many
+    were queued and how many were skipped because the chat is unreachable **/
+    pub async fn retry_broadcast(&self, id: u64) -> anyhow::Result<(usize, usize)> {
+        let Some(mut broadcast) = self.storage.get_broadcast(id).await? else {
+            bail!(r"Рассылка не найдена")
+        };
+        let failed: Vec<i64> = self
+            .storage
+            .deliveries(id)
+            .await?
+            .into_iter()
+            .filter(|(_, x)| matches!(x, Delivery::Failed(_)))
+            .map(|(chat, _)| chat)
+            .collect();
+        if failed.is_empty() {
+            bail!(r"В этой рассылке нет недоставленных сообщений")
+        }
+        let (skipped, failed): (Vec<i64>, Vec<i64>) = failed
+            .into_iter()
+            .partition(|x| self.outbox.is_unreachable(*x));
+        if failed.is_empty() {
+            bail!(r"Все недоставленные сообщения были для недоступных чатов, повторять нечего")
+        }
+        for chat in failed.iter() {
+            self.storage
+                .set_delivery(id, *chat, &Delivery::Pending)
+                .await?;
+        }
+        broadcast.reported = 0;
+        self.storage.update_broadcast(&broadcast).await?;
+        for chat in failed.iter() {
+            self.push_broadcast(&broadcast, *chat).await?;
+        }
+        Ok((failed.len(), skipped.len()))
+    }
+
+    pub async fn archive_event<S: AsRef<str>>(&self, id: S) -> anyhow::Result<Event> {
+        let Some(mut event) = self.storage.get_event(id.as_ref()).await? else {
+            bail!(r"Событие не найдено")
+        };
+        if event.id == self.event_id() {
+            bail!(r"Нельзя архивировать текущее событие, сначала переключись на другое")
+        }
+        event.archived = true;
+        self.storage.update_event(&event).await?;
+        Ok(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segments_parse_and_print_back() {
+        for (text, segment) in [
+            ("all", Segment::All),
+            ("zero", Segment::NoSolves),
+            ("unsolved web1", Segment::Unsolved(String::from("web1"))),
+            ("top 10", Segment::Top(10)),
+            ("testers", Segment::Testers),
+        ] {
+            assert_eq!(Segment::parse(text).unwrap(), segment);
+            assert_eq!(Segment::parse(&segment.to_string()).unwrap(), segment);
+        }
+        assert_eq!(Segment::parse("  top   3 ").unwrap(), Segment::Top(3));
+        for text in [
+            "",
+            "everyone",
+            "top",
+            "top 0",
+            "top x",
+            "unsolved",
+            "all users",
+        ] {
+            assert!(Segment::parse(text).is_err(), "{text}");
+        }
+    }
+
+    #[test]
+    fn send_time_forms() {
+        // 2025-06-05 12:00:00 UTC
+        let now = 1749124800;
+        assert_eq!(Api::parse_send_time("+30", now).unwrap(), now + 30 * 60);
+        assert_eq!(
+            Api::parse_send_time(" 1749200000 ", now).unwrap(),
+            1749200000
+        );
+        assert_eq!(
+            Api::parse_send_time("2025-06-06 09:30", now).unwrap(),
+            1749202200
+        );
+        assert_eq!(Api::parse_send_time("13:15", now).unwrap(), now + 75 * 60);
+        // a time that has passed today is tomorrow
+        assert_eq!(Api::parse_send_time("11:00", now).unwrap(), now + 23 * 3600);
+        for text in [
+            "+0",
+            "+x",
+            "100",
+            "2025-06-05 11:59",
+            "25:00",
+            "tomorrow",
+            "",
+        ] {
+            assert!(Api::parse_send_time(text, now).is_err(), "{text}");
+        }
+    }
+
+    #[test]
+    fn tasks_parse_from_lines() {
+        let task = Api::string_to_task("  Web 1 \n Flag{A} \n first\n second ").unwrap();
+        assert_eq!(task.name, "Web 1");
+        assert!(matches!(&task.flag, FlagType::Single(x) if x == "flag{a}"));
+        assert_eq!(task.hint, "first\nsecond");
+        assert!(!task.hidden);
+
+        let task = Api::string_to_task("hidden: Crypto\nf1, F2\nhint").unwrap();
+        assert_eq!(task.name, "Crypto");
+        assert!(task.hidden);
+        assert!(matches!(&task.flag, FlagType::Multi(x) if x == &["f1", "f2"]));
+
+        assert!(Api::string_to_task("name\nflag").is_err());
+
+        let task = Api::string_to_task(format!("name\n {KEEP_FLAGS} \nhint")).unwrap();
+        assert!(matches!(&task.flag, FlagType::Single(x) if x == KEEP_FLAGS));
+    }
+
+    #[test]
+    fn flags_hash_in_normal_form() {
+        let hash = hash_flag("salt", "flag{x}");
+        assert_eq!(hash.len(), 64);
+        assert!(hash.chars().all(|x| x.is_ascii_hexdigit()));
+        assert_eq!(hash_flag("salt", "  FLAG{X} "), hash);
+        assert_ne!(hash_flag("pepper", "flag{x}"), hash);
+        assert_ne!(hash_flag("salt", "flag{y}"), hash);
+        assert_ne!(salt_fingerprint("salt"), salt_fingerprint("pepper"));
+    }
 }
@@ -0,0 +1,112 @@
+/**
+Copyright 2025 Ivan Agarkov
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+**/
+use crate::api::{Api, unix_now};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::sync::Arc;
+use teloxide::types::User;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum EligibilityConfig {
+    /** Active vas3k.club members **/
+    #[default]
+    Vas3k,
+    /** Telegram IDs listed in the config **/
+    Allowlist {
+        users: Vec<i64>,
+    },
+    /** Whoever joined with one of these codes or one from /invite_create **/
+    Invite {
+        codes: Vec<String>,
+    },
+    Open,
+}
+
+/** Decides who may play, every message of a user goes through it **/
+#[async_trait]
+pub trait Eligibility: Send + Sync {
+    async fn is_eligible(&self, api: &Api, user: &User) -> bool;
+    /** Handles `/start <code>`, returns false if the code does not let the user in **/
+    async fn redeem(&self, _api: &Api, _user: &User, _code: &str) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+}
+
+pub struct Vas3kEligibility;
+
+#[async_trait]
+impl Eligibility for Vas3kEligibility {
+    async fn is_eligible(&self, api: &Api, user: &User) -> bool {
+        match api.receive_user_by_telegram(user.id.0).await {
+            Ok(profile) => profile.is_member(unix_now()),
+            Err(_) => false,
+        }
+    }
+}
+
+pub struct AllowlistEligibility {
+    users: Vec<i64>,
+}
+
+#[async_trait]
+impl Eligibility for AllowlistEligibility {
+    async fn is_eligible(&self, api: &Api, user: &User) -> bool {
+        self.users.contains(&(user.id.0 as i64)) && api.ensure_local_profile(user).await
+    }
+}
+
+pub struct InviteEligibility {
+    codes: Vec<String>,
+}
+
+#[async_trait]
+impl Eligibility for InviteEligibility {
+    /** Nobody but the invited guests, whom `Api::check_user_is_in_scope` lets in for every provider **/
+    async fn is_eligible(&self, _api: &Api, _user: &User) -> bool {
+        false
+    }
+
+    async fn redeem(&self, api: &Api, user: &User, code: &str) -> anyhow::Result<bool> {
+        if !self.codes.iter().any(|x| x == code) {
+            return Ok(false);
+        }
+        api.register(user.id.0, code).await?;
+        Ok(api.ensure_local_profile(user).await)
+    }
+}
+
+pub struct OpenEligibility;
+
+#[async_trait]
+impl Eligibility for OpenEligibility {
+    async fn is_eligible(&self, api: &Api, user: &User) -> bool {
+        api.ensure_local_profile(user).await
+    }
+}
+
+pub fn open(config: &EligibilityConfig) -> Arc<dyn Eligibility> {
+    match config {
+        EligibilityConfig::Vas3k => Arc::new(Vas3kEligibility),
+        EligibilityConfig::Allowlist { users } => Arc::new(AllowlistEligibility {
+            users: users.clone(),
+        }),
+        EligibilityConfig::Invite { codes } => Arc::new(InviteEligibility {
+            codes: codes.clone(),
+        }),
+        EligibilityConfig::Open => Arc::new(OpenEligibility),
+    }
+}
@@ -14,28 +14,55 @@
    limitations under the License.
 **/
 mod api;
+mod eligibility;
+mod mock;
 mod sender;
+mod snapshot;
+mod storage;
 mod text;
+mod upstream;
 
-use crate::api::{Api, SubmissionResult};
-use crate::sender::MessageSender;
+use crate::api::{Api, Event, MembershipConfig, SanctionKind, Segment, SubmissionResult};
+use crate::eligibility::EligibilityConfig;
+use crate::sender::{FileKind, FileSource, MessageSender};
+use crate::snapshot::SnapshotConfig;
+use crate::storage::StorageConfig;
 use crate::text::*;
+use anyhow::bail;
+use log::{error, info, warn};
 use serde::Deserialize;
 use std::env;
+use std::path::Path;
 use std::sync::Arc;
 use teloxide::dispatching::{Dispatcher, UpdateFilterExt};
 use teloxide::requests::Requester;
 use teloxide::types::{
-    CallbackQuery, InlineKeyboardButton, InlineKeyboardMarkup, InputFile, MediaKind, Message,
-    MessageKind, Update, User, UserId,
+    CallbackQuery, InlineKeyboardButton, InlineKeyboardMarkup, MediaKind, Message, MessageKind,
+    Update, User,
 };
 use teloxide::{Bot, dptree};
 use tokio::runtime::Builder;
+use tokio::signal::unix::{SignalKind, signal};
 
 fn main() -> anyhow::Result<()> {
     env_logger::try_init()?;
     let rt = Builder::new_current_thread().enable_all().build()?;
-    rt.block_on(rt_main())
+    let args = env::args().skip(1).collect::<Vec<String>>();
+    match args.as_slice() {
+        [] => rt.block_on(rt_main()),
+        [command, path] if command == "snapshot" || command == "restore" => {
+            rt.block_on(rt_snapshot(command, Path::new(path)))
+        }
+        [command, path] if command == "mock" => {
+            rt.block_on(mock::serve(Path::new(path), "127.0.0.1:8080"))
+        }
+        [command, path, addr] if command == "mock" => {
+            rt.block_on(mock::serve(Path::new(path), addr))
+        }
+        _ => bail!(
+            "Usage: v3k-ctf-bot [snapshot <file> | restore <file> | mock <fixtures> [address]]"
+        ),
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -48,25 +75,184 @@ struct Config {
     admin_group: Vec<i64>,
     #[serde(default)]
     notify_group: Vec<i64>,
+    /** May grant and revoke roles, `admin_group` if empty **/
+    #[serde(default)]
+    super_admins: Vec<i64>,
     #[serde(default)]
     event_start: u64,
     #[serde(default)]
     event_end: u64,
+    #[serde(default)]
+    storage: StorageConfig,
+    #[serde(default)]
+    snapshot: SnapshotConfig,
+    #[serde(default)]
+    membership: MembershipConfig,
+    /** Who may play, vas3k.club members by default **/
+    #[serde(default)]
+    eligibility: EligibilityConfig,
+    /** Key the flags are hashed with, changing it invalidates every stored flag **/
+    #[serde(default)]
+    flag_salt: String,
+}
+
+impl Config {
+    /** Checked before the bot starts and before a reload is swapped in **/
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.telegram_token.is_empty() {
+            bail!("telegram_token is not set in {CONFIG_NAME}");
+        }
+        if self.flag_salt.is_empty() {
+            bail!("flag_salt is not set in {CONFIG_NAME}");
+        }
+        if self.event_end != 0 && self.event_end <= self.event_start {
+            bail!("event_end is not after event_start in {CONFIG_NAME}");
+        }
+        Ok(())
+    }
+
+    /** Only vas3k.club profiles are refreshed, the other providers keep local ones **/
+    fn refresh_interval(&self) -> u64 {
+        match self.eligibility {
+            EligibilityConfig::Vas3k => self.membership.refresh_interval,
+            _ => 0,
+        }
+    }
+
+    /** What differs in `new`, secrets are only named **/
+    fn changes(&self, new: &Config) -> Vec<String> {
+        let mut ret = Vec::new();
+        for (name, changed, restart) in [
+            (
+                "telegram_token",
+                self.telegram_token != new.telegram_token,
+                true,
+            ),
+            ("vas3k_token", self.vas3k_token != new.vas3k_token, false),
+            (
+                "storage",
+                format!("{:?}", self.storage) != format!("{:?}", new.storage),
+                true,
+            ),
+        ] {
+            if changed {
+                ret.push(Format::format_config_hidden(name, restart));
+            }
+        }
+        // the periodic loops are spawned at startup, one that was off there stays off until a restart
+        let snapshot = self.snapshot.interval == 0 && new.snapshot.interval > 0;
+        let membership = self.refresh_interval() == 0 && new.refresh_interval() > 0;
+        for (name, old, new, restart) in [
+            (
+                "event_start",
+                format!("{}", self.event_start),
+                format!("{}", new.event_start),
+                false,
+            ),
+            (
+                "event_end",
+                format!("{}", self.event_end),
+                format!("{}", new.event_end),
+                false,
+            ),
+            (
+                "test_group",
+                format!("{:?}", self.test_group),
+                format!("{:?}", new.test_group),
+                false,
+            ),
+            (
+                "admin_group",
+                format!("{:?}", self.admin_group),
+                format!("{:?}", new.admin_group),
+                false,
+            ),
+            (
+                "notify_group",
+                format!("{:?}", self.notify_group),
+                format!("{:?}", new.notify_group),
+                false,
+            ),
+            (
+                "super_admins",
+                format!("{:?}", self.super_admins),
+                format!("{:?}", new.super_admins),
+                false,
+            ),
+            (
+                "snapshot",
+                format!("{:?}", self.snapshot),
+                format!("{:?}", new.snapshot),
+                snapshot,
+            ),
+            (
+                "membership",
+                format!("{:?}", self.membership),
+                format!("{:?}", new.membership),
+                membership,
+            ),
+            (
+                "eligibility",
+                format!("{:?}", self.eligibility),
+                format!("{:?}", new.eligibility),
+                membership,
+            ),
+        ] {
+            if old != new {
+                ret.push(Format::format_config_change(name, &old, &new, restart));
+            }
+        }
+        ret
+    }
 }
 
 async fn filter_users(_: Bot, api: Arc<Api>, msg: Message) -> bool {
     match msg.from.as_ref() {
         None => false,
         Some(user) => {
-            if user.is_bot {
+            if user.is_bot
+                || api
+                    .active_sanction(user.id.0, SanctionKind::Ban)
+                    .await
+                    .is_some()
+            {
                 false
+            } else if api.check_user_is_in_scope(user).await {
+                true
+            } else if let Some(code) = msg.text().and_then(invite_code) {
+                redeem_code(&api, user, code).await
             } else {
-                api.check_user_is_in_scope(user.id.0).await
+                false
             }
         }
     }
 }
 
+/** Code of `/start <code>`, the deep link form of an invite, or of `/join <code>` **/
+fn invite_code(text: &str) -> Option<&str> {
+    text.strip_prefix("/start ")
+        .or_else(|| text.strip_prefix("/join "))
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+}
+
+/** Lets the message through if the code is accepted, a rejected one is answered right away **/
+async fn redeem_code(api: &Arc<Api>, user: &User, code: &str) -> bool {
+    match api.redeem_code(user, code).await {
+        Ok(true) => true,
+        Ok(false) => {
+            let _ = api.send_message(user.id.0 as i64, INVALID_CODE).await;
+            false
+        }
+        Err(e) => {
+            let _ = api
+                .send_message(user.id.0 as i64, Format::format_error(e))
+                .await;
+            false
+        }
+    }
+}
+
 /** We accept ONLY text messages **/
 async fn filter_messages(_: Bot, _: Arc<Api>, msg: Message) -> bool {
     matches!(msg.kind, MessageKind::Common(x) if matches!(x.media_kind, MediaKind::Text(_)))
@@ -77,6 +263,7 @@ async fn answer_messages(bot: Bot, api: Arc<Api>, msg: Message) -> anyhow::Resul
         return Ok(());
     };
     let user = msg.from.as_ref().unwrap();
+    api.mark_reachable(msg.chat.id.0).await?;
     let state = api.get_user_state(user.id.0).await;
     let mut data = if state.is_some_and(|x| !x.is_empty()) {
         process_data(&bot, user, &api, text).await
@@ -114,7 +301,39 @@ enum BotCommands {
     AdminScoreboard,
     AdminMessageAll,
     AdminEdit,
+    AdminSnapshot,
+    AdminEvents,
+    AdminEventCreate,
+    AdminEventSwitch,
+    AdminEventArchive,
+    AdminEventBoard,
+    AdminEventStartNow,
+    AdminEventPause,
+    AdminEventResume,
+    AdminEventEnd,
+    AdminReveal,
+    AdminRefreshUser,
+    AdminSanction(SanctionKind),
+    AdminLift,
+    AdminSanctions,
+    AdminAudit,
+    AdminInvites,
+    AdminInviteCreate,
+    AdminInviteRevoke,
+    AdminGrant,
+    AdminReload,
+    AdminRevoke,
+    AdminRoles,
+    AdminUndelivered,
+    AdminBroadcasts,
+    AdminBroadcastRetry(Option<u64>),
+    AdminSchedule,
+    AdminSchedules,
+    AdminUnschedule(Option<u64>),
+    ModeratorReply,
+    UserJoin(String),
     UserScore,
+    UserStats,
     UserContact(Option<String>),
     UserHelp,
     UserRules,
@@ -133,6 +352,16 @@ impl From<&str> for BotCommands {
             } else {
                 Self::UserContact(None)
             }
+        } else if let Some(id) = value.strip_prefix("/retry_") {
+            Self::AdminBroadcastRetry(id.trim().parse().ok())
+        } else if let Some(id) = value.strip_prefix("/unschedule_") {
+            Self::AdminUnschedule(id.trim().parse().ok())
+        } else if value.starts_with("/join ")
+            && let Some(code) = invite_code(value)
+        {
+            Self::UserJoin(code.to_string())
+        } else if invite_code(value).is_some() {
+            Self::UserHelp
         } else {
             match value {
                 "/start" => Self::UserHelp,
@@ -141,11 +370,42 @@ impl From<&str> for BotCommands {
                 "/edit" => Self::AdminEdit,
                 "/message" => Self::AdminMessageAll,
                 "/board" => Self::AdminScoreboard,
+                "/snapshot" => Self::AdminSnapshot,
+                "/events" => Self::AdminEvents,
+                "/event_create" => Self::AdminEventCreate,
+                "/event_switch" => Self::AdminEventSwitch,
+                "/event_archive" => Self::AdminEventArchive,
+                "/event_board" => Self::AdminEventBoard,
+                "/event_start_now" => Self::AdminEventStartNow,
+                "/event_pause" => Self::AdminEventPause,
+                "/event_resume" => Self::AdminEventResume,
+                "/event_end" => Self::AdminEventEnd,
+                "/reveal" => Self::AdminReveal,
+                "/refresh_user" => Self::AdminRefreshUser,
+                "/ban" => Self::AdminSanction(SanctionKind::Ban),
+                "/mute" => Self::AdminSanction(SanctionKind::Mute),
+                "/disqualify" => Self::AdminSanction(SanctionKind::Disqualify),
+                "/lift" => Self::AdminLift,
+                "/sanctions" => Self::AdminSanctions,
+                "/audit" => Self::AdminAudit,
+                "/invites" => Self::AdminInvites,
+                "/invite_create" => Self::AdminInviteCreate,
+                "/invite_revoke" => Self::AdminInviteRevoke,
+                "/grant" => Self::AdminGrant,
+                "/reload" => Self::AdminReload,
+                "/revoke" => Self::AdminRevoke,
+                "/roles" => Self::AdminRoles,
+                "/undelivered" => Self::AdminUndelivered,
+                "/broadcasts" => Self::AdminBroadcasts,
+                "/schedule" => Self::AdminSchedule,
+                "/schedules" => Self::AdminSchedules,
+                "/reply" => Self::ModeratorReply,
                 "/help" => Self::UserHelp,
                 "/code" => Self::UserCode,
                 "/tasks" => Self::UserTasks,
                 "/rules" => Self::UserRules,
                 "/score" => Self::UserScore,
+                "/stats" => Self::UserStats,
                 "/s3cr3t_comm4nd" => Self::UserSecretFlag,
                 _ => Self::Unknown,
             }
@@ -179,17 +439,43 @@ impl From<ReplyText> for String {
     }
 }
 
-async fn process_command(bot: &Bot, user: &User, api: &Arc<Api>, text: &str) -> Vec<ReplyText> {
+/** Rights are checked again for the input a state waits for, they may be gone since the command **/
+fn state_allowed(api: &Api, user_id: u64, state: &str) -> bool {
+    if state.starts_with("contact") {
+        true
+    } else if state == "reply" {
+        api.is_moderator(user_id)
+    } else if state == "grant" || state == "revoke" {
+        api.is_super_admin(user_id)
+    } else {
+        api.is_admin(user_id)
+    }
+}
+
+/** Pushes a failed write to the reply instead of dropping it, returns true on success **/
+fn check_write(ret: &mut Vec<ReplyText>, result: anyhow::Result<()>) -> bool {
+    match result {
+        Ok(_) => true,
+        Err(e) => {
+            ret.push(Format::format_error(e).into());
+            false
+        }
+    }
+}
+
+async fn process_command(_bot: &Bot, user: &User, api: &Arc<Api>, text: &str) -> Vec<ReplyText> {
     let mut ret: Vec<ReplyText> = Vec::new();
     let user_id = user.id.0;
     let command: BotCommands = text.into();
     let is_admin = api.is_admin(user_id);
+    let is_moderator = api.is_moderator(user_id);
     let can_process = api.can_process_command(user_id);
     match command {
         BotCommands::AdminCreate => {
             if is_admin {
-                api.set_user_state(user_id, "create").await;
-                ret.push(CREATE_TASK.into());
+                if check_write(&mut ret, api.set_user_state(user_id, "create").await) {
+                    ret.push(CREATE_TASK.into());
+                }
             } else {
                 ret.push(DENIED.into());
             }
@@ -209,7 +495,7 @@ async fn process_command(bot: &Bot, user: &User, api: &Arc<Api>, text: &str) ->
                         InlineKeyboardMarkup::new(keyboard).into(),
                     )
                     .await;
-                api.set_user_state(user_id, "delete").await;
+                check_write(&mut ret, api.set_user_state(user_id, "delete").await);
             } else {
                 ret.push(DENIED.into());
             }
@@ -229,27 +515,393 @@ async fn process_command(bot: &Bot, user: &User, api: &Arc<Api>, text: &str) ->
                         InlineKeyboardMarkup::new(keyboard).into(),
                     )
                     .await;
-                api.set_user_state(user_id, "edit").await;
+                check_write(&mut ret, api.set_user_state(user_id, "edit").await);
             } else {
                 ret.push(DENIED.into());
             }
         }
         BotCommands::AdminScoreboard => {
+            if is_moderator {
+                let event = api.current_event();
+                ret.push(format_board(api, &event).await.into());
+            } else {
+                ret.push(DENIED.into());
+            }
+        }
+        BotCommands::AdminMessageAll => {
+            if is_admin {
+                // a draft left by an unfinished /message is dropped
+                check_write(
+                    &mut ret,
+                    api.retrieve_and_erase_contact(user_id).await.map(|_| ()),
+                );
+                if check_write(
+                    &mut ret,
+                    api.set_user_state(user_id, "message_segment").await,
+                ) {
+                    ret.push(SEGMENT_TEXT.into());
+                }
+            } else {
+                ret.push(DENIED.into());
+            }
+        }
+        BotCommands::AdminSnapshot => {
+            if is_admin {
+                match api.take_snapshot().await {
+                    Ok((path, skipped)) => {
+                        ret.push(Format::format_snapshot(path.display(), skipped).into());
+                        let file = FileSource::Path(path);
+                        let _ = api
+                            .send_file(user_id as i64, FileKind::Document, file, "")
+                            .await;
+                    }
+                    Err(e) => ret.push(Format::format_error(e).into()),
+                }
+            } else {
+                ret.push(DENIED.into());
+            }
+        }
+        BotCommands::AdminEvents => {
             if is_admin {
-                let board = api.get_scoreboard().await;
+                let current = api.current_event();
                 let mut msg = String::new();
-                for (i, (user, score)) in board.into_iter().enumerate() {
-                    msg.push_str(&Format::format_score_board(i + 1, &user, score));
+                for event in api.list_events().await {
+                    msg.push_str(&Format::format_event(&event, event.id == current.id));
                 }
-                ret.push(ReplyText::String(msg));
+                ret.push(msg.into());
             } else {
                 ret.push(DENIED.into());
             }
         }
-        BotCommands::AdminMessageAll => {
+        BotCommands::AdminEventCreate => {
+            if is_admin {
+                if check_write(&mut ret, api.set_user_state(user_id, "event_create").await) {
+                    ret.push(CREATE_EVENT.into());
+                }
+            } else {
+                ret.push(DENIED.into());
+            }
+        }
+        BotCommands::AdminEventStartNow
+        | BotCommands::AdminEventPause
+        | BotCommands::AdminEventResume => {
+            if is_admin {
+                let changed = match command {
+                    BotCommands::AdminEventStartNow => api
+                        .start_event_now()
+                        .await
+                        .map(|x| (Format::format_event_started(&x), x)),
+                    BotCommands::AdminEventPause => api
+                        .pause_event()
+                        .await
+                        .map(|x| (String::from(EVENT_PAUSED), x)),
+                    _ => api
+                        .resume_event()
+                        .await
+                        .map(|x| (String::from(EVENT_RESUMED), x)),
+                };
+                match changed {
+                    Ok((announcement, event)) => {
+                        check_write(&mut ret, api.announce(announcement).await);
+                        ret.push(Format::format_event(&event, true).into());
+                    }
+                    Err(e) => ret.push(Format::format_error(e).into()),
+                }
+            } else {
+                ret.push(DENIED.into());
+            }
+        }
+        BotCommands::AdminEventEnd => {
+            if is_admin {
+                if check_write(&mut ret, api.set_user_state(user_id, "event_end").await) {
+                    ret.push(EVENT_END_TEXT.into());
+                }
+            } else {
+                ret.push(DENIED.into());
+            }
+        }
+        BotCommands::AdminReveal => {
+            if is_admin {
+                if check_write(&mut ret, api.set_user_state(user_id, "reveal").await) {
+                    ret.push(REVEAL_FLAG.into());
+                }
+            } else {
+                ret.push(DENIED.into());
+            }
+        }
+        BotCommands::AdminRefreshUser => {
+            if is_admin {
+                if check_write(&mut ret, api.set_user_state(user_id, "refresh_user").await) {
+                    ret.push(REFRESH_USER.into());
+                }
+            } else {
+                ret.push(DENIED.into());
+            }
+        }
+        BotCommands::AdminSanction(kind) => {
+            if is_admin {
+                let state = format!("sanction_{}", kind.as_str());
+                if check_write(&mut ret, api.set_user_state(user_id, state).await) {
+                    ret.push(SANCTION_TEXT.into());
+                }
+            } else {
+                ret.push(DENIED.into());
+            }
+        }
+        BotCommands::AdminLift => {
+            if is_admin {
+                if check_write(&mut ret, api.set_user_state(user_id, "lift").await) {
+                    ret.push(LIFT_TEXT.into());
+                }
+            } else {
+                ret.push(DENIED.into());
+            }
+        }
+        BotCommands::AdminSanctions => {
+            if is_admin {
+                let mut msg = String::new();
+                for (user, sanction) in api.list_sanctions().await {
+                    msg.push_str(&Format::format_sanction(&user, &sanction));
+                }
+                if msg.is_empty() {
+                    ret.push(NO_SANCTIONS.into());
+                } else {
+                    ret.push(msg.into());
+                }
+            } else {
+                ret.push(DENIED.into());
+            }
+        }
+        BotCommands::AdminAudit => {
+            if is_admin {
+                let mut msg = String::new();
+                for (user, entry) in api.audit_log().await {
+                    msg.push_str(&Format::format_audit(&user, &entry));
+                }
+                if msg.is_empty() {
+                    ret.push(NO_AUDIT.into());
+                } else {
+                    ret.push(msg.into());
+                }
+            } else {
+                ret.push(DENIED.into());
+            }
+        }
+        BotCommands::AdminInvites => {
+            if is_admin {
+                let mut msg = String::new();
+                for (invite, users) in api.list_invites().await {
+                    msg.push_str(&Format::format_invite(&invite, &users));
+                }
+                if msg.is_empty() {
+                    ret.push(NO_INVITES.into());
+                } else {
+                    ret.push(msg.into());
+                }
+            } else {
+                ret.push(DENIED.into());
+            }
+        }
+        BotCommands::AdminInviteCreate => {
+            if is_admin {
+                if check_write(&mut ret, api.set_user_state(user_id, "invite_create").await) {
+                    ret.push(CREATE_INVITE.into());
+                }
+            } else {
+                ret.push(DENIED.into());
+            }
+        }
+        BotCommands::AdminInviteRevoke => {
+            if is_admin {
+                let mut keyboard: Vec<Vec<InlineKeyboardButton>> = Vec::new();
+                api.list_invites()
+                    .await
+                    .into_iter()
+                    .filter(|(x, _)| !x.revoked)
+                    .map(|(x, _)| InlineKeyboardButton::callback(x.code.clone(), x.code))
+                    .for_each(|btn| keyboard.push(vec![btn]));
+                if keyboard.is_empty() {
+                    ret.push(NO_INVITES.into());
+                } else {
+                    let _ = api
+                        .send_message_with_markup(
+                            user_id as i64,
+                            CHOOSE_INVITE,
+                            InlineKeyboardMarkup::new(keyboard).into(),
+                        )
+                        .await;
+                    check_write(&mut ret, api.set_user_state(user_id, "invite_revoke").await);
+                }
+            } else {
+                ret.push(DENIED.into());
+            }
+        }
+        BotCommands::AdminGrant | BotCommands::AdminRevoke => {
+            if api.is_super_admin(user_id) {
+                let state = match command {
+                    BotCommands::AdminGrant => "grant",
+                    _ => "revoke",
+                };
+                if check_write(&mut ret, api.set_user_state(user_id, state).await) {
+                    ret.push(ROLE_TEXT.into());
+                }
+            } else {
+                ret.push(DENIED.into());
+            }
+        }
+        BotCommands::AdminReload => {
+            if is_admin {
+                match reload_config(api).await {
+                    Ok(changes) => ret.push(Format::format_config_reloaded(&changes).into()),
+                    Err(e) => ret.push(Format::format_error(e).into()),
+                }
+            } else {
+                ret.push(DENIED.into());
+            }
+        }
+        BotCommands::AdminRoles => {
+            if is_admin {
+                let mut msg = String::new();
+                for (role, user) in api.list_roles().await {
+                    msg.push_str(&Format::format_role(role, &user));
+                }
+                if msg.is_empty() {
+                    ret.push(NO_ROLES.into());
+                } else {
+                    ret.push(msg.into());
+                }
+            } else {
+                ret.push(DENIED.into());
+            }
+        }
+        BotCommands::AdminUndelivered => {
+            if is_admin {
+                let mut msg = String::new();
+                for (chat, letter) in api.dead_letters().await {
+                    msg.push_str(&Format::format_dead_letter(&chat, &letter));
+                }
+                if msg.is_empty() {
+                    ret.push(NO_DEAD_LETTERS.into());
+                } else {
+                    ret.push(msg.into());
+                }
+            } else {
+                ret.push(DENIED.into());
+            }
+        }
+        BotCommands::AdminBroadcasts => {
+            if is_admin {
+                let mut msg = String::new();
+                for (broadcast, stats) in api.broadcasts().await {
+                    msg.push_str(&Format::format_broadcast(&broadcast, &stats));
+                }
+                if msg.is_empty() {
+                    ret.push(NO_BROADCASTS.into());
+                } else {
+                    ret.push(msg.into());
+                }
+            } else {
+                ret.push(DENIED.into());
+            }
+        }
+        BotCommands::AdminBroadcastRetry(id) => {
+            if is_admin {
+                let Some(id) = id else {
+                    ret.push(Format::format_error(r"Неверный номер рассылки").into());
+                    return ret;
+                };
+                match api.retry_broadcast(id).await {
+                    Ok((count, skipped)) => {
+                        ret.push(Format::format_broadcast_retried(id, count, skipped).into())
+                    }
+                    Err(e) => ret.push(Format::format_error(e).into()),
+                }
+            } else {
+                ret.push(DENIED.into());
+            }
+        }
+        BotCommands::AdminSchedule => {
+            if is_admin {
+                if check_write(&mut ret, api.set_user_state(user_id, "schedule").await) {
+                    ret.push(SCHEDULE_TEXT.into());
+                }
+            } else {
+                ret.push(DENIED.into());
+            }
+        }
+        BotCommands::AdminSchedules => {
+            if is_admin {
+                let mut msg = String::new();
+                for schedule in api.schedules().await {
+                    msg.push_str(&Format::format_schedule(&schedule));
+                }
+                if msg.is_empty() {
+                    ret.push(NO_SCHEDULES.into());
+                } else {
+                    ret.push(msg.into());
+                }
+            } else {
+                ret.push(DENIED.into());
+            }
+        }
+        BotCommands::AdminUnschedule(id) => {
+            if is_admin {
+                let reply = match id {
+                    Some(id) => api
+                        .cancel_schedule(id)
+                        .await
+                        .map(|_| Format::format_unscheduled(id))
+                        .unwrap_or_else(Format::format_error),
+                    None => Format::format_error(r"Неверный номер рассылки"),
+                };
+                ret.push(reply.into());
+            } else {
+                ret.push(DENIED.into());
+            }
+        }
+        BotCommands::ModeratorReply => {
+            if is_moderator {
+                if check_write(&mut ret, api.set_user_state(user_id, "reply").await) {
+                    ret.push(REPLY_TEXT.into());
+                }
+            } else {
+                ret.push(DENIED.into());
+            }
+        }
+        BotCommands::UserJoin(code) => match api.redeem_code(user, &code).await {
+            Ok(true) => ret.push(JOINED.into()),
+            Ok(false) => ret.push(INVALID_CODE.into()),
+            Err(e) => ret.push(Format::format_error(e).into()),
+        },
+        BotCommands::AdminEventSwitch
+        | BotCommands::AdminEventArchive
+        | BotCommands::AdminEventBoard => {
             if is_admin {
-                api.set_user_state(user_id, "message").await;
-                ret.push(MESSAGE_TEXT.into());
+                let (state, active_only) = match command {
+                    BotCommands::AdminEventSwitch => ("event_switch", true),
+                    BotCommands::AdminEventArchive => ("event_archive", true),
+                    _ => ("event_board", false),
+                };
+                let current = api.current_event();
+                let mut keyboard: Vec<Vec<InlineKeyboardButton>> = Vec::new();
+                api.list_events()
+                    .await
+                    .into_iter()
+                    .filter(|x| !active_only || (!x.archived && x.id != current.id))
+                    .map(|event| InlineKeyboardButton::callback(event.name, event.id))
+                    .for_each(|btn| keyboard.push(vec![btn]));
+                if keyboard.is_empty() {
+                    ret.push(NO_EVENTS.into());
+                } else {
+                    let _ = api
+                        .send_message_with_markup(
+                            user_id as i64,
+                            CHOOSE_EVENT,
+                            InlineKeyboardMarkup::new(keyboard).into(),
+                        )
+                        .await;
+                    check_write(&mut ret, api.set_user_state(user_id, state).await);
+                }
             } else {
                 ret.push(DENIED.into());
             }
@@ -262,14 +914,34 @@ async fn process_command(bot: &Bot, user: &User, api: &Arc<Api>, text: &str) ->
                 ret.push(Format::format_score(place, score).into());
             }
         }
+        BotCommands::UserStats => {
+            if !can_process {
+                ret.push(NOT_YET.into());
+            } else {
+                let stats = api.get_stats().await;
+                if stats.tasks.is_empty() {
+                    ret.push(NO_STATS.into());
+                } else if is_admin {
+                    ret.push(Format::format_stats_admin(&stats, api.event_start()).into());
+                } else {
+                    ret.push(Format::format_stats(&stats).into());
+                }
+            }
+        }
+        BotCommands::UserContact(_)
+            if let Some(mute) = api.active_sanction(user_id, SanctionKind::Mute).await =>
+        {
+            ret.push(Format::format_muted(&mute).into());
+        }
         BotCommands::UserContact(task_id) => {
             let state = if let Some(task_id) = task_id {
                 format!("contact_{}", task_id)
             } else {
                 String::from("contact")
             };
-            api.set_user_state(user_id, state).await;
-            ret.push(CONTACT_TEXT.into());
+            if check_write(&mut ret, api.set_user_state(user_id, state).await) {
+                ret.push(CONTACT_TEXT.into());
+            }
         }
         BotCommands::UserHelp => {
             ret.push(HELP_TEXT.into());
@@ -298,9 +970,13 @@ async fn process_command(bot: &Bot, user: &User, api: &Arc<Api>, text: &str) ->
             if !can_process {
                 ret.push(NOT_YET.into());
             } else {
-                let file = InputFile::file("src/main.rs");
-                if bot.send_document(UserId(user_id), file).await.is_err() {
+                let path = Path::new(CODE_PATH);
+                if !path.is_file() {
                     ret.push(CODE_TEXT.into());
+                } else {
+                    let file = FileSource::Path(path.to_path_buf());
+                    let sent = api.send_file(user_id as i64, FileKind::Document, file, "");
+                    check_write(&mut ret, sent.await);
                 }
             }
         }
@@ -331,15 +1007,23 @@ async fn process_data(_bot: &Bot, user: &User, api: &Arc<Api>, text: &str) -> Ve
         None => {
             if !can_process {
                 ret.push(NOT_YET.into());
+            } else if api.is_paused_for(user_id) {
+                ret.push(PAUSED.into());
             } else {
                 match api.try_submit_flag(user_id, text).await {
-                    SubmissionResult::NotAFlag => {
+                    Err(e) => {
+                        let _ = api
+                            .send_notification(Format::format_storage_error("submit flag", &e))
+                            .await;
+                        ret.push(Format::format_error(e).into());
+                    }
+                    Ok(SubmissionResult::NotAFlag) => {
                         ret.push(UNKNOWN_TEXT.into());
                     }
-                    SubmissionResult::AlreadySolved => {
+                    Ok(SubmissionResult::AlreadySolved) => {
                         ret.push(ALREADY_SOLVED.into());
                     }
-                    SubmissionResult::Solved(name) => {
+                    Ok(SubmissionResult::Solved(name)) => {
                         let id = match user.username {
                             None => {
                                 format!("{} ({})", user.first_name, user.id.0)
@@ -357,19 +1041,33 @@ async fn process_data(_bot: &Bot, user: &User, api: &Arc<Api>, text: &str) -> Ve
             }
         }
         Some(state) => {
-            if state.starts_with("contact") {
+            if !state_allowed(api, user_id, &state) {
+                ret.push(DENIED.into());
+                check_write(&mut ret, api.set_user_state(user_id, "").await);
+            } else if state.starts_with("contact")
+                && let Some(mute) = api.active_sanction(user_id, SanctionKind::Mute).await
+            {
+                ret.push(Format::format_muted(&mute).into());
+                check_write(&mut ret, api.set_user_state(user_id, "").await);
+            } else if state.starts_with("contact") {
                 if text.eq(".") {
                     let parts = state.split("_").collect::<Vec<&str>>();
                     let topic = if parts.len() == 2 {
-                        let task = api.get_task(parts[1]).await;
-                        task
+                        api.get_task(parts[1]).await
                     } else {
                         None
                     };
-                    let message = api.retrieve_and_erase_contact(user_id).await;
+                    let message = match api.retrieve_and_erase_contact(user_id).await {
+                        Ok(message) => message,
+                        Err(e) => {
+                            ret.push(Format::format_error(e).into());
+                            return ret;
+                        }
+                    };
                     let user_id_str = user.id.0.to_string();
                     let message = Format::format_message(
                         user.username.as_deref().unwrap_or_else(|| &user_id_str),
+                        user_id,
                         &message,
                         topic.as_ref().map(|x| x.name.as_str()),
                     );
@@ -379,30 +1077,174 @@ async fn process_data(_bot: &Bot, user: &User, api: &Arc<Api>, text: &str) -> Ve
                     } else {
                         ret.push(MESSAGE_SENT.into());
                     }
-                    api.set_user_state(user_id, "").await;
+                    check_write(&mut ret, api.set_user_state(user_id, "").await);
+                } else {
+                    check_write(&mut ret, api.append_to_contact(user_id, text).await);
+                }
+            } else if state.eq("event_create") {
+                match api.create_event(text).await {
+                    Ok(event) => ret.push(Format::format_event_created(&event).into()),
+                    Err(e) => ret.push(Format::format_error(e).into()),
+                }
+                check_write(&mut ret, api.set_user_state(user_id, "").await);
+            } else if state.eq("event_end") {
+                match api.set_event_end(text).await {
+                    Ok((event, old)) => {
+                        let announcement = Format::format_event_end_changed(&event, old);
+                        check_write(&mut ret, api.announce(announcement).await);
+                        ret.push(Format::format_event(&event, true).into());
+                    }
+                    Err(e) => ret.push(Format::format_error(e).into()),
+                }
+                check_write(&mut ret, api.set_user_state(user_id, "").await);
+            } else if state.eq("reveal") {
+                match api.reveal_flag(text).await {
+                    Ok(Some(task)) => ret.push(Format::format_flag_revealed(&task).into()),
+                    Ok(None) => ret.push(FLAG_NOT_FOUND.into()),
+                    Err(e) => ret.push(Format::format_error(e).into()),
+                }
+                check_write(&mut ret, api.set_user_state(user_id, "").await);
+            } else if let Some(kind) = state.strip_prefix("sanction_") {
+                let kind = match kind {
+                    "ban" => SanctionKind::Ban,
+                    "mute" => SanctionKind::Mute,
+                    _ => SanctionKind::Disqualify,
+                };
+                match api.sanction_user(user_id, kind, text).await {
+                    Ok((target, sanction)) => {
+                        let _ = api
+                            .send_message(target as i64, Format::format_sanction_user(&sanction))
+                            .await;
+                        ret.push(Format::format_sanctioned(target, &sanction).into());
+                    }
+                    Err(e) => ret.push(Format::format_error(e).into()),
+                }
+                check_write(&mut ret, api.set_user_state(user_id, "").await);
+            } else if state.eq("grant") || state.eq("revoke") {
+                if state.eq("grant") {
+                    match api.grant_role(user_id, text).await {
+                        Ok((id, role)) => ret.push(Format::format_role_granted(id, role).into()),
+                        Err(e) => ret.push(Format::format_error(e).into()),
+                    }
                 } else {
-                    api.append_to_contact(user_id, text).await;
+                    match api.revoke_role(user_id, text).await {
+                        Ok((id, role)) => ret.push(Format::format_role_revoked(id, role).into()),
+                        Err(e) => ret.push(Format::format_error(e).into()),
+                    }
+                }
+                check_write(&mut ret, api.set_user_state(user_id, "").await);
+            } else if state.eq("reply") {
+                match api.reply_to_user(text).await {
+                    Ok(target) => ret.push(Format::format_replied(target).into()),
+                    Err(e) => ret.push(Format::format_error(e).into()),
+                }
+                check_write(&mut ret, api.set_user_state(user_id, "").await);
+            } else if state.eq("lift") {
+                match api.lift_sanction(user_id, text).await {
+                    Ok((target, kind)) => ret.push(Format::format_lifted(target, kind).into()),
+                    Err(e) => ret.push(Format::format_error(e).into()),
+                }
+                check_write(&mut ret, api.set_user_state(user_id, "").await);
+            } else if state.eq("invite_create") {
+                match api.create_invite(text).await {
+                    Ok(invite) => ret.push(Format::format_invite_created(&invite).into()),
+                    Err(e) => ret.push(Format::format_error(e).into()),
+                }
+                check_write(&mut ret, api.set_user_state(user_id, "").await);
+            } else if state.eq("refresh_user") {
+                match text.trim().parse::<u64>() {
+                    Ok(id) => match api.fetch_user(id).await {
+                        Ok(user) => ret.push(Format::format_user_refreshed(&user).into()),
+                        Err(e) => ret.push(Format::format_error(e).into()),
+                    },
+                    Err(_) => ret.push(NOT_A_USER_ID.into()),
                 }
+                check_write(&mut ret, api.set_user_state(user_id, "").await);
             } else if state.eq("create") {
                 match api.create_task(text).await {
-                    Ok(id) => ret.push(Format::format_created(&id).into()),
+                    Ok((task, flag)) => {
+                        ret.push(Format::format_created(&format!("task:{}", task.id)).into());
+                        ret.push(Format::format_task_admin(&task, Some(&flag)).into());
+                    }
                     Err(e) => ret.push(Format::format_error(e).into()),
                 }
-                api.set_user_state(user_id, "").await;
-            } else if state.eq("message") {
+                check_write(&mut ret, api.set_user_state(user_id, "").await);
+            } else if state.eq("schedule") {
+                match api.add_schedule(user_id, text).await {
+                    Ok(schedule) => ret.push(Format::format_scheduled(&schedule).into()),
+                    Err(e) => ret.push(Format::format_error(e).into()),
+                }
+                check_write(&mut ret, api.set_user_state(user_id, "").await);
+            } else if state.eq("message_segment") {
+                let segment = match Segment::parse(text) {
+                    Ok(segment) => segment,
+                    Err(e) => {
+                        ret.push(Format::format_error(e).into());
+                        check_write(&mut ret, api.set_user_state(user_id, "").await);
+                        return ret;
+                    }
+                };
+                match api.segment_users(&segment).await {
+                    Ok(users) => {
+                        let state = format!("message:{segment}");
+                        if check_write(&mut ret, api.set_user_state(user_id, state).await) {
+                            ret.push(Format::format_message_prompt(&segment, users.len()).into());
+                        }
+                    }
+                    Err(e) => {
+                        ret.push(Format::format_error(e).into());
+                        check_write(&mut ret, api.set_user_state(user_id, "").await);
+                    }
+                }
+            } else if let Some(segment) = state.strip_prefix("message:") {
                 if text.eq(".") {
-                    let message = api.retrieve_and_erase_contact(user_id).await;
-                    let message = Format::format_message_broadcast(&message);
-                    api.set_user_state(user_id, "").await;
-                    for uid in api.get_all_users().await {
-                        if uid != 0 {
-                            if let Err(e) = api.send_message(uid as i64, &message).await {
-                                ret.push(Format::format_error(e).into());
-                            }
+                    let preview = match api.retrieve_contact(user_id).await {
+                        Ok(message) if message.trim().is_empty() => {
+                            check_write(&mut ret, api.set_user_state(user_id, "").await);
+                            ret.push(EMPTY_BROADCAST.into());
+                            return ret;
+                        }
+                        Ok(message) => Format::format_message_broadcast(&message),
+                        Err(e) => {
+                            ret.push(Format::format_error(e).into());
+                            return ret;
+                        }
+                    };
+                    // a segment that no longer parses never widens to everyone
+                    let segment = match Segment::parse(segment) {
+                        Ok(segment) => segment,
+                        Err(e) => {
+                            ret.push(Format::format_error(e).into());
+                            check_write(&mut ret, api.set_user_state(user_id, "").await);
+                            return ret;
                         }
+                    };
+                    let count = match api.segment_users(&segment).await {
+                        Ok(users) => users.len(),
+                        Err(e) => {
+                            ret.push(Format::format_error(e).into());
+                            check_write(&mut ret, api.set_user_state(user_id, "").await);
+                            return ret;
+                        }
+                    };
+                    let state = format!("message_confirm:{segment}");
+                    if !check_write(&mut ret, api.set_user_state(user_id, state).await) {
+                        return ret;
                     }
+                    let keyboard = vec![vec![
+                        InlineKeyboardButton::callback(BROADCAST_SEND, "send"),
+                        InlineKeyboardButton::callback(BROADCAST_CANCEL, "cancel"),
+                    ]];
+                    let _ = api.send_message(user_id as i64, preview).await;
+                    let _ = api
+                        .send_message_with_markup(
+                            user_id as i64,
+                            Format::format_broadcast_confirm(&segment, count),
+                            InlineKeyboardMarkup::new(keyboard).into(),
+                        )
+                        .await;
                 } else {
-                    api.append_to_contact(user_id, text).await;
+                    check_write(&mut ret, api.append_to_contact(user_id, text).await);
                 }
             } else if state.starts_with("edit_") {
                 if let Some(id) = state.split("_").last() {
@@ -411,9 +1253,9 @@ async fn process_data(_bot: &Bot, user: &User, api: &Arc<Api>, text: &str) -> Ve
                         Err(e) => ret.push(Format::format_error(e).into()),
                     }
                 }
-                api.set_user_state(user_id, "").await;
+                check_write(&mut ret, api.set_user_state(user_id, "").await);
             } else {
-                api.set_user_state(user_id, "").await; // reset state
+                check_write(&mut ret, api.set_user_state(user_id, "").await); // reset state
                 ret.push(NOT_IMPLEMENTED.into());
             }
         }
@@ -421,10 +1263,18 @@ async fn process_data(_bot: &Bot, user: &User, api: &Arc<Api>, text: &str) -> Ve
     ret
 }
 
+async fn format_board(api: &Arc<Api>, event: &Event) -> String {
+    let mut msg = Format::format_event_board(event);
+    for (i, (user, score)) in api.get_scoreboard(&event.id).await.into_iter().enumerate() {
+        msg.push_str(&Format::format_score_board(i + 1, &user, score));
+    }
+    msg
+}
+
 async fn callback_handler(bot: Bot, api: Arc<Api>, query: CallbackQuery) -> anyhow::Result<()> {
     let user_id = query.from.id.0;
     let state = api.get_user_state(user_id).await;
-    api.set_user_state(user_id, "").await;
+    api.set_user_state(user_id, "").await?;
     let message = match query.message {
         Some(m) => m,
         None => return Ok(()),
@@ -437,6 +1287,54 @@ async fn callback_handler(bot: Bot, api: Arc<Api>, query: CallbackQuery) -> anyh
 
     match state {
         None => return Ok(()),
+        Some(ref state) if !state_allowed(&api, user_id, state) => {
+            api.send_message(query.from.id.0 as i64, DENIED).await?;
+        }
+        Some(ref state) if state.starts_with("event_") => {
+            let reply = match state.as_str() {
+                "event_switch" => api
+                    .switch_event(&id)
+                    .await
+                    .map(|x| Format::format_event_switched(&x)),
+                "event_archive" => api
+                    .archive_event(&id)
+                    .await
+                    .map(|x| Format::format_event_archived(&x)),
+                "event_board" => match api.get_event(&id).await {
+                    Some(event) => Ok(format_board(&api, &event).await),
+                    None => return Ok(()),
+                },
+                _ => return Ok(()),
+            };
+            let reply = reply.unwrap_or_else(Format::format_error);
+            api.send_message(query.from.id.0 as i64, reply).await?;
+        }
+        Some(ref state) if state.starts_with("message_confirm:") => {
+            let message = api.retrieve_and_erase_contact(user_id).await?;
+            let reply = if id == "send" {
+                match Segment::parse(state.strip_prefix("message_confirm:").unwrap_or_default()) {
+                    Ok(segment) => api
+                        .broadcast(user_id, &message, &segment)
+                        .await
+                        .map(|(broadcast, total)| {
+                            Format::format_broadcast_queued(&broadcast, total)
+                        })
+                        .unwrap_or_else(Format::format_error),
+                    Err(e) => Format::format_error(e),
+                }
+            } else {
+                String::from(BROADCAST_CANCELLED)
+            };
+            api.send_message(query.from.id.0 as i64, reply).await?;
+        }
+        Some(ref state) if state == "invite_revoke" => {
+            let reply = api
+                .revoke_invite(&id)
+                .await
+                .map(|x| Format::format_invite_revoked(&x))
+                .unwrap_or_else(Format::format_error);
+            api.send_message(query.from.id.0 as i64, reply).await?;
+        }
         Some(ref state) => {
             let Some(task) = api.get_task(&id).await else {
                 return Ok(());
@@ -445,9 +1343,12 @@ async fn callback_handler(bot: Bot, api: Arc<Api>, query: CallbackQuery) -> anyh
                 "edit" => {
                     api.send_message(query.from.id.0 as i64, CREATE_TASK)
                         .await?;
-                    api.send_message(query.from.id.0 as i64, Format::format_task_admin(&task))
-                        .await?;
-                    api.set_user_state(user_id, format!("edit_{id}")).await;
+                    api.send_message(
+                        query.from.id.0 as i64,
+                        Format::format_task_admin(&task, None),
+                    )
+                    .await?;
+                    api.set_user_state(user_id, format!("edit_{id}")).await?;
                 }
                 "delete" => {
                     api.delete_task(id).await?;
@@ -461,16 +1362,64 @@ async fn callback_handler(bot: Bot, api: Arc<Api>, query: CallbackQuery) -> anyh
     Ok(())
 }
 
+async fn read_config() -> anyhow::Result<Arc<Config>> {
+    let data = tokio::fs::read(CONFIG_NAME).await?;
+    Ok(Arc::new(serde_json::from_slice(&data)?))
+}
+
+async fn reload_config(api: &Api) -> anyhow::Result<Vec<String>> {
+    api.reload_config(read_config().await?).await
+}
+
+/** `kill -HUP` rereads the config, the notify group gets what changed **/
+async fn reload_on_hangup(api: Arc<Api>) -> anyhow::Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    while hangup.recv().await.is_some() {
+        let message = match reload_config(&api).await {
+            Ok(changes) => Format::format_config_reloaded(&changes),
+            Err(e) => {
+                error!("Failed to reload {CONFIG_NAME}: {e}");
+                Format::format_error(e)
+            }
+        };
+        if let Err(e) = api.send_notification(message).await {
+            error!("Failed to report the reload: {e}");
+        }
+    }
+    Ok(())
+}
+
+/** Offline backup and restore, works on the configured storage without starting the bot **/
+async fn rt_snapshot(command: &str, path: &Path) -> anyhow::Result<()> {
+    let config = read_config().await?;
+    let storage = storage::open(&config.storage).await?;
+    if command == "snapshot" {
+        let skipped = snapshot::save(storage.as_ref(), path, &config.flag_salt).await?;
+        if skipped > 0 {
+            warn!("{skipped} records did not decode and are left out");
+        }
+    } else {
+        let snapshot = snapshot::load(path).await?;
+        snapshot::restore(storage.as_ref(), &snapshot, &config.flag_salt).await?;
+    }
+    info!("{command} done: {}", path.display());
+    Ok(())
+}
+
 async fn rt_main() -> anyhow::Result<()> {
-    let config: Arc<Config> = {
-        let data = tokio::fs::read(CONFIG_NAME).await?;
-        Arc::new(serde_json::from_slice(&data)?)
-    };
+    let config = read_config().await?;
 
     let bot = Bot::new(&config.telegram_token);
-    let sender = MessageSender::new(bot.clone());
-    let api = Api::new(config, sender.sender()).await;
-    tokio::spawn(sender.start());
+    let api = Api::new(config).await?;
+    tokio::spawn(MessageSender::new(bot.clone(), api.outbox()).start());
+    tokio::spawn(reload_on_hangup(api.clone()));
+    tokio::spawn(api.clone().run_schedules());
+    if api.snapshot_interval() > 0 {
+        tokio::spawn(api.clone().run_snapshots());
+    }
+    if api.membership_refresh_interval() > 0 {
+        tokio::spawn(api.clone().run_membership_refresh());
+    }
     let msg_handler = Update::filter_message()
         .filter_async(filter_users)
         .filter_async(filter_messages)
@@ -0,0 +1,144 @@
+/**
+Copyright 2025 Ivan Agarkov
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+**/
+use crate::api::Vas3kUserReply;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/** Requests bigger than this are not GETs from the bot **/
+const MAX_REQUEST: usize = 8192;
+
+/** Telegram ID to the reply vas3k.club would give, unknown IDs get a not-found error **/
+type Fixtures = HashMap<u64, Vas3kUserReply>;
+
+/** Serves `/user/by_telegram_id/<id>.json` from the fixtures file, for running the bot without vas3k.club **/
+pub async fn serve(fixtures: &Path, addr: &str) -> anyhow::Result<()> {
+    serve_on(fixtures, TcpListener::bind(addr).await?).await
+}
+
+/** `serve` on a bound listener, so the port of `127.0.0.1:0` can be read before it runs **/
+pub async fn serve_on(fixtures: &Path, listener: TcpListener) -> anyhow::Result<()> {
+    let data = tokio::fs::read(fixtures).await?;
+    let fixtures = Arc::new(serde_json::from_slice::<Fixtures>(&data)?);
+    info!(
+        "Mock vas3k.club with {} users at http://{}",
+        fixtures.len(),
+        listener.local_addr()?
+    );
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let fixtures = fixtures.clone();
+        tokio::spawn(async move {
+            if let Err(e) = answer(stream, &fixtures).await {
+                warn!("Mock request from {peer} failed: {e}");
+            }
+        });
+    }
+}
+
+/** One request per connection, the bot does not need keep-alive from a mock **/
+async fn answer(mut stream: TcpStream, fixtures: &Fixtures) -> anyhow::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|x| x == b"\r\n\r\n") && request.len() < MAX_REQUEST {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let path = request
+        .lines()
+        .next()
+        .and_then(|x| x.strip_prefix("GET "))
+        .and_then(|x| x.split(' ').next())
+        .unwrap_or_default();
+    let id = path
+        .strip_prefix("/user/by_telegram_id/")
+        .and_then(|x| x.strip_suffix(".json"))
+        .and_then(|x| x.parse::<u64>().ok());
+    let (status, body) = match id.and_then(|x| fixtures.get(&x)) {
+        Some(reply) => ("200 OK", serde_json::to_string(reply)?),
+        None => (
+            "404 Not Found",
+            String::from(r#"{"error":{"code":"not-found","title":"User not found"}}"#),
+        ),
+    };
+    info!("Mock GET {path}: {status}");
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+    use crate::api::{Api, unix_now};
+
+    /** Api on the memory storage asking the mock started from mock_users.json **/
+    async fn api() -> Arc<Api> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("mock_users.json");
+        tokio::spawn(async move { serve_on(&fixtures, listener).await });
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "telegram_token": "token",
+            "vas3k_token": "token",
+            "flag_salt": "salt",
+            "storage": {"type": "memory"},
+            "membership": {"url": format!("http://{addr}"), "retries": 0},
+        }))
+        .unwrap();
+        Api::new(Arc::new(config)).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn members_are_looked_up_by_telegram_id() {
+        let api = api().await;
+        let now = unix_now();
+
+        let member = api.receive_user_by_telegram(1001).await.unwrap();
+        assert_eq!(member.to_string(), "Active Member (member)");
+        assert!(member.is_member(now));
+
+        let expired = api.receive_user_by_telegram(1002).await.unwrap();
+        assert_eq!(expired.to_string(), "Expired Member (expired)");
+        assert!(!expired.is_member(now));
+
+        let error = |user_id: u64| {
+            let api = api.clone();
+            async move {
+                match api.receive_user_by_telegram(user_id).await {
+                    Ok(user) => panic!("expected an error, got {user}"),
+                    Err(e) => e.to_string(),
+                }
+            }
+        };
+        let unknown = error(42).await;
+        assert!(unknown.contains("not-found"), "{unknown}");
+        let banned = error(1003).await;
+        assert!(banned.contains("user-banned"), "{banned}");
+    }
+}
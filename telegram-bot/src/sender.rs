@@ -13,29 +13,124 @@ WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 See the License for the specific language governing permissions and
 limitations under the License.
 **/
-use log::{debug, error, info};
-use std::collections::HashMap;
-use std::sync::atomic::AtomicI64;
-use std::sync::atomic::Ordering::SeqCst;
-use std::sync::Arc;
+use crate::api::{Delivery, DeliveryStats, unix_now};
+use crate::storage::Storage;
+use crate::text::Format;
+use anyhow::bail;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
 use std::time::{Duration, SystemTime};
 use teloxide::adaptors::DefaultParseMode;
-use teloxide::payloads::SendMessageSetters;
+use teloxide::payloads::{
+    SendAudioSetters, SendDocumentSetters, SendMessageSetters, SendPhotoSetters,
+};
 use teloxide::requests::{Requester, RequesterExt};
 use teloxide::sugar::request::RequestLinkPreviewExt;
-use teloxide::types::{ChatId, ParseMode, ReplyMarkup};
-use teloxide::Bot;
-use tokio::sync::mpsc::{Receiver, Sender};
-use tokio::time::sleep;
+use teloxide::types::{
+    ChatId, InputFile, InputMedia, InputMediaAudio, InputMediaDocument, InputMediaPhoto, ParseMode,
+    ReplyMarkup,
+};
+use teloxide::{ApiError, Bot, RequestError};
+use tokio::sync::Notify;
+use tokio::time::{Instant, sleep, timeout};
 
-pub struct Message(i64, String, Option<ReplyMarkup>);
+/** Lanes of the queue, a lower one is sent first whatever its age **/
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    /** Replies to what a user just did and notifications for admins **/
+    #[default]
+    Interactive = 0,
+    /** Broadcasts and announcements to every user **/
+    Bulk = 1,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileKind {
+    Photo,
+    Document,
+    Audio,
+}
+
+/** Where a file is taken from when its message is sent **/
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum FileSource {
+    /** Already on Telegram servers, sent again without an upload **/
+    Id(String),
+    Url(String),
+    /** Read from disk at send time, uploaded once while it is unchanged **/
+    Path(PathBuf),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Media {
+    pub kind: FileKind,
+    pub file: FileSource,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Message {
+    pub chat: i64,
+    pub text: String,
+    #[serde(default)]
+    pub markup: Option<ReplyMarkup>,
+    /** Broadcast to report the delivery to **/
+    #[serde(default)]
+    pub broadcast: Option<u64>,
+    #[serde(default)]
+    pub priority: Priority,
+    /** Files with `text` as the caption, 2-10 go as an album, which cannot carry `markup` **/
+    #[serde(default)]
+    pub media: Vec<Media>,
+}
+
+impl Message {
+    /** One file with the caption under it **/
+    pub fn file<S: AsRef<str>>(chat: i64, kind: FileKind, file: FileSource, caption: S) -> Self {
+        Self::group(chat, vec![Media { kind, file }], caption)
+    }
+
+    /** An album of 2-10 files, the caption goes under the first one **/
+    pub fn group<S: AsRef<str>>(chat: i64, media: Vec<Media>, caption: S) -> Self {
+        let mut ret = Self::from((chat, caption));
+        ret.media = media;
+        ret
+    }
+
+    /** Err is the reason Telegram would refuse the album **/
+    fn check_album(&self) -> Result<(), String> {
+        if self.media.len() > ALBUM_MAX_SIZE {
+            return Err(format!(
+                "album of {} files, at most {ALBUM_MAX_SIZE} fit",
+                self.media.len()
+            ));
+        }
+        if self.media.len() > 1 && self.markup.is_some() {
+            return Err(String::from("an album cannot carry reply markup"));
+        }
+        Ok(())
+    }
+}
 
 impl<S> From<(i64, S)> for Message
 where
     S: AsRef<str>,
 {
     fn from(value: (i64, S)) -> Self {
-        Self(value.0, String::from(value.1.as_ref()), None)
+        Self {
+            chat: value.0,
+            text: String::from(value.1.as_ref()),
+            markup: None,
+            broadcast: None,
+            priority: Priority::Interactive,
+            media: Vec::new(),
+        }
     }
 }
 
@@ -44,99 +139,657 @@ where
     S: AsRef<str>,
 {
     fn from(value: (i64, S, ReplyMarkup)) -> Self {
-        Self(value.0, String::from(value.1.as_ref()), Some(value.2))
+        Self {
+            chat: value.0,
+            text: String::from(value.1.as_ref()),
+            markup: Some(value.2),
+            broadcast: None,
+            priority: Priority::Interactive,
+            media: Vec::new(),
+        }
+    }
+}
+
+/** A message Telegram refused for good **/
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeadLetter {
+    /** ID it had in the queue **/
+    pub id: u64,
+    pub message: Message,
+    pub reason: String,
+    pub time: u64,
+}
+
+/** What to do with a message after a failed send **/
+enum Failure {
+    /** Telegram asked to wait, the chat alone or everything when it is the bot-wide limit **/
+    RetryAfter(Duration),
+    /** Network trouble or a Telegram outage, tried again with a growing delay **/
+    Transient,
+    /** The group became a supergroup with another ID **/
+    Migrated(i64),
+    /** The chat blocked the bot or is gone, later messages to it are not tried **/
+    Unreachable,
+    /** Telegram will never take this message, e.g. broken HTML **/
+    Rejected,
+}
+
+fn classify(error: &RequestError) -> Failure {
+    match error {
+        RequestError::RetryAfter(seconds) => Failure::RetryAfter(seconds.duration()),
+        RequestError::MigrateToChatId(chat) => Failure::Migrated(chat.0),
+        RequestError::Network(_) | RequestError::Io(_) => Failure::Transient,
+        // an answer the client cannot parse would come back the same on a retry
+        RequestError::InvalidJson { .. } => Failure::Rejected,
+        RequestError::Api(error) => match error {
+            ApiError::BotBlocked
+            | ApiError::ChatNotFound
+            | ApiError::UserNotFound
+            | ApiError::UserDeactivated
+            | ApiError::GroupDeactivated
+            | ApiError::BotKicked
+            | ApiError::BotKickedFromSupergroup
+            | ApiError::BotKickedFromChannel
+            | ApiError::CantInitiateConversation
+            | ApiError::CantTalkWithBots
+            | ApiError::NotEnoughRightsToPostMessages => Failure::Unreachable,
+            // 5xx from Telegram have no variant of their own
+            ApiError::Unknown(text) if TRANSIENT_API_ERRORS.iter().any(|x| text.contains(x)) => {
+                Failure::Transient
+            }
+            _ => Failure::Rejected,
+        },
+    }
+}
+
+/** Flood waits of two different chats close together are taken for the bot-wide limit **/
+fn is_global_flood(last: Option<(Instant, i64)>, chat: i64, now: Instant) -> bool {
+    last.is_some_and(|(at, other)| other != chat && now.duration_since(at) < FLOOD_GLOBAL_WINDOW)
+}
+
+/** Queue of outgoing messages kept in the storage, so a restart does not lose them and handlers never wait **/
+#[derive(Clone)]
+pub struct Outbox {
+    storage: Arc<dyn Storage>,
+    wake: Arc<Notify>,
+    /** Copy of the unreachable chats in the storage **/
+    unreachable: Arc<RwLock<HashSet<i64>>>,
+    /** Set by an interactive message, the sender stops a round of bulk ones to pick it up **/
+    urgent: Arc<AtomicBool>,
+}
+
+impl Outbox {
+    pub async fn open(storage: Arc<dyn Storage>) -> anyhow::Result<Self> {
+        let unreachable = storage.unreachable_chats().await?.into_iter().collect();
+        Ok(Self {
+            storage,
+            wake: Arc::new(Notify::new()),
+            unreachable: Arc::new(RwLock::new(unreachable)),
+            urgent: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    pub async fn push<M: Into<Message>>(&self, message: M) -> anyhow::Result<()> {
+        let message = message.into();
+        if let Err(reason) = message.check_album() {
+            bail!("Message to {} is not queued: {reason}", message.chat);
+        }
+        self.storage.enqueue_message(&message).await?;
+        if message.priority == Priority::Interactive {
+            self.urgent.store(true, Ordering::Release);
+        }
+        self.wake.notify_one();
+        Ok(())
     }
+
+    pub fn is_unreachable(&self, chat: i64) -> bool {
+        self.unreachable
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains(&chat)
+    }
+
+    /** A chat that writes to the bot again can be written to **/
+    pub async fn mark_reachable(&self, chat: i64) -> anyhow::Result<()> {
+        if !self.is_unreachable(chat) {
+            return Ok(());
+        }
+        self.storage.set_unreachable(chat, false).await?;
+        self.unreachable
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&chat);
+        Ok(())
+    }
+
+    async fn mark_unreachable(&self, chat: i64) -> anyhow::Result<()> {
+        self.storage.set_unreachable(chat, true).await?;
+        self.unreachable
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(chat);
+        Ok(())
+    }
+
+    pub async fn dead_letters(&self, count: usize) -> anyhow::Result<Vec<DeadLetter>> {
+        self.storage.dead_letters(count).await
+    }
+
+    /** Interactive messages then bulk ones, each lane is read on its own so a bulk backlog never
+    crowds out a reply. Chats in `waiting` are left for a later round **/
+    async fn next_round(&self, waiting: &HashSet<i64>) -> anyhow::Result<Vec<(u64, Message)>> {
+        let mut ret = self
+            .storage
+            .pending_messages(Priority::Interactive, BATCH_SIZE, waiting)
+            .await?;
+        // a chat gets one message per round, its bulk ones wait for its replies
+        let mut skip = waiting.clone();
+        skip.extend(ret.iter().map(|(_, message)| message.chat));
+        ret.extend(
+            self.storage
+                .pending_messages(Priority::Bulk, BATCH_SIZE, &skip)
+                .await?,
+        );
+        Ok(ret)
+    }
+}
+
+/** file_id of files uploaded from disk with the modification time they had **/
+type Uploads = HashMap<PathBuf, (SystemTime, String)>;
+
+fn modified(path: &Path) -> std::io::Result<SystemTime> {
+    std::fs::metadata(path)?.modified()
 }
 
 pub struct MessageSender {
-    recv: Receiver<Message>,
-    send: Sender<Message>,
+    outbox: Outbox,
     bot: DefaultParseMode<Bot>,
 }
 
-const LIMIT_RATE_PER_CHAT: u128 = 1000; // 1 sec
-const LIMIT_RATE_PER_ALL: i64 = 30; // 30/sec
+const LIMIT_RATE_PER_CHAT: Duration = Duration::from_secs(1);
+const LIMIT_RATE_PER_ALL: u32 = 30; // 30/sec
+/** Chats served in one round per lane, each with its oldest message **/
+const BATCH_SIZE: usize = 256;
+/** The queue is reread this often even without a wake up **/
+const IDLE_CHECK: Duration = Duration::from_secs(5);
+/** Delay after the first transient failure, doubled on every next one **/
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(300);
+/** Transient failures in a row before the message goes to the dead letters, some 25 minutes of retries **/
+const RETRY_MAX_ATTEMPTS: u32 = 12;
+/** Flood waits of one message before it goes to the dead letters **/
+const FLOOD_MAX_WAIT: Duration = Duration::from_secs(3600);
+/** Flood waits of two chats this close together mean the bot-wide limit, not a per-chat one **/
+const FLOOD_GLOBAL_WINDOW: Duration = Duration::from_secs(1);
+const TRANSIENT_API_ERRORS: [&str; 4] = [
+    "Internal Server Error",
+    "Bad Gateway",
+    "Service Unavailable",
+    "Gateway Timeout",
+];
+const UNREACHABLE_REASON: &str = "chat is unreachable";
+const ALBUM_MAX_SIZE: usize = 10;
 
 impl MessageSender {
-    pub fn new(bot: Bot) -> Self {
+    pub fn new(bot: Bot, outbox: Outbox) -> Self {
         let bot = bot.parse_mode(ParseMode::Html);
-        let (send, recv) = tokio::sync::mpsc::channel(1024);
-        Self { recv, send, bot }
+        Self { outbox, bot }
     }
 
-    pub fn sender(&self) -> Sender<Message> {
-        self.send.clone()
+    /** Files of a message ready to send, Err is the reason it never can be **/
+    fn input_files(message: &Message, uploads: &Uploads) -> Result<Vec<InputFile>, String> {
+        // queued before the check in `push`
+        message.check_album()?;
+        let mut ret = Vec::new();
+        for media in message.media.iter() {
+            ret.push(match &media.file {
+                FileSource::Id(id) => InputFile::file_id(id),
+                FileSource::Url(url) => match url.parse() {
+                    Ok(url) => InputFile::url(url),
+                    Err(e) => return Err(format!("bad url {url}: {e}")),
+                },
+                FileSource::Path(path) => match modified(path) {
+                    Ok(time) => match uploads.get(path) {
+                        Some((uploaded, id)) if *uploaded == time => InputFile::file_id(id),
+                        _ => InputFile::file(path),
+                    },
+                    Err(e) => return Err(format!("file {}: {e}", path.display())),
+                },
+            });
+        }
+        Ok(ret)
+    }
+
+    /** Returns the file_id Telegram gave to a single file **/
+    async fn send_message(
+        &self,
+        message: &Message,
+        mut files: Vec<InputFile>,
+    ) -> Result<Option<String>, RequestError> {
+        let chat = ChatId(message.chat);
+        let caption = &message.text;
+        if files.len() > 1 {
+            let mut media = Vec::new();
+            for (i, (item, file)) in message.media.iter().zip(files).enumerate() {
+                // only the first caption is shown under an album
+                let caption = if i == 0 { caption.as_str() } else { "" };
+                media.push(match item.kind {
+                    FileKind::Photo => {
+                        InputMedia::Photo(InputMediaPhoto::new(file).caption(caption))
+                    }
+                    FileKind::Document => {
+                        InputMedia::Document(InputMediaDocument::new(file).caption(caption))
+                    }
+                    FileKind::Audio => {
+                        InputMedia::Audio(InputMediaAudio::new(file).caption(caption))
+                    }
+                });
+            }
+            self.bot.send_media_group(chat, media).await?;
+            return Ok(None);
+        }
+        let Some(file) = files.pop() else {
+            let fut = self
+                .bot
+                .send_message(chat, caption)
+                .disable_link_preview(true);
+            match &message.markup {
+                None => fut.await?,
+                Some(kbd) => fut.reply_markup(kbd.clone()).await?,
+            };
+            return Ok(None);
+        };
+        let markup = message.markup.clone();
+        let sent = match message.media[0].kind {
+            FileKind::Photo => {
+                let mut fut = self.bot.send_photo(chat, file);
+                if !caption.is_empty() {
+                    fut = fut.caption(caption);
+                }
+                if let Some(kbd) = markup {
+                    fut = fut.reply_markup(kbd);
+                }
+                fut.await?
+            }
+            FileKind::Document => {
+                let mut fut = self.bot.send_document(chat, file);
+                if !caption.is_empty() {
+                    fut = fut.caption(caption);
+                }
+                if let Some(kbd) = markup {
+                    fut = fut.reply_markup(kbd);
+                }
+                fut.await?
+            }
+            FileKind::Audio => {
+                let mut fut = self.bot.send_audio(chat, file);
+                if !caption.is_empty() {
+                    fut = fut.caption(caption);
+                }
+                if let Some(kbd) = markup {
+                    fut = fut.reply_markup(kbd);
+                }
+                fut.await?
+            }
+        };
+        let id = match message.media[0].kind {
+            FileKind::Photo => sent.photo().and_then(|x| x.last()).map(|x| &x.file.id),
+            FileKind::Document => sent.document().map(|x| &x.file.id),
+            FileKind::Audio => sent.audio().map(|x| &x.file.id),
+        };
+        Ok(id.cloned())
     }
 
-    #[inline(always)]
-    async fn send_message(&self, message: Message) -> Result<(), (anyhow::Error, Message)> {
-        let fut = self
-            .bot
-            .send_message(ChatId(message.0), &message.1)
-            .disable_link_preview(true);
-        let res = match &message.2 {
-            None => fut.await,
-            Some(kbd) => fut.reply_markup(kbd.clone()).await,
+    async fn bury(&self, id: u64, message: Message, reason: String) {
+        warn!("Message {id} to {} is dropped: {reason}", message.chat);
+        let letter = DeadLetter {
+            id,
+            message,
+            reason,
+            time: unix_now(),
         };
-        if let Err(e) = res {
-            Err((e.into(), message))
-        } else {
-            Ok(())
+        if let Err(e) = self.outbox.storage.bury_message(&letter).await {
+            error!("Error moving message {id} to the dead letters: {e}");
         }
+        self.report_delivery(&letter.message, Delivery::Failed(letter.reason.clone()))
+            .await;
     }
 
-    pub async fn start(mut self) {
-        let mut timeouts: HashMap<i64, SystemTime> = HashMap::new();
-        let counter = Arc::new(AtomicI64::new(LIMIT_RATE_PER_ALL));
+    /** Records how a broadcast message went, the last one posts the report to the admin **/
+    async fn report_delivery(&self, message: &Message, delivery: Delivery) {
+        let Some(id) = message.broadcast else {
+            return;
+        };
+        match self
+            .outbox
+            .storage
+            .set_delivery(id, message.chat, &delivery)
+            .await
         {
-            let counter = counter.clone();
-            tokio::spawn(async move {
-                loop {
-                    sleep(Duration::from_secs(1)).await;
-                    counter.store(LIMIT_RATE_PER_ALL, SeqCst); // set to 30 each second
+            Ok(0) => {
+                if let Err(e) = self.finish_broadcast(id).await {
+                    error!("Error reporting broadcast {id}: {e}");
                 }
-            });
+            }
+            Ok(_) => {}
+            Err(e) => error!("Error recording delivery of broadcast {id}: {e}"),
         }
-        while let Some(mut message) = self.recv.recv().await {
-            // check for global rate limit
-            loop {
-                let left = counter.load(SeqCst);
-                if left < 1 {
-                    sleep(Duration::from_millis(100)).await;
-                } else {
-                    counter.fetch_add(-1, SeqCst);
-                    break;
+    }
+
+    async fn finish_broadcast(&self, id: u64) -> anyhow::Result<()> {
+        let storage = &self.outbox.storage;
+        let Some(mut broadcast) = storage.get_broadcast(id).await? else {
+            return Ok(());
+        };
+        if broadcast.reported != 0 {
+            return Ok(());
+        }
+        broadcast.reported = unix_now();
+        storage.update_broadcast(&broadcast).await?;
+        let stats = DeliveryStats::count(&storage.deliveries(id).await?);
+        let report = Format::format_broadcast_report(&broadcast, &stats);
+        self.outbox.push((broadcast.admin as i64, report)).await
+    }
+
+    /** Delivers the queue in order per chat, a message leaves the queue once Telegram took or refused it **/
+    pub async fn start(self) {
+        // when a chat may get its next message
+        let mut ready_at: HashMap<i64, Instant> = HashMap::new();
+        // failed tries and flood waits of the messages being retried
+        let mut retries: HashMap<u64, (u32, Duration)> = HashMap::new();
+        // the last flood wait, to tell the bot-wide limit from a per-chat one
+        let mut last_flood: Option<(Instant, i64)> = None;
+        let mut uploads = Uploads::new();
+        let mut window = Instant::now();
+        let mut sent = 0;
+        loop {
+            // cleared before the read, so anything pushed after it stops the round
+            self.outbox.urgent.store(false, Ordering::Release);
+            let now = Instant::now();
+            ready_at.retain(|_, x| *x > now);
+            // chats that are rate limited or backing off are left in the queue unread
+            let waiting: HashSet<i64> = ready_at.keys().copied().collect();
+            let batch = match self.outbox.next_round(&waiting).await {
+                Ok(batch) => batch,
+                Err(e) => {
+                    error!("Error reading the outbox: {e}");
+                    sleep(IDLE_CHECK).await;
+                    continue;
                 }
+            };
+            if batch.is_empty() {
+                let next = ready_at
+                    .values()
+                    .min()
+                    .map_or(IDLE_CHECK, |x| x.duration_since(now).min(IDLE_CHECK));
+                let _ = timeout(next, self.outbox.wake.notified()).await;
+                continue;
             }
-
-            if let Some(t) = timeouts.get(&message.0) {
-                if let Ok(elapsed) = t.elapsed() {
-                    if elapsed.as_millis() < LIMIT_RATE_PER_CHAT {
-                        debug!("Message is not ready for {}, push_back", message.0);
-                        if let Err(e) = self.send.send(message).await {
-                            // we can't push_back - we must wait
-                            sleep(Duration::from_millis(
-                                (LIMIT_RATE_PER_CHAT - elapsed.as_millis()) as u64,
-                            ))
-                            .await;
-                            message = e.0;
+            // one message per chat, so each chat keeps its order
+            for (id, message) in batch {
+                let chat = message.chat;
+                if message.priority == Priority::Bulk && self.outbox.urgent.load(Ordering::Acquire)
+                {
+                    debug!("Interactive message queued, bulk round stopped");
+                    break;
+                }
+                if self.outbox.is_unreachable(chat) {
+                    self.bury(id, message, String::from(UNREACHABLE_REASON))
+                        .await;
+                    continue;
+                }
+                let files = match Self::input_files(&message, &uploads) {
+                    Ok(files) => files,
+                    Err(reason) => {
+                        self.bury(id, message, reason).await;
+                        continue;
+                    }
+                };
+                // every file of an album counts as a message
+                let cost = message.media.len().max(1) as u32;
+                // check for global rate limit
+                if window.elapsed() >= Duration::from_secs(1) {
+                    window = Instant::now();
+                    sent = 0;
+                }
+                if sent > 0 && sent + cost > LIMIT_RATE_PER_ALL {
+                    sleep(Duration::from_secs(1).saturating_sub(window.elapsed())).await;
+                    window = Instant::now();
+                    sent = 0;
+                }
+                sent += cost;
+                ready_at.insert(chat, Instant::now() + LIMIT_RATE_PER_CHAT);
+                let error = match self.send_message(&message, files).await {
+                    Ok(file_id) => {
+                        if let (Some(file_id), [media]) = (file_id, message.media.as_slice())
+                            && let FileSource::Path(path) = &media.file
+                            && let Ok(time) = modified(path)
+                        {
+                            uploads.insert(path.clone(), (time, file_id));
+                        }
+                        retries.remove(&id);
+                        if let Err(e) = self.outbox.storage.ack_message(id).await {
+                            error!("Error removing sent message {id} from the outbox: {e}");
+                        } else {
+                            self.report_delivery(&message, Delivery::Sent).await;
+                        }
+                        continue;
+                    }
+                    Err(error) => error,
+                };
+                match classify(&error) {
+                    Failure::RetryAfter(delay) => {
+                        let waited = &mut retries.entry(id).or_default().1;
+                        *waited += delay;
+                        if *waited > FLOOD_MAX_WAIT {
+                            let reason = format!(
+                                "gave up after waiting {}s for flood control: {error}",
+                                waited.as_secs()
+                            );
+                            retries.remove(&id);
+                            self.bury(id, message, reason).await;
+                            continue;
+                        }
+                        let now = Instant::now();
+                        if is_global_flood(last_flood, chat, now) {
+                            warn!(
+                                "Telegram asked to wait {}s, all sending paused",
+                                delay.as_secs()
+                            );
+                            sleep(delay).await;
+                            window = Instant::now();
+                            sent = 0;
                         } else {
+                            warn!(
+                                "Telegram asked to wait {}s for chat {chat}",
+                                delay.as_secs()
+                            );
+                            ready_at.insert(chat, now + delay);
+                        }
+                        last_flood = Some((now, chat));
+                    }
+                    Failure::Transient => {
+                        let tries = &mut retries.entry(id).or_default().0;
+                        *tries += 1;
+                        if *tries >= RETRY_MAX_ATTEMPTS {
+                            retries.remove(&id);
+                            let reason =
+                                format!("gave up after {RETRY_MAX_ATTEMPTS} tries: {error}");
+                            self.bury(id, message, reason).await;
                             continue;
                         }
+                        let delay = RETRY_DELAY
+                            .saturating_mul(1 << (*tries - 1).min(16))
+                            .min(RETRY_MAX_DELAY);
+                        info!(
+                            "Error sending message {id} to {chat}, retry in {}s: {error}",
+                            delay.as_secs()
+                        );
+                        ready_at.insert(chat, Instant::now() + delay);
+                    }
+                    Failure::Migrated(to) => {
+                        info!("Chat {chat} moved to {to}");
+                        // the report counts chats it was started with
+                        self.report_delivery(
+                            &message,
+                            Delivery::Failed(format!("moved to {to}, sent there untracked")),
+                        )
+                        .await;
+                        let mut message = message;
+                        message.chat = to;
+                        message.broadcast = None;
+                        if let Err(e) = self.outbox.push(message).await {
+                            error!("Error requeueing message {id} for {to}: {e}");
+                        } else if let Err(e) = self.outbox.storage.ack_message(id).await {
+                            error!("Error removing moved message {id} from the outbox: {e}");
+                        }
+                    }
+                    Failure::Unreachable => {
+                        retries.remove(&id);
+                        if let Err(e) = self.outbox.mark_unreachable(chat).await {
+                            error!("Error marking {chat} unreachable: {e}");
+                        }
+                        self.bury(id, message, error.to_string()).await;
+                    }
+                    Failure::Rejected => {
+                        retries.remove(&id);
+                        self.bury(id, message, error.to_string()).await;
                     }
                 }
             }
-            // send immediately
-            let id = message.0;
-            if let Err((e, message)) = self.send_message(message).await {
-                info!("Error sending message to {}: {}", message.0, e);
-                // resend
-                if let Err(e) = self.send.send(message).await {
-                    error!("Error sending message: {:?}", e);
-                }
-            } else {
-                timeouts.insert(id, SystemTime::now());
-            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use teloxide::types::{InlineKeyboardMarkup, Seconds};
+
+    fn bulk(chat: i64) -> Message {
+        let mut message = Message::from((chat, "bulk"));
+        message.priority = Priority::Bulk;
+        message
+    }
+
+    #[test]
+    fn flood_waits_of_two_chats_pause_all() {
+        let now = Instant::now();
+        assert!(!is_global_flood(None, 1, now));
+        assert!(!is_global_flood(Some((now, 1)), 1, now));
+        assert!(is_global_flood(Some((now, 1)), 2, now));
+        assert!(!is_global_flood(
+            Some((now, 1)),
+            2,
+            now + FLOOD_GLOBAL_WINDOW
+        ));
+    }
+
+    #[test]
+    fn failures_are_classified() {
+        let api = |error: ApiError| classify(&RequestError::Api(error));
+        assert!(matches!(
+            classify(&RequestError::RetryAfter(Seconds::from_seconds(7))),
+            Failure::RetryAfter(x) if x == Duration::from_secs(7)
+        ));
+        assert!(matches!(
+            classify(&RequestError::MigrateToChatId(ChatId(-100))),
+            Failure::Migrated(-100)
+        ));
+        let io = std::io::Error::other("reset");
+        assert!(matches!(
+            classify(&RequestError::Io(Arc::new(io))),
+            Failure::Transient
+        ));
+        assert!(matches!(
+            api(ApiError::Unknown(String::from("Bad Gateway"))),
+            Failure::Transient
+        ));
+        let json = serde_json::from_str::<u8>("{").unwrap_err();
+        assert!(matches!(
+            classify(&RequestError::InvalidJson {
+                source: Arc::new(json),
+                raw: "{".into(),
+            }),
+            Failure::Rejected
+        ));
+        assert!(matches!(api(ApiError::BotBlocked), Failure::Unreachable));
+        assert!(matches!(api(ApiError::ChatNotFound), Failure::Unreachable));
+        assert!(matches!(
+            api(ApiError::MessageTextIsEmpty),
+            Failure::Rejected
+        ));
+        assert!(matches!(
+            api(ApiError::Unknown(String::from(
+                "Bad Request: can't parse entities"
+            ))),
+            Failure::Rejected
+        ));
+    }
+
+    #[tokio::test]
+    async fn interactive_goes_ahead_of_bulk_backlog() {
+        let outbox = Outbox::open(Arc::new(MemoryStorage::default()))
+            .await
+            .unwrap();
+        // more bulk chats than a round takes, half of them backing off
+        for chat in 0..BATCH_SIZE as i64 * 2 {
+            outbox.push(bulk(chat)).await.unwrap();
+            outbox.push(bulk(chat)).await.unwrap();
+        }
+        let waiting: HashSet<i64> = (0..BATCH_SIZE as i64).collect();
+        outbox.push((-1, "reply")).await.unwrap();
+        outbox.push((0, "reply to a waiting chat")).await.unwrap();
+
+        let round = outbox.next_round(&waiting).await.unwrap();
+        assert_eq!(round[0].1.chat, -1);
+        assert_eq!(round[0].1.priority, Priority::Interactive);
+        assert_eq!(round.len(), 1 + BATCH_SIZE);
+        // one message per chat and none for chats that are not ready
+        let chats: HashSet<i64> = round.iter().map(|(_, message)| message.chat).collect();
+        assert_eq!(chats.len(), round.len());
+        assert!(chats.is_disjoint(&waiting));
+    }
+
+    #[tokio::test]
+    async fn albums_are_checked_when_queued() {
+        let outbox = Outbox::open(Arc::new(MemoryStorage::default()))
+            .await
+            .unwrap();
+        let files = |count: usize| {
+            (0..count)
+                .map(|i| Media {
+                    kind: FileKind::Photo,
+                    file: FileSource::Id(i.to_string()),
+                })
+                .collect::<Vec<Media>>()
+        };
+        outbox.push(Message::group(1, files(10), "")).await.unwrap();
+        assert!(outbox.push(Message::group(1, files(11), "")).await.is_err());
+        let mut album = Message::group(1, files(2), "");
+        album.markup = Some(InlineKeyboardMarkup::default().into());
+        assert!(outbox.push(album).await.is_err());
+        // a single file keeps its markup
+        let mut file = Message::group(1, files(1), "");
+        file.markup = Some(InlineKeyboardMarkup::default().into());
+        outbox.push(file).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn bulk_waits_for_replies_to_the_same_chat() {
+        let outbox = Outbox::open(Arc::new(MemoryStorage::default()))
+            .await
+            .unwrap();
+        outbox.push(bulk(1)).await.unwrap();
+        outbox.push(bulk(2)).await.unwrap();
+        outbox.push((1, "reply")).await.unwrap();
+
+        let round = outbox.next_round(&HashSet::new()).await.unwrap();
+        let sent: Vec<(i64, Priority)> = round
+            .iter()
+            .map(|(_, message)| (message.chat, message.priority))
+            .collect();
+        assert_eq!(sent, [(1, Priority::Interactive), (2, Priority::Bulk)]);
+    }
+}
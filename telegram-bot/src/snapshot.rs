@@ -0,0 +1,231 @@
+/**
+Copyright 2025 Ivan Agarkov
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+**/
+use crate::api::{
+    Attempt, AuditEntry, Broadcast, Delivery, Event, Invite, Role, Sanction, Schedule, Task,
+    Vas3kUser, salt_fingerprint, unix_now,
+};
+use crate::sender::{DeadLetter, Message};
+use crate::storage::{DEFAULT_EVENT, Storage};
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/** Bumped on every incompatible change of the archive layout **/
+pub const SNAPSHOT_VERSION: u32 = 3;
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct SnapshotConfig {
+    /** Where /snapshot and the periodic snapshots are written **/
+    pub dir: String,
+    /** Seconds between automatic snapshots, 0 turns them off **/
+    pub interval: u64,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        Self {
+            dir: String::from("snapshots"),
+            interval: 0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SolveRecord {
+    pub user_id: u64,
+    pub task_id: String,
+    /** Unranked solves may have no time, those are written as 0 **/
+    pub time: u64,
+    pub ranked: bool,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct EventSnapshot {
+    /** Missing for archives from before events, the bot recreates it from the config **/
+    #[serde(default)]
+    pub event: Option<Event>,
+    pub tasks: BTreeMap<String, Task>,
+    pub solves: Vec<SolveRecord>,
+    pub attempts: Vec<Attempt>,
+}
+
+/** A broadcast with how it went to each of its chats **/
+#[derive(Serialize, Deserialize)]
+pub struct BroadcastSnapshot {
+    pub broadcast: Broadcast,
+    pub deliveries: BTreeMap<i64, Delivery>,
+}
+
+/** Every event and the shared user data in one document **/
+#[derive(Serialize, Deserialize, Default)]
+pub struct Snapshot {
+    pub version: u32,
+    pub created: u64,
+    /** `salt_fingerprint` of the salt the flags are hashed with, missing in older archives **/
+    #[serde(default)]
+    pub salt: Option<String>,
+    pub current_event: Option<String>,
+    pub events: BTreeMap<String, EventSnapshot>,
+    pub users: BTreeMap<u64, Vas3kUser>,
+    pub states: BTreeMap<u64, String>,
+    pub contacts: BTreeMap<u64, String>,
+    /** User to the invite code they joined with **/
+    #[serde(default)]
+    pub registrations: BTreeMap<u64, String>,
+    #[serde(default)]
+    pub invites: BTreeMap<String, Invite>,
+    #[serde(default)]
+    pub sanctions: BTreeMap<u64, Vec<Sanction>>,
+    #[serde(default)]
+    pub audit: Vec<AuditEntry>,
+    #[serde(default)]
+    pub roles: Vec<(i64, Role)>,
+    /** Roles of `roles` seeded from the config groups **/
+    #[serde(default)]
+    pub config_roles: Vec<(i64, Role)>,
+    /** Queued messages by their ID, restored in the same order **/
+    #[serde(default)]
+    pub outbox: BTreeMap<u64, Message>,
+    #[serde(default)]
+    pub dead_letters: Vec<DeadLetter>,
+    #[serde(default)]
+    pub unreachable: Vec<i64>,
+    #[serde(default)]
+    pub broadcasts: BTreeMap<u64, BroadcastSnapshot>,
+    #[serde(default)]
+    pub schedules: BTreeMap<u64, Schedule>,
+    /** Records left out of the export because they did not decode **/
+    #[serde(skip)]
+    pub skipped: usize,
+}
+
+/** Version 1 layout, a single event without a record of its own **/
+#[derive(Deserialize)]
+struct SnapshotV1 {
+    created: u64,
+    tasks: BTreeMap<String, Task>,
+    users: BTreeMap<u64, Vas3kUser>,
+    solves: Vec<SolveRecord>,
+    attempts: Vec<Attempt>,
+    states: BTreeMap<u64, String>,
+    contacts: BTreeMap<u64, String>,
+}
+
+impl From<SnapshotV1> for Snapshot {
+    fn from(value: SnapshotV1) -> Self {
+        let event = EventSnapshot {
+            event: None,
+            tasks: value.tasks,
+            solves: value.solves,
+            attempts: value.attempts,
+        };
+        Self {
+            version: SNAPSHOT_VERSION,
+            created: value.created,
+            salt: None,
+            current_event: None,
+            events: BTreeMap::from([(String::from(DEFAULT_EVENT), event)]),
+            users: value.users,
+            states: value.states,
+            contacts: value.contacts,
+            registrations: BTreeMap::new(),
+            invites: BTreeMap::new(),
+            sanctions: BTreeMap::new(),
+            audit: Vec::new(),
+            roles: Vec::new(),
+            config_roles: Vec::new(),
+            outbox: BTreeMap::new(),
+            dead_letters: Vec::new(),
+            unreachable: Vec::new(),
+            broadcasts: BTreeMap::new(),
+            schedules: BTreeMap::new(),
+            skipped: 0,
+        }
+    }
+}
+
+impl Snapshot {
+    pub fn new() -> Self {
+        Self {
+            version: SNAPSHOT_VERSION,
+            created: unix_now(),
+            ..Default::default()
+        }
+    }
+}
+
+pub fn snapshot_path(dir: &str, created: u64) -> PathBuf {
+    Path::new(dir).join(format!("snapshot-{created}.json"))
+}
+
+/** Dumps the store into `path`, the file is replaced only once it is fully written,
+returns how many records were left out because they did not decode **/
+pub async fn save(storage: &dyn Storage, path: &Path, salt: &str) -> anyhow::Result<usize> {
+    let mut snapshot = storage.export().await?;
+    snapshot.salt = Some(salt_fingerprint(salt));
+    let data = serde_json::to_vec(&snapshot)?;
+    if let Some(dir) = path.parent()
+        && !dir.as_os_str().is_empty()
+    {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, data).await?;
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(snapshot.skipped)
+}
+
+pub async fn load(path: &Path) -> anyhow::Result<Snapshot> {
+    let data = tokio::fs::read(path).await?;
+    let value = serde_json::from_slice::<serde_json::Value>(&data)?;
+    match value.get("version").and_then(|x| x.as_u64()) {
+        Some(1) => Ok(serde_json::from_value::<SnapshotV1>(value)?.into()),
+        // version 2 had no queue, broadcasts or schedules, they start empty
+        Some(2) => {
+            let mut snapshot = serde_json::from_value::<Snapshot>(value)?;
+            snapshot.version = SNAPSHOT_VERSION;
+            Ok(snapshot)
+        }
+        Some(version) if version == SNAPSHOT_VERSION as u64 => Ok(serde_json::from_value(value)?),
+        version => {
+            bail!("Snapshot version {version:?} is not supported, expected {SNAPSHOT_VERSION}")
+        }
+    }
+}
+
+/** Loads a snapshot, refuses to mix it with existing data or to take flags hashed with another salt **/
+pub async fn restore(storage: &dyn Storage, snapshot: &Snapshot, salt: &str) -> anyhow::Result<()> {
+    if let Some(fingerprint) = &snapshot.salt
+        && *fingerprint != salt_fingerprint(salt)
+    {
+        bail!(
+            "Snapshot flags are hashed with another flag_salt, restore with the salt it was taken with"
+        );
+    }
+    if !storage.event_ids().await?.is_empty() || !storage.user_ids().await?.is_empty() {
+        bail!("Storage is not empty, restore only into an empty one");
+    }
+    // a store without event records can still hold data of the default event
+    if !storage.task_ids(DEFAULT_EVENT).await?.is_empty()
+        || !storage.scores(DEFAULT_EVENT).await?.is_empty()
+        || storage.attempt_counters(DEFAULT_EVENT).await?.attempts > 0
+    {
+        bail!("Storage is not empty, restore only into an empty one");
+    }
+    storage.import(snapshot).await
+}
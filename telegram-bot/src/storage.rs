@@ -0,0 +1,376 @@
+/**
+Copyright 2025 Ivan Agarkov
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+**/
+mod memory;
+mod redis;
+mod sqlite;
+/** What every backend has to do the same way, each check runs against all of them **/
+#[cfg(test)]
+mod tests;
+
+use crate::api::{
+    Attempt, AuditEntry, Broadcast, Delivery, Event, Invite, Role, Sanction, SanctionKind,
+    Schedule, Task, Vas3kUser,
+};
+use crate::sender::{DeadLetter, Message, Priority};
+use crate::snapshot::Snapshot;
+use anyhow::{anyhow, bail};
+use async_trait::async_trait;
+use log::error;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use std::collections::{HashMap, HashSet};
+
+pub use self::memory::MemoryStorage;
+pub use self::redis::RedisStorage;
+pub use self::sqlite::SqliteStorage;
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum StorageConfig {
+    Redis(RedisConfig),
+    Memory,
+    Sqlite { path: String },
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self::Redis(RedisConfig::default())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct RedisConfig {
+    pub url: String,
+    pub password: Option<String>,
+    pub db: Option<i64>,
+    pub tls: bool,
+    /** How many times to retry a lost connection before failing a command **/
+    pub retries: usize,
+    /** Upper bound for the exponential backoff between retries, ms **/
+    pub max_delay: u64,
+}
+
+impl Default for RedisConfig {
+    fn default() -> Self {
+        Self {
+            url: String::from("redis://127.0.0.1/"),
+            password: None,
+            db: None,
+            tls: false,
+            retries: 6,
+            max_delay: 10_000,
+        }
+    }
+}
+
+pub enum TaskWrite {
+    Done,
+    IdTaken,
+    /** (flag, task that owns it) **/
+    FlagTaken(String, String),
+}
+
+pub enum InviteRedeem {
+    /** Also returned to a user who has joined before, without taking a use **/
+    Done,
+    Unknown,
+    Revoked,
+    Expired,
+    Exhausted,
+}
+
+#[derive(Default)]
+pub struct AttemptCounters {
+    pub participants: u64,
+    pub attempts: u64,
+    /** (user, wrong attempts), most wrong first **/
+    pub wrong: Vec<(u64, u64)>,
+}
+
+/** Event that databases from before namespaces are moved into **/
+pub const DEFAULT_EVENT: &str = "main";
+
+/** Everything the bot keeps between restarts, every write is atomic on the storage side.
+Tasks, solves and attempts belong to an event, users, states and contacts are shared **/
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn get_event(&self, id: &str) -> anyhow::Result<Option<Event>>;
+    async fn event_ids(&self) -> anyhow::Result<Vec<String>>;
+    /** Returns false if an event with this ID already exists **/
+    async fn create_event(&self, event: &Event) -> anyhow::Result<bool>;
+    async fn update_event(&self, event: &Event) -> anyhow::Result<()>;
+    async fn current_event(&self) -> anyhow::Result<Option<String>>;
+    async fn set_current_event(&self, id: &str) -> anyhow::Result<()>;
+
+    async fn get_task(&self, event: &str, id: &str) -> anyhow::Result<Option<Task>>;
+    async fn task_ids(&self, event: &str) -> anyhow::Result<Vec<String>>;
+    async fn task_by_flag(&self, event: &str, flag: &str) -> anyhow::Result<Option<String>>;
+    /** Stores a new task under `task.id` unless the ID or any of its flags is taken **/
+    async fn create_task(&self, event: &str, task: &Task) -> anyhow::Result<TaskWrite>;
+    /** Replaces the task under `task.id` and re-indexes its flags unless a flag is taken **/
+    async fn update_task(&self, event: &str, task: &Task) -> anyhow::Result<TaskWrite>;
+    async fn delete_task(&self, event: &str, id: &str) -> anyhow::Result<()>;
+
+    async fn is_solved(&self, event: &str, user_id: u64, task_id: &str) -> anyhow::Result<bool>;
+    /** Returns false if it was already solved. Only `ranked` solves go to the board and to the per-task stats **/
+    async fn add_solve(
+        &self,
+        event: &str,
+        user_id: u64,
+        task_id: &str,
+        time: u64,
+        ranked: bool,
+    ) -> anyhow::Result<bool>;
+    async fn solves_count(&self, event: &str, user_id: u64) -> anyhow::Result<u64>;
+    /** (user, score), best first **/
+    async fn scores(&self, event: &str) -> anyhow::Result<Vec<(u64, u64)>>;
+    /** Number of ranked solves and the earliest timed one, both leaving out users in `skip`; first as (user, time) **/
+    async fn task_solves(
+        &self,
+        event: &str,
+        task_id: &str,
+        skip: &HashSet<u64>,
+    ) -> anyhow::Result<(u64, Option<(u64, u64)>)>;
+
+    /** Only `attempt.counted` ones go to the counters **/
+    async fn log_attempt(&self, event: &str, attempt: &Attempt) -> anyhow::Result<()>;
+    async fn attempt_counters(&self, event: &str) -> anyhow::Result<AttemptCounters>;
+
+    async fn get_state(&self, user_id: u64) -> anyhow::Result<Option<String>>;
+    async fn set_state(&self, user_id: u64, state: &str) -> anyhow::Result<()>;
+
+    async fn append_contact(&self, user_id: u64, text: &str) -> anyhow::Result<()>;
+    /** Returns the collected message and clears it **/
+    async fn take_contact(&self, user_id: u64) -> anyhow::Result<String>;
+    /** Returns the collected message, it is kept **/
+    async fn get_contact(&self, user_id: u64) -> anyhow::Result<String>;
+
+    async fn get_user(&self, user_id: u64) -> anyhow::Result<Option<Vas3kUser>>;
+    async fn put_user(&self, user_id: u64, user: &Vas3kUser) -> anyhow::Result<()>;
+    async fn user_ids(&self) -> anyhow::Result<Vec<u64>>;
+
+    /** Records the invite code a user joined with **/
+    async fn register(&self, user_id: u64, code: &str) -> anyhow::Result<()>;
+    async fn registration(&self, user_id: u64) -> anyhow::Result<Option<String>>;
+    /** Users who joined with the code **/
+    async fn code_users(&self, code: &str) -> anyhow::Result<Vec<u64>>;
+
+    async fn get_invite(&self, code: &str) -> anyhow::Result<Option<Invite>>;
+    async fn invite_codes(&self) -> anyhow::Result<Vec<String>>;
+    /** Returns false if the code is taken **/
+    async fn create_invite(&self, invite: &Invite) -> anyhow::Result<bool>;
+    async fn update_invite(&self, invite: &Invite) -> anyhow::Result<()>;
+    /** Checks the code and takes one use of it while registering the user, all at once **/
+    async fn redeem_invite(
+        &self,
+        code: &str,
+        user_id: u64,
+        now: u64,
+    ) -> anyhow::Result<InviteRedeem>;
+
+    async fn sanctions(&self, user_id: u64) -> anyhow::Result<Vec<Sanction>>;
+    /** (user, sanction) for every user, expired ones included **/
+    async fn all_sanctions(&self) -> anyhow::Result<Vec<(u64, Sanction)>>;
+    async fn put_sanction(&self, user_id: u64, sanction: &Sanction) -> anyhow::Result<()>;
+    /** Returns false if there was none **/
+    async fn remove_sanction(&self, user_id: u64, kind: SanctionKind) -> anyhow::Result<bool>;
+
+    /** (Telegram ID, role) pairs, chats have negative IDs **/
+    async fn roles(&self) -> anyhow::Result<Vec<(i64, Role)>>;
+    /** Returns false if it was already granted **/
+    async fn grant_role(&self, id: i64, role: Role) -> anyhow::Result<bool>;
+    /** Returns false if there was none, the config mark goes with it **/
+    async fn revoke_role(&self, id: i64, role: Role) -> anyhow::Result<bool>;
+    /** Roles seeded from the config groups, only these are revoked when an ID leaves a group **/
+    async fn config_roles(&self) -> anyhow::Result<Vec<(i64, Role)>>;
+    /** Marks a granted role as seeded from the config or clears the mark **/
+    async fn set_config_role(&self, id: i64, role: Role, config: bool) -> anyhow::Result<()>;
+
+    async fn log_audit(&self, entry: &AuditEntry) -> anyhow::Result<()>;
+    /** The last `count` entries, oldest first **/
+    async fn audit(&self, count: usize) -> anyhow::Result<Vec<AuditEntry>>;
+
+    /** Queues an outgoing Telegram message, returns its ID, IDs grow in queue order **/
+    async fn enqueue_message(&self, message: &Message) -> anyhow::Result<u64>;
+    /** The next message in the lane of up to `count` chats, chats in `skip` are left out. They stay
+    queued until acked. A message that does not decode is logged and dropped, so it never holds up
+    the rest. A backend may look only so far into a long lane and return fewer **/
+    async fn pending_messages(
+        &self,
+        lane: Priority,
+        count: usize,
+        skip: &HashSet<i64>,
+    ) -> anyhow::Result<Vec<(u64, Message)>>;
+    async fn ack_message(&self, id: u64) -> anyhow::Result<()>;
+    /** Moves a message that cannot be delivered out of the queue **/
+    async fn bury_message(&self, letter: &DeadLetter) -> anyhow::Result<()>;
+    /** The last dead letters, oldest first **/
+    async fn dead_letters(&self, count: usize) -> anyhow::Result<Vec<DeadLetter>>;
+    /** Chats that blocked the bot or are gone **/
+    async fn unreachable_chats(&self) -> anyhow::Result<Vec<i64>>;
+    async fn set_unreachable(&self, chat: i64, unreachable: bool) -> anyhow::Result<()>;
+
+    /** Saves a new broadcast with every chat pending, returns its ID **/
+    async fn create_broadcast(&self, broadcast: &Broadcast, chats: &[i64]) -> anyhow::Result<u64>;
+    async fn get_broadcast(&self, id: u64) -> anyhow::Result<Option<Broadcast>>;
+    async fn update_broadcast(&self, broadcast: &Broadcast) -> anyhow::Result<()>;
+    /** The last broadcasts, oldest first **/
+    async fn broadcasts(&self, count: usize) -> anyhow::Result<Vec<Broadcast>>;
+    async fn deliveries(&self, id: u64) -> anyhow::Result<HashMap<i64, Delivery>>;
+    /** Returns how many chats of the broadcast are still pending **/
+    async fn set_delivery(&self, id: u64, chat: i64, delivery: &Delivery) -> anyhow::Result<usize>;
+
+    /** Saves a scheduled broadcast, returns its ID **/
+    async fn add_schedule(&self, schedule: &Schedule) -> anyhow::Result<u64>;
+    /** Every pending schedule, in no particular order **/
+    async fn schedules(&self) -> anyhow::Result<Vec<Schedule>>;
+    /** Returns false if there was none, so only one caller gets to send it **/
+    async fn remove_schedule(&self, id: u64) -> anyhow::Result<bool>;
+
+    /** Copy of the whole store taken at a single point in time **/
+    async fn export(&self) -> anyhow::Result<Snapshot>;
+    /** Replays a snapshot through the regular writes, counters are rebuilt on the way **/
+    async fn import(&self, snapshot: &Snapshot) -> anyhow::Result<()> {
+        for (event_id, data) in snapshot.events.iter() {
+            if let Some(event) = data.event.as_ref() {
+                let mut event = event.clone();
+                event.id = event_id.clone();
+                self.create_event(&event).await?;
+            }
+            for (id, task) in data.tasks.iter() {
+                let mut task = task.clone();
+                task.id = id.clone();
+                if let TaskWrite::FlagTaken(flag, owner) = self.create_task(event_id, &task).await?
+                {
+                    bail!("Flag {flag} of task {id} is already used by task {owner}");
+                }
+            }
+            for solve in data.solves.iter() {
+                self.add_solve(
+                    event_id,
+                    solve.user_id,
+                    &solve.task_id,
+                    solve.time,
+                    solve.ranked,
+                )
+                .await?;
+            }
+            for attempt in data.attempts.iter() {
+                self.log_attempt(event_id, attempt).await?;
+            }
+        }
+        if let Some(current) = snapshot.current_event.as_ref() {
+            self.set_current_event(current).await?;
+        }
+        for (user_id, user) in snapshot.users.iter() {
+            self.put_user(*user_id, user).await?;
+        }
+        for (user_id, state) in snapshot.states.iter() {
+            self.set_state(*user_id, state).await?;
+        }
+        for (user_id, message) in snapshot.contacts.iter() {
+            self.append_contact(*user_id, message).await?;
+        }
+        for (code, invite) in snapshot.invites.iter() {
+            let mut invite = invite.clone();
+            invite.code = code.clone();
+            self.create_invite(&invite).await?;
+        }
+        for (user_id, code) in snapshot.registrations.iter() {
+            self.register(*user_id, code).await?;
+        }
+        for (user_id, sanctions) in snapshot.sanctions.iter() {
+            for sanction in sanctions {
+                self.put_sanction(*user_id, sanction).await?;
+            }
+        }
+        for (id, role) in snapshot.roles.iter() {
+            self.grant_role(*id, *role).await?;
+        }
+        for (id, role) in snapshot.config_roles.iter() {
+            self.set_config_role(*id, *role, true).await?;
+        }
+        for entry in snapshot.audit.iter() {
+            self.log_audit(entry).await?;
+        }
+        // before the queue, burying takes its ID out of the outbox
+        for letter in snapshot.dead_letters.iter() {
+            self.bury_message(letter).await?;
+        }
+        // broadcasts get new IDs, queued messages are pointed at them
+        let mut broadcasts = HashMap::new();
+        for (id, data) in snapshot.broadcasts.iter() {
+            let chats: Vec<i64> = data.deliveries.keys().copied().collect();
+            let new_id = self.create_broadcast(&data.broadcast, &chats).await?;
+            for (chat, delivery) in data.deliveries.iter() {
+                if *delivery != Delivery::Pending {
+                    self.set_delivery(new_id, *chat, delivery).await?;
+                }
+            }
+            broadcasts.insert(*id, new_id);
+        }
+        for message in snapshot.outbox.values() {
+            let mut message = message.clone();
+            message.broadcast = message.broadcast.and_then(|x| broadcasts.get(&x).copied());
+            self.enqueue_message(&message).await?;
+        }
+        for chat in snapshot.unreachable.iter() {
+            self.set_unreachable(*chat, true).await?;
+        }
+        for schedule in snapshot.schedules.values() {
+            self.add_schedule(schedule).await?;
+        }
+        Ok(())
+    }
+}
+
+/** What a redemption of the invite would end with, for backends that check it in Rust **/
+fn check_invite(invite: &Invite, now: u64) -> InviteRedeem {
+    if invite.revoked {
+        InviteRedeem::Revoked
+    } else if invite.expires != 0 && invite.expires <= now {
+        InviteRedeem::Expired
+    } else if invite.max_uses != 0 && invite.uses >= invite.max_uses {
+        InviteRedeem::Exhausted
+    } else {
+        InviteRedeem::Done
+    }
+}
+
+/** Exports leave out a record that did not decode, `decode` has logged it, and count it **/
+fn or_skip<T>(record: anyhow::Result<T>, skipped: &mut usize) -> Option<T> {
+    record.map_err(|_| *skipped += 1).ok()
+}
+
+/** A record that does not decode is logged with its key and contents, never skipped silently **/
+fn decode<T: DeserializeOwned>(key: &str, value: &[u8]) -> anyhow::Result<T> {
+    serde_json::from_slice(value).map_err(|e| {
+        error!(
+            "Failed to decode {key}: {e}, stored value: {}",
+            String::from_utf8_lossy(value)
+        );
+        anyhow!("Failed to decode {key}: {e}")
+    })
+}
+
+pub async fn open(config: &StorageConfig) -> anyhow::Result<Box<dyn Storage>> {
+    Ok(match config {
+        StorageConfig::Redis(redis) => Box::new(RedisStorage::new(redis).await?),
+        StorageConfig::Memory => Box::new(MemoryStorage::default()),
+        StorageConfig::Sqlite { path } => Box::new(SqliteStorage::new(path).await?),
+    })
+}
@@ -0,0 +1,685 @@
+/**
+Copyright 2025 Ivan Agarkov
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+**/
+use crate::api::{
+    Attempt, AttemptKind, AuditEntry, Broadcast, Delivery, Event, Invite, Role, Sanction,
+    SanctionKind, Schedule, Task, Vas3kUser,
+};
+use crate::sender::{DeadLetter, Message, Priority};
+use crate::snapshot::{BroadcastSnapshot, EventSnapshot, Snapshot, SolveRecord};
+use crate::storage::{AttemptCounters, InviteRedeem, Storage, TaskWrite, check_invite};
+use async_trait::async_trait;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Mutex, MutexGuard};
+
+#[derive(Default)]
+struct EventState {
+    tasks: HashMap<String, Task>,
+    flags: HashMap<String, String>,
+    solves: HashMap<u64, HashSet<String>>,
+    scores: HashMap<u64, u64>,
+    task_solves: HashMap<String, HashMap<u64, u64>>,
+    participants: HashSet<u64>,
+    attempts: Vec<Attempt>,
+    attempts_count: u64,
+    wrong: HashMap<u64, u64>,
+}
+
+#[derive(Default)]
+struct State {
+    events: HashMap<String, Event>,
+    current: Option<String>,
+    data: HashMap<String, EventState>,
+    states: HashMap<u64, String>,
+    contacts: HashMap<u64, String>,
+    users: HashMap<u64, Vas3kUser>,
+    registrations: HashMap<u64, String>,
+    invites: HashMap<String, Invite>,
+    sanctions: HashMap<u64, HashMap<SanctionKind, Sanction>>,
+    audit: Vec<AuditEntry>,
+    roles: HashSet<(i64, Role)>,
+    config_roles: HashSet<(i64, Role)>,
+    outbox: BTreeMap<u64, Message>,
+    outbox_seq: u64,
+    dead_letters: Vec<DeadLetter>,
+    unreachable: HashSet<i64>,
+    broadcasts: BTreeMap<u64, (Broadcast, HashMap<i64, Delivery>)>,
+    broadcast_seq: u64,
+    schedules: BTreeMap<u64, Schedule>,
+    schedule_seq: u64,
+}
+
+impl State {
+    fn event(&mut self, event: &str) -> &mut EventState {
+        self.data.entry(event.to_owned()).or_default()
+    }
+}
+
+impl EventState {
+    fn write_task(&mut self, task: &Task, create: bool) -> TaskWrite {
+        if create && self.tasks.contains_key(&task.id) {
+            return TaskWrite::IdTaken;
+        }
+        for flag in task.flag.iter() {
+            if let Some(owner) = self.flags.get(flag)
+                && *owner != task.id
+            {
+                return TaskWrite::FlagTaken(flag.clone(), owner.clone());
+            }
+        }
+        if let Some(old) = self.tasks.remove(&task.id) {
+            for flag in old.flag.iter() {
+                self.flags.remove(flag);
+            }
+        }
+        for flag in task.flag.iter() {
+            self.flags.insert(flag.clone(), task.id.clone());
+        }
+        self.tasks.insert(task.id.clone(), task.clone());
+        TaskWrite::Done
+    }
+
+    fn scores(&self) -> Vec<(u64, u64)> {
+        let mut scores = self
+            .scores
+            .iter()
+            .map(|(k, v)| (*k, *v))
+            .collect::<Vec<(u64, u64)>>();
+        scores.sort_by(|x, y| y.1.cmp(&x.1).then(x.0.cmp(&y.0)));
+        scores
+    }
+
+    fn export(&self, event: Option<Event>) -> EventSnapshot {
+        let mut snapshot = EventSnapshot {
+            event,
+            tasks: self
+                .tasks
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            attempts: self.attempts.clone(),
+            ..Default::default()
+        };
+        for (user_id, tasks) in self.solves.iter() {
+            for task_id in tasks {
+                let time = self
+                    .task_solves
+                    .get(task_id)
+                    .and_then(|x| x.get(user_id).copied());
+                snapshot.solves.push(SolveRecord {
+                    user_id: *user_id,
+                    task_id: task_id.clone(),
+                    time: time.unwrap_or(0),
+                    ranked: time.is_some(),
+                });
+            }
+        }
+        snapshot
+    }
+}
+
+/** Keeps everything in process memory, for local runs without a database **/
+#[derive(Default)]
+pub struct MemoryStorage {
+    state: Mutex<State>,
+}
+
+impl MemoryStorage {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn get_event(&self, id: &str) -> anyhow::Result<Option<Event>> {
+        Ok(self.lock().events.get(id).cloned())
+    }
+
+    async fn event_ids(&self) -> anyhow::Result<Vec<String>> {
+        Ok(self.lock().events.keys().cloned().collect())
+    }
+
+    async fn create_event(&self, event: &Event) -> anyhow::Result<bool> {
+        let mut state = self.lock();
+        if state.events.contains_key(&event.id) {
+            return Ok(false);
+        }
+        state.events.insert(event.id.clone(), event.clone());
+        Ok(true)
+    }
+
+    async fn update_event(&self, event: &Event) -> anyhow::Result<()> {
+        self.lock().events.insert(event.id.clone(), event.clone());
+        Ok(())
+    }
+
+    async fn current_event(&self) -> anyhow::Result<Option<String>> {
+        Ok(self.lock().current.clone())
+    }
+
+    async fn set_current_event(&self, id: &str) -> anyhow::Result<()> {
+        self.lock().current = Some(id.to_owned());
+        Ok(())
+    }
+
+    async fn get_task(&self, event: &str, id: &str) -> anyhow::Result<Option<Task>> {
+        Ok(self.lock().event(event).tasks.get(id).cloned())
+    }
+
+    async fn task_ids(&self, event: &str) -> anyhow::Result<Vec<String>> {
+        Ok(self.lock().event(event).tasks.keys().cloned().collect())
+    }
+
+    async fn task_by_flag(&self, event: &str, flag: &str) -> anyhow::Result<Option<String>> {
+        Ok(self.lock().event(event).flags.get(flag).cloned())
+    }
+
+    async fn create_task(&self, event: &str, task: &Task) -> anyhow::Result<TaskWrite> {
+        Ok(self.lock().event(event).write_task(task, true))
+    }
+
+    async fn update_task(&self, event: &str, task: &Task) -> anyhow::Result<TaskWrite> {
+        Ok(self.lock().event(event).write_task(task, false))
+    }
+
+    async fn delete_task(&self, event: &str, id: &str) -> anyhow::Result<()> {
+        let mut state = self.lock();
+        let state = state.event(event);
+        if let Some(old) = state.tasks.remove(id) {
+            for flag in old.flag.iter() {
+                state.flags.remove(flag);
+            }
+        }
+        Ok(())
+    }
+
+    async fn is_solved(&self, event: &str, user_id: u64, task_id: &str) -> anyhow::Result<bool> {
+        Ok(self
+            .lock()
+            .event(event)
+            .solves
+            .get(&user_id)
+            .is_some_and(|x| x.contains(task_id)))
+    }
+
+    async fn add_solve(
+        &self,
+        event: &str,
+        user_id: u64,
+        task_id: &str,
+        time: u64,
+        ranked: bool,
+    ) -> anyhow::Result<bool> {
+        let mut state = self.lock();
+        let state = state.event(event);
+        if !state
+            .solves
+            .entry(user_id)
+            .or_default()
+            .insert(task_id.to_owned())
+        {
+            return Ok(false);
+        }
+        if ranked {
+            state
+                .task_solves
+                .entry(task_id.to_owned())
+                .or_default()
+                .insert(user_id, time);
+            state.participants.insert(user_id);
+            *state.scores.entry(user_id).or_default() += 1;
+        }
+        Ok(true)
+    }
+
+    async fn solves_count(&self, event: &str, user_id: u64) -> anyhow::Result<u64> {
+        Ok(self
+            .lock()
+            .event(event)
+            .solves
+            .get(&user_id)
+            .map_or(0, |x| x.len() as u64))
+    }
+
+    async fn scores(&self, event: &str) -> anyhow::Result<Vec<(u64, u64)>> {
+        Ok(self.lock().event(event).scores())
+    }
+
+    async fn task_solves(
+        &self,
+        event: &str,
+        task_id: &str,
+        skip: &HashSet<u64>,
+    ) -> anyhow::Result<(u64, Option<(u64, u64)>)> {
+        let mut state = self.lock();
+        let Some(solves) = state.event(event).task_solves.get(task_id) else {
+            return Ok((0, None));
+        };
+        let first = solves
+            .iter()
+            .filter(|x| *x.1 > 0 && !skip.contains(x.0))
+            .min_by_key(|x| *x.1)
+            .map(|(k, v)| (*k, *v));
+        let count = solves.keys().filter(|x| !skip.contains(x)).count();
+        Ok((count as u64, first))
+    }
+
+    async fn log_attempt(&self, event: &str, attempt: &Attempt) -> anyhow::Result<()> {
+        let mut state = self.lock();
+        let state = state.event(event);
+        state.attempts.push(attempt.clone());
+        if attempt.counted {
+            state.participants.insert(attempt.user_id);
+            state.attempts_count += 1;
+            if attempt.kind == AttemptKind::Wrong {
+                *state.wrong.entry(attempt.user_id).or_default() += 1;
+            }
+        }
+        Ok(())
+    }
+
+    async fn attempt_counters(&self, event: &str) -> anyhow::Result<AttemptCounters> {
+        let mut state = self.lock();
+        let state = state.event(event);
+        let mut wrong = state
+            .wrong
+            .iter()
+            .map(|(k, v)| (*k, *v))
+            .collect::<Vec<(u64, u64)>>();
+        wrong.sort_by(|x, y| y.1.cmp(&x.1).then(x.0.cmp(&y.0)));
+        Ok(AttemptCounters {
+            participants: state.participants.len() as u64,
+            attempts: state.attempts_count,
+            wrong,
+        })
+    }
+
+    async fn get_state(&self, user_id: u64) -> anyhow::Result<Option<String>> {
+        Ok(self.lock().states.get(&user_id).cloned())
+    }
+
+    async fn set_state(&self, user_id: u64, state: &str) -> anyhow::Result<()> {
+        self.lock().states.insert(user_id, state.to_owned());
+        Ok(())
+    }
+
+    async fn append_contact(&self, user_id: u64, text: &str) -> anyhow::Result<()> {
+        let mut state = self.lock();
+        let message = state.contacts.entry(user_id).or_default();
+        if !message.is_empty() {
+            message.push('\n');
+        }
+        message.push_str(text);
+        Ok(())
+    }
+
+    async fn take_contact(&self, user_id: u64) -> anyhow::Result<String> {
+        Ok(self.lock().contacts.remove(&user_id).unwrap_or_default())
+    }
+
+    async fn get_contact(&self, user_id: u64) -> anyhow::Result<String> {
+        Ok(self
+            .lock()
+            .contacts
+            .get(&user_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn get_user(&self, user_id: u64) -> anyhow::Result<Option<Vas3kUser>> {
+        Ok(self.lock().users.get(&user_id).cloned())
+    }
+
+    async fn put_user(&self, user_id: u64, user: &Vas3kUser) -> anyhow::Result<()> {
+        let mut user = user.clone();
+        user.telegram_id = user_id as i64;
+        self.lock().users.insert(user_id, user);
+        Ok(())
+    }
+
+    async fn user_ids(&self) -> anyhow::Result<Vec<u64>> {
+        Ok(self.lock().users.keys().copied().collect())
+    }
+
+    async fn register(&self, user_id: u64, code: &str) -> anyhow::Result<()> {
+        self.lock().registrations.insert(user_id, code.to_owned());
+        Ok(())
+    }
+
+    async fn registration(&self, user_id: u64) -> anyhow::Result<Option<String>> {
+        Ok(self.lock().registrations.get(&user_id).cloned())
+    }
+
+    async fn code_users(&self, code: &str) -> anyhow::Result<Vec<u64>> {
+        Ok(self
+            .lock()
+            .registrations
+            .iter()
+            .filter(|(_, v)| v.as_str() == code)
+            .map(|(k, _)| *k)
+            .collect())
+    }
+
+    async fn get_invite(&self, code: &str) -> anyhow::Result<Option<Invite>> {
+        Ok(self.lock().invites.get(code).cloned())
+    }
+
+    async fn invite_codes(&self) -> anyhow::Result<Vec<String>> {
+        Ok(self.lock().invites.keys().cloned().collect())
+    }
+
+    async fn create_invite(&self, invite: &Invite) -> anyhow::Result<bool> {
+        let mut state = self.lock();
+        if state.invites.contains_key(&invite.code) {
+            return Ok(false);
+        }
+        state.invites.insert(invite.code.clone(), invite.clone());
+        Ok(true)
+    }
+
+    async fn update_invite(&self, invite: &Invite) -> anyhow::Result<()> {
+        self.lock()
+            .invites
+            .insert(invite.code.clone(), invite.clone());
+        Ok(())
+    }
+
+    async fn redeem_invite(
+        &self,
+        code: &str,
+        user_id: u64,
+        now: u64,
+    ) -> anyhow::Result<InviteRedeem> {
+        let mut state = self.lock();
+        if state.registrations.contains_key(&user_id) {
+            return Ok(InviteRedeem::Done);
+        }
+        let Some(invite) = state.invites.get_mut(code) else {
+            return Ok(InviteRedeem::Unknown);
+        };
+        let ret = check_invite(invite, now);
+        if matches!(ret, InviteRedeem::Done) {
+            invite.uses += 1;
+            state.registrations.insert(user_id, code.to_owned());
+        }
+        Ok(ret)
+    }
+
+    async fn sanctions(&self, user_id: u64) -> anyhow::Result<Vec<Sanction>> {
+        Ok(self
+            .lock()
+            .sanctions
+            .get(&user_id)
+            .map(|x| x.values().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn all_sanctions(&self) -> anyhow::Result<Vec<(u64, Sanction)>> {
+        Ok(self
+            .lock()
+            .sanctions
+            .iter()
+            .flat_map(|(k, v)| v.values().map(|x| (*k, x.clone())))
+            .collect())
+    }
+
+    async fn put_sanction(&self, user_id: u64, sanction: &Sanction) -> anyhow::Result<()> {
+        self.lock()
+            .sanctions
+            .entry(user_id)
+            .or_default()
+            .insert(sanction.kind, sanction.clone());
+        Ok(())
+    }
+
+    async fn remove_sanction(&self, user_id: u64, kind: SanctionKind) -> anyhow::Result<bool> {
+        Ok(self
+            .lock()
+            .sanctions
+            .get_mut(&user_id)
+            .and_then(|x| x.remove(&kind))
+            .is_some())
+    }
+
+    async fn roles(&self) -> anyhow::Result<Vec<(i64, Role)>> {
+        Ok(self.lock().roles.iter().copied().collect())
+    }
+
+    async fn grant_role(&self, id: i64, role: Role) -> anyhow::Result<bool> {
+        Ok(self.lock().roles.insert((id, role)))
+    }
+
+    async fn revoke_role(&self, id: i64, role: Role) -> anyhow::Result<bool> {
+        let mut state = self.lock();
+        state.config_roles.remove(&(id, role));
+        Ok(state.roles.remove(&(id, role)))
+    }
+
+    async fn config_roles(&self) -> anyhow::Result<Vec<(i64, Role)>> {
+        Ok(self.lock().config_roles.iter().copied().collect())
+    }
+
+    async fn set_config_role(&self, id: i64, role: Role, config: bool) -> anyhow::Result<()> {
+        let mut state = self.lock();
+        if !config {
+            state.config_roles.remove(&(id, role));
+        } else if state.roles.contains(&(id, role)) {
+            state.config_roles.insert((id, role));
+        }
+        Ok(())
+    }
+
+    async fn log_audit(&self, entry: &AuditEntry) -> anyhow::Result<()> {
+        self.lock().audit.push(entry.clone());
+        Ok(())
+    }
+
+    async fn audit(&self, count: usize) -> anyhow::Result<Vec<AuditEntry>> {
+        let state = self.lock();
+        let skip = state.audit.len().saturating_sub(count);
+        Ok(state.audit[skip..].to_vec())
+    }
+
+    async fn enqueue_message(&self, message: &Message) -> anyhow::Result<u64> {
+        let mut state = self.lock();
+        state.outbox_seq += 1;
+        let id = state.outbox_seq;
+        state.outbox.insert(id, message.clone());
+        Ok(id)
+    }
+
+    async fn pending_messages(
+        &self,
+        lane: Priority,
+        count: usize,
+        skip: &HashSet<i64>,
+    ) -> anyhow::Result<Vec<(u64, Message)>> {
+        let state = self.lock();
+        let mut seen = skip.clone();
+        Ok(state
+            .outbox
+            .iter()
+            .filter(|(_, message)| message.priority == lane && seen.insert(message.chat))
+            .take(count)
+            .map(|(id, message)| (*id, message.clone()))
+            .collect())
+    }
+
+    async fn ack_message(&self, id: u64) -> anyhow::Result<()> {
+        self.lock().outbox.remove(&id);
+        Ok(())
+    }
+
+    async fn bury_message(&self, letter: &DeadLetter) -> anyhow::Result<()> {
+        let mut state = self.lock();
+        state.outbox.remove(&letter.id);
+        state.dead_letters.push(letter.clone());
+        Ok(())
+    }
+
+    async fn dead_letters(&self, count: usize) -> anyhow::Result<Vec<DeadLetter>> {
+        let state = self.lock();
+        let skip = state.dead_letters.len().saturating_sub(count);
+        Ok(state.dead_letters[skip..].to_vec())
+    }
+
+    async fn unreachable_chats(&self) -> anyhow::Result<Vec<i64>> {
+        Ok(self.lock().unreachable.iter().copied().collect())
+    }
+
+    async fn set_unreachable(&self, chat: i64, unreachable: bool) -> anyhow::Result<()> {
+        let mut state = self.lock();
+        if unreachable {
+            state.unreachable.insert(chat);
+        } else {
+            state.unreachable.remove(&chat);
+        }
+        Ok(())
+    }
+
+    async fn create_broadcast(&self, broadcast: &Broadcast, chats: &[i64]) -> anyhow::Result<u64> {
+        let mut state = self.lock();
+        state.broadcast_seq += 1;
+        let id = state.broadcast_seq;
+        let mut broadcast = broadcast.clone();
+        broadcast.id = id;
+        let deliveries = chats.iter().map(|x| (*x, Delivery::Pending)).collect();
+        state.broadcasts.insert(id, (broadcast, deliveries));
+        Ok(id)
+    }
+
+    async fn get_broadcast(&self, id: u64) -> anyhow::Result<Option<Broadcast>> {
+        Ok(self.lock().broadcasts.get(&id).map(|x| x.0.clone()))
+    }
+
+    async fn update_broadcast(&self, broadcast: &Broadcast) -> anyhow::Result<()> {
+        if let Some(entry) = self.lock().broadcasts.get_mut(&broadcast.id) {
+            entry.0 = broadcast.clone();
+        }
+        Ok(())
+    }
+
+    async fn broadcasts(&self, count: usize) -> anyhow::Result<Vec<Broadcast>> {
+        let state = self.lock();
+        let skip = state.broadcasts.len().saturating_sub(count);
+        Ok(state
+            .broadcasts
+            .values()
+            .skip(skip)
+            .map(|x| x.0.clone())
+            .collect())
+    }
+
+    async fn deliveries(&self, id: u64) -> anyhow::Result<HashMap<i64, Delivery>> {
+        Ok(self
+            .lock()
+            .broadcasts
+            .get(&id)
+            .map(|x| x.1.clone())
+            .unwrap_or_default())
+    }
+
+    async fn set_delivery(&self, id: u64, chat: i64, delivery: &Delivery) -> anyhow::Result<usize> {
+        let mut state = self.lock();
+        let Some((_, deliveries)) = state.broadcasts.get_mut(&id) else {
+            return Ok(0);
+        };
+        deliveries.insert(chat, delivery.clone());
+        Ok(deliveries
+            .values()
+            .filter(|x| **x == Delivery::Pending)
+            .count())
+    }
+
+    async fn add_schedule(&self, schedule: &Schedule) -> anyhow::Result<u64> {
+        let mut state = self.lock();
+        state.schedule_seq += 1;
+        let id = state.schedule_seq;
+        let mut schedule = schedule.clone();
+        schedule.id = id;
+        state.schedules.insert(id, schedule);
+        Ok(id)
+    }
+
+    async fn schedules(&self) -> anyhow::Result<Vec<Schedule>> {
+        Ok(self.lock().schedules.values().cloned().collect())
+    }
+
+    async fn remove_schedule(&self, id: u64) -> anyhow::Result<bool> {
+        Ok(self.lock().schedules.remove(&id).is_some())
+    }
+
+    async fn export(&self) -> anyhow::Result<Snapshot> {
+        let state = self.lock();
+        let mut snapshot = Snapshot::new();
+        snapshot.current_event = state.current.clone();
+        for (id, data) in state.data.iter() {
+            let event = state.events.get(id).cloned();
+            snapshot.events.insert(id.clone(), data.export(event));
+        }
+        for (id, event) in state.events.iter() {
+            snapshot
+                .events
+                .entry(id.clone())
+                .or_insert_with(|| EventState::default().export(Some(event.clone())));
+        }
+        snapshot.users = state.users.iter().map(|(k, v)| (*k, v.clone())).collect();
+        snapshot.states = state.states.iter().map(|(k, v)| (*k, v.clone())).collect();
+        snapshot.contacts = state
+            .contacts
+            .iter()
+            .map(|(k, v)| (*k, v.clone()))
+            .collect();
+        snapshot.registrations = state
+            .registrations
+            .iter()
+            .map(|(k, v)| (*k, v.clone()))
+            .collect();
+        snapshot.invites = state
+            .invites
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        snapshot.sanctions = state
+            .sanctions
+            .iter()
+            .filter(|(_, v)| !v.is_empty())
+            .map(|(k, v)| (*k, v.values().cloned().collect()))
+            .collect();
+        snapshot.audit = state.audit.clone();
+        snapshot.roles = state.roles.iter().copied().collect();
+        snapshot.roles.sort();
+        snapshot.config_roles = state.config_roles.iter().copied().collect();
+        snapshot.config_roles.sort();
+        snapshot.outbox = state.outbox.clone();
+        snapshot.dead_letters = state.dead_letters.clone();
+        snapshot.unreachable = state.unreachable.iter().copied().collect();
+        snapshot.unreachable.sort();
+        snapshot.broadcasts = state
+            .broadcasts
+            .iter()
+            .map(|(id, (broadcast, deliveries))| {
+                let data = BroadcastSnapshot {
+                    broadcast: broadcast.clone(),
+                    deliveries: deliveries.iter().map(|(k, v)| (*k, v.clone())).collect(),
+                };
+                (*id, data)
+            })
+            .collect();
+        snapshot.schedules = state.schedules.clone();
+        Ok(snapshot)
+    }
+}
@@ -0,0 +1,1642 @@
+/**
+Copyright 2025 Ivan Agarkov
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+**/
+use crate::api::{
+    Attempt, AttemptKind, AuditEntry, Broadcast, Delivery, Event, Invite, Role, Sanction,
+    SanctionKind, Schedule, Task, Vas3kUser,
+};
+use crate::sender::{DeadLetter, Message, Priority};
+use crate::snapshot::{BroadcastSnapshot, EventSnapshot, Snapshot, SolveRecord};
+use crate::storage::{
+    AttemptCounters, DEFAULT_EVENT, InviteRedeem, RedisConfig, Storage, TaskWrite, decode, or_skip,
+};
+use ::redis::aio::{ConnectionManager, ConnectionManagerConfig};
+use ::redis::{AsyncCommands, ConnectionAddr, FromRedisValue, IntoConnectionInfo, Script};
+use anyhow::bail;
+use async_trait::async_trait;
+use log::{error, info};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+const TASKS_KEY: &str = "tasks";
+const FLAGS_KEY: &str = "flags";
+const USERS_KEY: &str = "users";
+const SCORES_KEY: &str = "scores";
+const PARTICIPANTS_KEY: &str = "participants";
+const WRONG_KEY: &str = "wrong_attempts";
+const ATTEMPTS_KEY: &str = "attempts";
+const ATTEMPTS_COUNT_KEY: &str = "attempts_count";
+const EVENTS_KEY: &str = "events";
+const CURRENT_EVENT_KEY: &str = "current_event";
+const REGISTRATIONS_KEY: &str = "registrations";
+const INVITES_KEY: &str = "invites";
+/** Hash of `<user>:<kind>` to the sanction **/
+const SANCTIONS_KEY: &str = "sanctions";
+const AUDIT_KEY: &str = "audit";
+/** Set of `<id>:<role>` **/
+const ROLES_KEY: &str = "roles";
+/** Members of `roles` seeded from the config groups **/
+const CONFIG_ROLES_KEY: &str = "config_roles";
+/** Hash of message ID to the message, `outbox_ids` keeps the order and `outbox_seq` the last ID **/
+const OUTBOX_KEY: &str = "outbox";
+const OUTBOX_IDS_KEY: &str = "outbox_ids";
+const OUTBOX_SEQ_KEY: &str = "outbox_seq";
+const OUTBOX_BULK_IDS_KEY: &str = "outbox_bulk_ids";
+/** Queued IDs read at once while looking for the next message of each chat **/
+const OUTBOX_PAGE: usize = 512;
+/** Pages looked through per round, chats queued deeper wait until the front drains **/
+const OUTBOX_MAX_PAGES: usize = 8;
+const DEAD_LETTERS_KEY: &str = "dead_letters";
+const UNREACHABLE_KEY: &str = "unreachable";
+const BROADCASTS_KEY: &str = "broadcasts";
+const BROADCAST_SEQ_KEY: &str = "broadcast_seq";
+const SCHEDULES_KEY: &str = "schedules";
+const SCHEDULE_SEQ_KEY: &str = "schedule_seq";
+const INDEXED_KEY: &str = "indexed";
+/** Keys read per round trip of an export, so Redis keeps serving the bot meanwhile **/
+const EXPORT_BATCH: usize = 256;
+const SCHEMA_KEY: &str = "schema_version";
+
+/** Bumped together with a new step in `migrate` **/
+const SCHEMA_VERSION: u64 = 4;
+
+/** Keys of an event live under `event:<id>:`, users, states and contacts are shared **/
+fn ns(event: &str) -> String {
+    format!("event:{event}:")
+}
+
+/** KEYS: solves, task_solves, participants, scores; ARGV: task, user, time, ranked **/
+const SOLVE_SCRIPT: &str = r"
+if redis.call('SADD', KEYS[1], ARGV[1]) == 0 then
+    return 0
+end
+if ARGV[4] == '1' then
+    redis.call('ZADD', KEYS[2], ARGV[3], ARGV[2])
+    redis.call('SADD', KEYS[3], ARGV[2])
+    redis.call('ZINCRBY', KEYS[4], 1, ARGV[2])
+end
+return 1
+";
+
+/** Drops the flags of the stored task from the flag hash, shared by write and delete **/
+const UNINDEX_LUA: &str = r"
+local function unindex(old, flags, id)
+    local flag = cjson.decode(old)['flag']
+    if type(flag) == 'string' then
+        flag = {flag}
+    end
+    for _, f in ipairs(flag) do
+        if redis.call('HGET', flags, f) == id then
+            redis.call('HDEL', flags, f)
+        end
+    end
+end
+";
+
+/** KEYS: task, tasks, flags; ARGV: id, body, create, flags... **/
+const WRITE_TASK_SCRIPT: &str = r"
+local old = redis.call('GET', KEYS[1])
+if old and ARGV[3] == '1' then
+    return {'id'}
+end
+for i = 4, #ARGV do
+    local owner = redis.call('HGET', KEYS[3], ARGV[i])
+    if owner and owner ~= ARGV[1] then
+        return {'flag', ARGV[i], owner}
+    end
+end
+if old then
+    unindex(old, KEYS[3], ARGV[1])
+end
+redis.call('SET', KEYS[1], ARGV[2])
+redis.call('SADD', KEYS[2], ARGV[1])
+for i = 4, #ARGV do
+    redis.call('HSET', KEYS[3], ARGV[i], ARGV[1])
+end
+return {'ok'}
+";
+
+/** KEYS: task, tasks, flags; ARGV: id **/
+const DELETE_TASK_SCRIPT: &str = r"
+local old = redis.call('GET', KEYS[1])
+if old then
+    unindex(old, KEYS[3], ARGV[1])
+end
+redis.call('DEL', KEYS[1])
+redis.call('SREM', KEYS[2], ARGV[1])
+return 1
+";
+
+/** KEYS: contact; ARGV: text **/
+const APPEND_CONTACT_SCRIPT: &str = r"
+if redis.call('STRLEN', KEYS[1]) > 0 then
+    redis.call('APPEND', KEYS[1], '\n')
+end
+return redis.call('APPEND', KEYS[1], ARGV[1])
+";
+
+/** KEYS: contact **/
+const TAKE_CONTACT_SCRIPT: &str = r"
+local message = redis.call('GET', KEYS[1])
+redis.call('DEL', KEYS[1])
+return message
+";
+
+/** KEYS: invites, registrations, users of the code; ARGV: code, user, now **/
+const REDEEM_INVITE_SCRIPT: &str = r"
+if redis.call('HEXISTS', KEYS[2], ARGV[2]) == 1 then
+    return 'done'
+end
+local body = redis.call('HGET', KEYS[1], ARGV[1])
+if not body then
+    return 'unknown'
+end
+local invite = cjson.decode(body)
+if invite['revoked'] == true then
+    return 'revoked'
+end
+local expires = tonumber(invite['expires']) or 0
+if expires ~= 0 and expires <= tonumber(ARGV[3]) then
+    return 'expired'
+end
+local uses = tonumber(invite['uses']) or 0
+local max_uses = tonumber(invite['max_uses']) or 0
+if max_uses ~= 0 and uses >= max_uses then
+    return 'exhausted'
+end
+invite['uses'] = uses + 1
+redis.call('HSET', KEYS[1], ARGV[1], cjson.encode(invite))
+redis.call('HSET', KEYS[2], ARGV[2], ARGV[1])
+redis.call('SADD', KEYS[3], ARGV[2])
+return 'done'
+";
+
+/** KEYS: registrations, users of the new code, users of the old code; ARGV: user, code, old code or ''. Moves the user to the set of the new code, 0 when the old code changed meanwhile **/
+const REGISTER_SCRIPT: &str = r"
+local old = redis.call('HGET', KEYS[1], ARGV[1]) or ''
+if old ~= ARGV[3] then
+    return 0
+end
+if old ~= '' then
+    redis.call('SREM', KEYS[3], ARGV[1])
+end
+redis.call('HSET', KEYS[1], ARGV[1], ARGV[2])
+redis.call('SADD', KEYS[2], ARGV[1])
+return 1
+";
+
+trait FillId {
+    fn fill_id<S: AsRef<str>>(&mut self, key: S);
+}
+
+impl FillId for String {
+    fn fill_id<S: AsRef<str>>(&mut self, _key: S) {}
+}
+
+impl FillId for Vas3kUser {
+    fn fill_id<S: AsRef<str>>(&mut self, key: S) {
+        if let Some(rest) = key
+            .as_ref()
+            .split(':')
+            .next_back()
+            .and_then(|x| x.parse::<i64>().ok())
+        {
+            self.telegram_id = rest;
+        }
+    }
+}
+
+impl FillId for Task {
+    fn fill_id<S: AsRef<str>>(&mut self, key: S) {
+        if let Some(rest) = key.as_ref().split(':').next_back() {
+            self.id = rest.to_owned();
+        }
+    }
+}
+
+impl FillId for Solve {
+    fn fill_id<S: AsRef<str>>(&mut self, _: S) {}
+}
+
+/** Legacy per-user solve blob, only read while building indexes **/
+#[derive(Serialize, Deserialize, Debug)]
+struct Solve {
+    solves: Vec<String>,
+}
+
+/** Connection is re-established with exponential backoff on failures **/
+pub struct RedisStorage {
+    conn: ConnectionManager,
+}
+
+impl RedisStorage {
+    /** Unknown or malformed members are logged and skipped **/
+    fn decode_roles(members: Vec<String>) -> Vec<(i64, Role)> {
+        let mut ret = Vec::new();
+        for member in members {
+            let role = member
+                .split_once(':')
+                .and_then(|(id, role)| Some((id.parse().ok()?, Role::parse(role)?)));
+            match role {
+                Some(role) => ret.push(role),
+                None => error!("Unknown role {member} in {ROLES_KEY}"),
+            }
+        }
+        ret.sort();
+        ret
+    }
+
+    /** Flat `<user>:<kind>`, sanction pairs of the sanctions hash **/
+    /** Each field decoded on its own, so an export can leave a broken one out **/
+    fn decode_sanctions(pairs: Vec<String>) -> Vec<anyhow::Result<(u64, Sanction)>> {
+        let mut ret = Vec::new();
+        for pair in pairs.chunks_exact(2) {
+            let key = format!("{SANCTIONS_KEY}[{}]", pair[0]);
+            let user_id = pair[0]
+                .split_once(':')
+                .and_then(|(user_id, _)| user_id.parse::<u64>().ok());
+            ret.push(match user_id {
+                Some(user_id) => decode(&key, pair[1].as_bytes()).map(|x| (user_id, x)),
+                None => {
+                    error!("Malformed sanction field {key}");
+                    Err(anyhow::anyhow!("Malformed sanction field {key}"))
+                }
+            });
+        }
+        ret
+    }
+
+    pub async fn new(config: &RedisConfig) -> anyhow::Result<Self> {
+        let mut info = config.url.as_str().into_connection_info()?;
+        if config.password.is_some() {
+            info.redis.password = config.password.clone();
+        }
+        if let Some(db) = config.db {
+            info.redis.db = db;
+        }
+        if config.tls
+            && let ConnectionAddr::Tcp(host, port) = info.addr
+        {
+            info.addr = ConnectionAddr::TcpTls {
+                host,
+                port,
+                insecure: false,
+                tls_params: None,
+            };
+        }
+        let cli = ::redis::Client::open(info)?;
+        let manager = ConnectionManagerConfig::new()
+            .set_number_of_retries(config.retries)
+            .set_max_delay(config.max_delay);
+        let conn = ConnectionManager::new_with_config(cli, manager).await?;
+        let storage = Self { conn };
+        storage.migrate().await?;
+        Ok(storage)
+    }
+
+    async fn collect_from_cache<T>(&self, key: &str) -> anyhow::Result<Option<T>>
+    where
+        T: DeserializeOwned + FillId,
+    {
+        let mut conn = self.conn.clone();
+        let Some(value) = conn.get::<&str, Option<Vec<u8>>>(key).await? else {
+            return Ok(None);
+        };
+        let mut value = decode::<T>(key, &value)?;
+        value.fill_id(key);
+        Ok(Some(value))
+    }
+
+    /** Migrations leave broken records where they are, `decode` has already logged them **/
+    async fn collect_or_keep<T>(&self, key: &str) -> anyhow::Result<Option<T>>
+    where
+        T: DeserializeOwned + FillId,
+    {
+        match self.collect_from_cache(key).await {
+            Ok(value) => Ok(value),
+            Err(e) if e.is::<::redis::RedisError>() => Err(e),
+            Err(_) => Ok(None),
+        }
+    }
+
+    async fn put_into_cache<T>(&self, key: &str, value: &T) -> anyhow::Result<()>
+    where
+        T: Serialize,
+    {
+        let mut conn = self.conn.clone();
+        let serialized = serde_json::to_vec(value)?;
+        conn.set::<&str, &Vec<u8>, ()>(key, &serialized).await?;
+        Ok(())
+    }
+
+    /** Walks the keyspace with SCAN, used only by migrations **/
+    async fn scan_keys(&self, pattern: &str) -> anyhow::Result<Vec<String>> {
+        let mut conn = self.conn.clone();
+        let mut keys = Vec::new();
+        let mut iter = conn.scan_match::<&str, String>(pattern).await?;
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
+        }
+        Ok(keys)
+    }
+
+    async fn get_attempts(&self) -> anyhow::Result<Vec<Attempt>> {
+        let mut conn = self.conn.clone();
+        Ok(conn
+            .lrange::<&str, Vec<Vec<u8>>>(ATTEMPTS_KEY, 0, -1)
+            .await?
+            .iter()
+            .enumerate()
+            .filter_map(|(i, x)| decode(&format!("{ATTEMPTS_KEY}[{i}]"), x).ok())
+            .collect())
+    }
+
+    /** Upgrades the stored records step by step, the version is kept in `schema_version` **/
+    async fn migrate(&self) -> anyhow::Result<()> {
+        let mut conn = self.conn.clone();
+        let mut version = conn
+            .get::<&str, Option<u64>>(SCHEMA_KEY)
+            .await?
+            .unwrap_or(0);
+        // indexed before the version marker existed
+        if version == 0 && conn.exists::<&str, bool>(INDEXED_KEY).await? {
+            version = 1;
+        }
+        if version > SCHEMA_VERSION {
+            bail!("Storage schema {version} is newer than supported {SCHEMA_VERSION}");
+        }
+        while version < SCHEMA_VERSION {
+            info!("Migrating storage schema {} -> {}", version, version + 1);
+            match version {
+                0 => self.build_indexes().await?,
+                1 => self.rewrite_records().await?,
+                2 => self.move_into_event().await?,
+                _ => self.index_invite_users().await?,
+            }
+            version += 1;
+            conn.set::<&str, u64, ()>(SCHEMA_KEY, version).await?;
+        }
+        Ok(())
+    }
+
+    /** Version 1: index sets, the flag hash and the score board from the legacy per-key layout **/
+    async fn build_indexes(&self) -> anyhow::Result<()> {
+        let mut conn = self.conn.clone();
+        for key in self.scan_keys("task:*").await? {
+            if let Some(task) = self.collect_or_keep::<Task>(&key).await? {
+                self.index_task("", &task).await?;
+            }
+        }
+        for key in self.scan_keys("user:*").await? {
+            if let Some(user_id) = key
+                .strip_prefix("user:")
+                .and_then(|x| x.parse::<u64>().ok())
+            {
+                conn.sadd::<&str, u64, ()>(USERS_KEY, user_id).await?;
+            }
+        }
+        let attempts = self.get_attempts().await?;
+        for key in self.scan_keys("solve:*").await? {
+            let Some(user_id) = key
+                .strip_prefix("solve:")
+                .and_then(|x| x.parse::<u64>().ok())
+            else {
+                continue;
+            };
+            let Some(solve) = self.collect_or_keep::<Solve>(&key).await? else {
+                continue;
+            };
+            for task_key in solve.solves {
+                let task_id = task_key.strip_prefix("task:").unwrap_or(&task_key);
+                // legacy solves carry no time, take it from the attempt log where possible
+                let time = attempts
+                    .iter()
+                    .find(|x| {
+                        x.user_id == user_id
+                            && x.kind == AttemptKind::Solved
+                            && x.task.as_deref() == Some(task_id)
+                    })
+                    .map_or(0, |x| x.time);
+                // the staff filter is not known here, legacy boards did not have one either
+                self.solve("", user_id, task_id, time, true).await?;
+            }
+        }
+        for attempt in attempts {
+            let mut pipe = ::redis::pipe();
+            Self::count_attempt(&mut pipe, "", &attempt);
+            pipe.query_async::<()>(&mut conn).await?;
+        }
+        conn.set::<&str, u64, ()>(INDEXED_KEY, 1).await?;
+        Ok(())
+    }
+
+    /** Version 2: re-encodes tasks, users and attempts with the current fields, contacts become raw strings **/
+    async fn rewrite_records(&self) -> anyhow::Result<()> {
+        let mut conn = self.conn.clone();
+        let mut broken = 0;
+        for id in conn.smembers::<&str, Vec<String>>(TASKS_KEY).await? {
+            let key = format!("task:{id}");
+            match self.collect_or_keep::<Task>(&key).await? {
+                Some(task) => self.put_into_cache(&key, &task).await?,
+                None => broken += 1,
+            }
+        }
+        for id in conn.smembers::<&str, Vec<u64>>(USERS_KEY).await? {
+            let key = format!("user:{id}");
+            match self.collect_or_keep::<Vas3kUser>(&key).await? {
+                Some(user) => self.put_into_cache(&key, &user).await?,
+                None => broken += 1,
+            }
+        }
+        let attempts = conn
+            .lrange::<&str, Vec<Vec<u8>>>(ATTEMPTS_KEY, 0, -1)
+            .await?;
+        for (i, raw) in attempts.iter().enumerate() {
+            match decode::<Attempt>(&format!("{ATTEMPTS_KEY}[{i}]"), raw) {
+                Ok(attempt) => {
+                    conn.lset::<&str, Vec<u8>, ()>(
+                        ATTEMPTS_KEY,
+                        i as isize,
+                        serde_json::to_vec(&attempt)?,
+                    )
+                    .await?
+                }
+                Err(_) => broken += 1,
+            }
+        }
+        for key in self.scan_keys("contact:*").await? {
+            let Some(raw) = conn.get::<&str, Option<String>>(&key).await? else {
+                continue;
+            };
+            if let Ok(message) = serde_json::from_str::<String>(&raw) {
+                conn.set::<&str, String, ()>(&key, message).await?;
+            }
+        }
+        if broken > 0 {
+            error!("{broken} records could not be decoded and were left as is, see above");
+        }
+        Ok(())
+    }
+
+    /** Version 3: everything but users, states and contacts moves under the default event **/
+    async fn move_into_event(&self) -> anyhow::Result<()> {
+        let mut conn = self.conn.clone();
+        let prefix = ns(DEFAULT_EVENT);
+        let mut keys = Vec::new();
+        for key in [
+            TASKS_KEY,
+            FLAGS_KEY,
+            SCORES_KEY,
+            PARTICIPANTS_KEY,
+            WRONG_KEY,
+            ATTEMPTS_KEY,
+            ATTEMPTS_COUNT_KEY,
+        ] {
+            if conn.exists::<&str, bool>(key).await? {
+                keys.push(String::from(key));
+            }
+        }
+        for pattern in ["task:*", "solves:*", "task_solves:*"] {
+            keys.append(&mut self.scan_keys(pattern).await?);
+        }
+        for key in keys {
+            conn.rename::<&str, String, ()>(&key, format!("{prefix}{key}"))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /** Version 4: a set of users per invite code, so listing them does not read every registration **/
+    async fn index_invite_users(&self) -> anyhow::Result<()> {
+        let mut conn = self.conn.clone();
+        let registrations = conn
+            .hgetall::<&str, Vec<(u64, String)>>(REGISTRATIONS_KEY)
+            .await?;
+        for (user_id, code) in registrations {
+            conn.sadd::<String, u64, ()>(Self::invite_users_key(&code), user_id)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /** Users who joined with the code, kept next to `registrations` **/
+    fn invite_users_key(code: &str) -> String {
+        format!("invite_users:{code}")
+    }
+
+    async fn index_task(&self, prefix: &str, task: &Task) -> anyhow::Result<()> {
+        let mut conn = self.conn.clone();
+        conn.sadd::<String, &str, ()>(format!("{prefix}{TASKS_KEY}"), &task.id)
+            .await?;
+        for flag in task.flag.iter() {
+            conn.hset::<String, &str, &str, ()>(format!("{prefix}{FLAGS_KEY}"), flag, &task.id)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn write_task(
+        &self,
+        event: &str,
+        task: &Task,
+        create: bool,
+    ) -> anyhow::Result<TaskWrite> {
+        let prefix = ns(event);
+        let mut conn = self.conn.clone();
+        let script = Script::new(&format!("{UNINDEX_LUA}{WRITE_TASK_SCRIPT}"));
+        let mut invocation = script.key(format!("{prefix}task:{}", task.id));
+        invocation
+            .key(format!("{prefix}{TASKS_KEY}"))
+            .key(format!("{prefix}{FLAGS_KEY}"))
+            .arg(&task.id)
+            .arg(serde_json::to_vec(task)?)
+            .arg(if create { "1" } else { "0" });
+        for flag in task.flag.iter() {
+            invocation.arg(flag);
+        }
+        let reply = invocation.invoke_async::<Vec<String>>(&mut conn).await?;
+        Ok(match reply.as_slice() {
+            [status] if status == "id" => TaskWrite::IdTaken,
+            [status, flag, owner] if status == "flag" => {
+                TaskWrite::FlagTaken(flag.clone(), owner.clone())
+            }
+            _ => TaskWrite::Done,
+        })
+    }
+
+    async fn solve(
+        &self,
+        prefix: &str,
+        user_id: u64,
+        task_id: &str,
+        time: u64,
+        ranked: bool,
+    ) -> anyhow::Result<bool> {
+        let mut conn = self.conn.clone();
+        let added = Script::new(SOLVE_SCRIPT)
+            .key(format!("{prefix}solves:{user_id}"))
+            .key(format!("{prefix}task_solves:{task_id}"))
+            .key(format!("{prefix}{PARTICIPANTS_KEY}"))
+            .key(format!("{prefix}{SCORES_KEY}"))
+            .arg(task_id)
+            .arg(user_id)
+            .arg(time)
+            .arg(if ranked { "1" } else { "0" })
+            .invoke_async::<u64>(&mut conn)
+            .await?;
+        Ok(added == 1)
+    }
+
+    /** (deliveries hash, pending set) of a broadcast **/
+    fn broadcast_keys(id: u64) -> (String, String) {
+        (
+            format!("broadcast:{id}:deliveries"),
+            format!("broadcast:{id}:pending"),
+        )
+    }
+
+    /** One reply of `command` per key, read in pipelines of `EXPORT_BATCH` **/
+    async fn read_keys<T: FromRedisValue>(
+        &self,
+        command: &str,
+        keys: &[String],
+        args: &[&str],
+    ) -> anyhow::Result<Vec<T>> {
+        let mut conn = self.conn.clone();
+        let mut ret = Vec::with_capacity(keys.len());
+        for chunk in keys.chunks(EXPORT_BATCH) {
+            let mut pipe = ::redis::pipe();
+            for key in chunk {
+                pipe.cmd(command).arg(key).arg(args);
+            }
+            ret.extend(pipe.query_async::<Vec<T>>(&mut conn).await?);
+        }
+        Ok(ret)
+    }
+
+    fn count_attempt(pipe: &mut ::redis::Pipeline, prefix: &str, attempt: &Attempt) {
+        pipe.sadd(format!("{prefix}{PARTICIPANTS_KEY}"), attempt.user_id)
+            .ignore()
+            .incr(format!("{prefix}{ATTEMPTS_COUNT_KEY}"), 1)
+            .ignore();
+        if attempt.kind == AttemptKind::Wrong {
+            pipe.zincr(format!("{prefix}{WRONG_KEY}"), attempt.user_id, 1)
+                .ignore();
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for RedisStorage {
+    async fn get_event(&self, id: &str) -> anyhow::Result<Option<Event>> {
+        let mut conn = self.conn.clone();
+        let Some(value) = conn
+            .hget::<&str, &str, Option<Vec<u8>>>(EVENTS_KEY, id)
+            .await?
+        else {
+            return Ok(None);
+        };
+        let mut event = decode::<Event>(&format!("{EVENTS_KEY}[{id}]"), &value)?;
+        event.id = String::from(id);
+        Ok(Some(event))
+    }
+
+    async fn event_ids(&self) -> anyhow::Result<Vec<String>> {
+        let mut conn = self.conn.clone();
+        Ok(conn.hkeys::<&str, Vec<String>>(EVENTS_KEY).await?)
+    }
+
+    async fn create_event(&self, event: &Event) -> anyhow::Result<bool> {
+        let mut conn = self.conn.clone();
+        Ok(conn
+            .hset_nx::<&str, &str, Vec<u8>, bool>(EVENTS_KEY, &event.id, serde_json::to_vec(event)?)
+            .await?)
+    }
+
+    async fn update_event(&self, event: &Event) -> anyhow::Result<()> {
+        let mut conn = self.conn.clone();
+        conn.hset::<&str, &str, Vec<u8>, ()>(EVENTS_KEY, &event.id, serde_json::to_vec(event)?)
+            .await?;
+        Ok(())
+    }
+
+    async fn current_event(&self) -> anyhow::Result<Option<String>> {
+        let mut conn = self.conn.clone();
+        Ok(conn.get::<&str, Option<String>>(CURRENT_EVENT_KEY).await?)
+    }
+
+    async fn set_current_event(&self, id: &str) -> anyhow::Result<()> {
+        let mut conn = self.conn.clone();
+        conn.set::<&str, &str, ()>(CURRENT_EVENT_KEY, id).await?;
+        Ok(())
+    }
+
+    async fn get_task(&self, event: &str, id: &str) -> anyhow::Result<Option<Task>> {
+        self.collect_from_cache(&format!("{}task:{}", ns(event), id))
+            .await
+    }
+
+    async fn task_ids(&self, event: &str) -> anyhow::Result<Vec<String>> {
+        let mut conn = self.conn.clone();
+        Ok(conn
+            .smembers::<String, Vec<String>>(format!("{}{TASKS_KEY}", ns(event)))
+            .await?)
+    }
+
+    async fn task_by_flag(&self, event: &str, flag: &str) -> anyhow::Result<Option<String>> {
+        let mut conn = self.conn.clone();
+        Ok(conn
+            .hget::<String, &str, Option<String>>(format!("{}{FLAGS_KEY}", ns(event)), flag)
+            .await?)
+    }
+
+    async fn create_task(&self, event: &str, task: &Task) -> anyhow::Result<TaskWrite> {
+        self.write_task(event, task, true).await
+    }
+
+    async fn update_task(&self, event: &str, task: &Task) -> anyhow::Result<TaskWrite> {
+        self.write_task(event, task, false).await
+    }
+
+    async fn delete_task(&self, event: &str, id: &str) -> anyhow::Result<()> {
+        let prefix = ns(event);
+        let mut conn = self.conn.clone();
+        Script::new(&format!("{UNINDEX_LUA}{DELETE_TASK_SCRIPT}"))
+            .key(format!("{prefix}task:{id}"))
+            .key(format!("{prefix}{TASKS_KEY}"))
+            .key(format!("{prefix}{FLAGS_KEY}"))
+            .arg(id)
+            .invoke_async::<()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn is_solved(&self, event: &str, user_id: u64, task_id: &str) -> anyhow::Result<bool> {
+        let mut conn = self.conn.clone();
+        Ok(conn
+            .sismember::<String, &str, bool>(format!("{}solves:{}", ns(event), user_id), task_id)
+            .await?)
+    }
+
+    async fn add_solve(
+        &self,
+        event: &str,
+        user_id: u64,
+        task_id: &str,
+        time: u64,
+        ranked: bool,
+    ) -> anyhow::Result<bool> {
+        self.solve(&ns(event), user_id, task_id, time, ranked).await
+    }
+
+    async fn solves_count(&self, event: &str, user_id: u64) -> anyhow::Result<u64> {
+        let mut conn = self.conn.clone();
+        Ok(conn
+            .scard::<String, u64>(format!("{}solves:{}", ns(event), user_id))
+            .await?)
+    }
+
+    async fn scores(&self, event: &str) -> anyhow::Result<Vec<(u64, u64)>> {
+        let mut conn = self.conn.clone();
+        Ok(conn
+            .zrevrange_withscores::<String, Vec<(u64, u64)>>(
+                format!("{}{SCORES_KEY}", ns(event)),
+                0,
+                -1,
+            )
+            .await?)
+    }
+
+    async fn task_solves(
+        &self,
+        event: &str,
+        task_id: &str,
+        skip: &HashSet<u64>,
+    ) -> anyhow::Result<(u64, Option<(u64, u64)>)> {
+        let key = format!("{}task_solves:{}", ns(event), task_id);
+        let mut conn = self.conn.clone();
+        let mut pipe = ::redis::pipe();
+        pipe.zcard(&key);
+        for user in skip {
+            pipe.zscore(&key, user);
+        }
+        // the count first, then a score for every skipped user who solved the task
+        let replies = pipe.query_async::<Vec<Option<u64>>>(&mut conn).await?;
+        let skipped = replies.iter().skip(1).flatten().count() as u64;
+        let solves = replies[0].unwrap_or(0).saturating_sub(skipped);
+        // enough to get past every skipped user
+        let first = conn
+            .zrangebyscore_limit_withscores::<&str, u64, &str, Vec<(u64, u64)>>(
+                &key,
+                1,
+                "+inf",
+                0,
+                skip.len() as isize + 1,
+            )
+            .await?;
+        Ok((solves, first.into_iter().find(|x| !skip.contains(&x.0))))
+    }
+
+    async fn log_attempt(&self, event: &str, attempt: &Attempt) -> anyhow::Result<()> {
+        let prefix = ns(event);
+        let mut conn = self.conn.clone();
+        let mut pipe = ::redis::pipe();
+        pipe.atomic()
+            .rpush(
+                format!("{prefix}{ATTEMPTS_KEY}"),
+                serde_json::to_vec(attempt)?,
+            )
+            .ignore();
+        if attempt.counted {
+            Self::count_attempt(&mut pipe, &prefix, attempt);
+        }
+        pipe.query_async::<()>(&mut conn).await?;
+        Ok(())
+    }
+
+    async fn attempt_counters(&self, event: &str) -> anyhow::Result<AttemptCounters> {
+        let prefix = ns(event);
+        let mut conn = self.conn.clone();
+        Ok(AttemptCounters {
+            participants: conn
+                .scard::<String, u64>(format!("{prefix}{PARTICIPANTS_KEY}"))
+                .await?,
+            attempts: conn
+                .get::<String, Option<u64>>(format!("{prefix}{ATTEMPTS_COUNT_KEY}"))
+                .await?
+                .unwrap_or(0),
+            wrong: conn
+                .zrevrange_withscores::<String, Vec<(u64, u64)>>(
+                    format!("{prefix}{WRONG_KEY}"),
+                    0,
+                    -1,
+                )
+                .await?,
+        })
+    }
+
+    async fn get_state(&self, user_id: u64) -> anyhow::Result<Option<String>> {
+        self.collect_from_cache(&format!("user_state:{}", user_id))
+            .await
+    }
+
+    async fn set_state(&self, user_id: u64, state: &str) -> anyhow::Result<()> {
+        self.put_into_cache(&format!("user_state:{}", user_id), &state)
+            .await
+    }
+
+    async fn append_contact(&self, user_id: u64, text: &str) -> anyhow::Result<()> {
+        let mut conn = self.conn.clone();
+        Script::new(APPEND_CONTACT_SCRIPT)
+            .key(format!("contact:{}", user_id))
+            .arg(text)
+            .invoke_async::<()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn take_contact(&self, user_id: u64) -> anyhow::Result<String> {
+        let mut conn = self.conn.clone();
+        let message = Script::new(TAKE_CONTACT_SCRIPT)
+            .key(format!("contact:{}", user_id))
+            .invoke_async::<Option<String>>(&mut conn)
+            .await?
+            .unwrap_or_default();
+        Ok(message)
+    }
+
+    async fn get_contact(&self, user_id: u64) -> anyhow::Result<String> {
+        let mut conn = self.conn.clone();
+        Ok(conn
+            .get::<String, Option<String>>(format!("contact:{}", user_id))
+            .await?
+            .unwrap_or_default())
+    }
+
+    async fn get_user(&self, user_id: u64) -> anyhow::Result<Option<Vas3kUser>> {
+        self.collect_from_cache(&format!("user:{}", user_id)).await
+    }
+
+    async fn put_user(&self, user_id: u64, user: &Vas3kUser) -> anyhow::Result<()> {
+        let mut conn = self.conn.clone();
+        ::redis::pipe()
+            .atomic()
+            .set(format!("user:{}", user_id), serde_json::to_vec(user)?)
+            .ignore()
+            .sadd(USERS_KEY, user_id)
+            .ignore()
+            .query_async::<()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn user_ids(&self) -> anyhow::Result<Vec<u64>> {
+        let mut conn = self.conn.clone();
+        Ok(conn.smembers::<&str, Vec<u64>>(USERS_KEY).await?)
+    }
+
+    async fn register(&self, user_id: u64, code: &str) -> anyhow::Result<()> {
+        let mut conn = self.conn.clone();
+        let script = Script::new(REGISTER_SCRIPT);
+        // the old code names a key, so it is read first and checked again in the script
+        loop {
+            let old = conn
+                .hget::<&str, u64, Option<String>>(REGISTRATIONS_KEY, user_id)
+                .await?
+                .unwrap_or_default();
+            let moved = script
+                .key(REGISTRATIONS_KEY)
+                .key(Self::invite_users_key(code))
+                .key(Self::invite_users_key(&old))
+                .arg(user_id)
+                .arg(code)
+                .arg(&old)
+                .invoke_async::<bool>(&mut conn)
+                .await?;
+            if moved {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn registration(&self, user_id: u64) -> anyhow::Result<Option<String>> {
+        let mut conn = self.conn.clone();
+        Ok(conn
+            .hget::<&str, u64, Option<String>>(REGISTRATIONS_KEY, user_id)
+            .await?)
+    }
+
+    async fn code_users(&self, code: &str) -> anyhow::Result<Vec<u64>> {
+        let mut conn = self.conn.clone();
+        Ok(conn
+            .smembers::<String, Vec<u64>>(Self::invite_users_key(code))
+            .await?)
+    }
+
+    async fn get_invite(&self, code: &str) -> anyhow::Result<Option<Invite>> {
+        let mut conn = self.conn.clone();
+        let Some(value) = conn
+            .hget::<&str, &str, Option<Vec<u8>>>(INVITES_KEY, code)
+            .await?
+        else {
+            return Ok(None);
+        };
+        let mut invite = decode::<Invite>(&format!("{INVITES_KEY}[{code}]"), &value)?;
+        invite.code = String::from(code);
+        Ok(Some(invite))
+    }
+
+    async fn invite_codes(&self) -> anyhow::Result<Vec<String>> {
+        let mut conn = self.conn.clone();
+        Ok(conn.hkeys::<&str, Vec<String>>(INVITES_KEY).await?)
+    }
+
+    async fn create_invite(&self, invite: &Invite) -> anyhow::Result<bool> {
+        let mut conn = self.conn.clone();
+        Ok(conn
+            .hset_nx::<&str, &str, Vec<u8>, bool>(
+                INVITES_KEY,
+                &invite.code,
+                serde_json::to_vec(invite)?,
+            )
+            .await?)
+    }
+
+    async fn update_invite(&self, invite: &Invite) -> anyhow::Result<()> {
+        let mut conn = self.conn.clone();
+        conn.hset::<&str, &str, Vec<u8>, ()>(
+            INVITES_KEY,
+            &invite.code,
+            serde_json::to_vec(invite)?,
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn redeem_invite(
+        &self,
+        code: &str,
+        user_id: u64,
+        now: u64,
+    ) -> anyhow::Result<InviteRedeem> {
+        let mut conn = self.conn.clone();
+        let status = Script::new(REDEEM_INVITE_SCRIPT)
+            .key(INVITES_KEY)
+            .key(REGISTRATIONS_KEY)
+            .key(Self::invite_users_key(code))
+            .arg(code)
+            .arg(user_id)
+            .arg(now)
+            .invoke_async::<String>(&mut conn)
+            .await?;
+        Ok(match status.as_str() {
+            "done" => InviteRedeem::Done,
+            "unknown" => InviteRedeem::Unknown,
+            "revoked" => InviteRedeem::Revoked,
+            "expired" => InviteRedeem::Expired,
+            "exhausted" => InviteRedeem::Exhausted,
+            status => bail!("Unexpected invite status {status}"),
+        })
+    }
+
+    async fn sanctions(&self, user_id: u64) -> anyhow::Result<Vec<Sanction>> {
+        let mut conn = self.conn.clone();
+        let fields = [
+            SanctionKind::Ban,
+            SanctionKind::Mute,
+            SanctionKind::Disqualify,
+        ]
+        .map(|x| format!("{user_id}:{}", x.as_str()));
+        let values = conn
+            .hget::<&str, &[String], Vec<Option<Vec<u8>>>>(SANCTIONS_KEY, &fields)
+            .await?;
+        let mut ret = Vec::new();
+        for (field, value) in fields.iter().zip(values) {
+            if let Some(value) = value {
+                ret.push(decode(&format!("{SANCTIONS_KEY}[{field}]"), &value)?);
+            }
+        }
+        Ok(ret)
+    }
+
+    async fn all_sanctions(&self) -> anyhow::Result<Vec<(u64, Sanction)>> {
+        let mut conn = self.conn.clone();
+        let pairs = conn.hgetall::<&str, Vec<String>>(SANCTIONS_KEY).await?;
+        Self::decode_sanctions(pairs).into_iter().collect()
+    }
+
+    async fn put_sanction(&self, user_id: u64, sanction: &Sanction) -> anyhow::Result<()> {
+        let mut conn = self.conn.clone();
+        conn.hset::<&str, String, Vec<u8>, ()>(
+            SANCTIONS_KEY,
+            format!("{user_id}:{}", sanction.kind.as_str()),
+            serde_json::to_vec(sanction)?,
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn remove_sanction(&self, user_id: u64, kind: SanctionKind) -> anyhow::Result<bool> {
+        let mut conn = self.conn.clone();
+        let removed = conn
+            .hdel::<&str, String, u64>(SANCTIONS_KEY, format!("{user_id}:{}", kind.as_str()))
+            .await?;
+        Ok(removed > 0)
+    }
+
+    async fn roles(&self) -> anyhow::Result<Vec<(i64, Role)>> {
+        let mut conn = self.conn.clone();
+        let members = conn.smembers::<&str, Vec<String>>(ROLES_KEY).await?;
+        Ok(Self::decode_roles(members))
+    }
+
+    async fn grant_role(&self, id: i64, role: Role) -> anyhow::Result<bool> {
+        let mut conn = self.conn.clone();
+        let added = conn
+            .sadd::<&str, String, u64>(ROLES_KEY, format!("{id}:{}", role.as_str()))
+            .await?;
+        Ok(added > 0)
+    }
+
+    async fn revoke_role(&self, id: i64, role: Role) -> anyhow::Result<bool> {
+        let mut conn = self.conn.clone();
+        let member = format!("{id}:{}", role.as_str());
+        let (removed, _) = ::redis::pipe()
+            .atomic()
+            .srem(ROLES_KEY, &member)
+            .srem(CONFIG_ROLES_KEY, &member)
+            .query_async::<(u64, u64)>(&mut conn)
+            .await?;
+        Ok(removed > 0)
+    }
+
+    async fn config_roles(&self) -> anyhow::Result<Vec<(i64, Role)>> {
+        let mut conn = self.conn.clone();
+        let members = conn.smembers::<&str, Vec<String>>(CONFIG_ROLES_KEY).await?;
+        Ok(Self::decode_roles(members))
+    }
+
+    async fn set_config_role(&self, id: i64, role: Role, config: bool) -> anyhow::Result<()> {
+        let mut conn = self.conn.clone();
+        let member = format!("{id}:{}", role.as_str());
+        if !config {
+            conn.srem::<&str, String, u64>(CONFIG_ROLES_KEY, member)
+                .await?;
+        } else if conn
+            .sismember::<&str, &str, bool>(ROLES_KEY, &member)
+            .await?
+        {
+            conn.sadd::<&str, String, u64>(CONFIG_ROLES_KEY, member)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn enqueue_message(&self, message: &Message) -> anyhow::Result<u64> {
+        let mut conn = self.conn.clone();
+        let data = serde_json::to_vec(message)?;
+        let id = conn.incr::<&str, u64, u64>(OUTBOX_SEQ_KEY, 1).await?;
+        let ids_key = match message.priority {
+            Priority::Interactive => OUTBOX_IDS_KEY,
+            Priority::Bulk => OUTBOX_BULK_IDS_KEY,
+        };
+        ::redis::pipe()
+            .atomic()
+            .hset(OUTBOX_KEY, id, data)
+            .zadd(ids_key, id, id)
+            .query_async::<()>(&mut conn)
+            .await?;
+        Ok(id)
+    }
+
+    async fn pending_messages(
+        &self,
+        lane: Priority,
+        count: usize,
+        skip: &HashSet<i64>,
+    ) -> anyhow::Result<Vec<(u64, Message)>> {
+        let mut conn = self.conn.clone();
+        let mut seen = skip.clone();
+        let mut ret = Vec::new();
+        let ids_key = match lane {
+            Priority::Interactive => OUTBOX_IDS_KEY,
+            Priority::Bulk => OUTBOX_BULK_IDS_KEY,
+        };
+        // messages of skipped or already taken chats are read and passed over
+        let mut offset = 0;
+        for _ in 0..OUTBOX_MAX_PAGES {
+            if ret.len() >= count {
+                break;
+            }
+            let ids = conn
+                .zrange::<&str, Vec<u64>>(ids_key, offset, offset + OUTBOX_PAGE as isize - 1)
+                .await?;
+            if ids.is_empty() {
+                break;
+            }
+            offset += ids.len() as isize;
+            let data = ::redis::cmd("HMGET")
+                .arg(OUTBOX_KEY)
+                .arg(&ids)
+                .query_async::<Vec<Option<Vec<u8>>>>(&mut conn)
+                .await?;
+            for (id, data) in ids.into_iter().zip(data) {
+                let message = match data {
+                    Some(data) => decode::<Message>(&format!("{OUTBOX_KEY}[{id}]"), &data).ok(),
+                    // an ack that was cut in half
+                    None => None,
+                };
+                match message {
+                    Some(message) => {
+                        if ret.len() < count && seen.insert(message.chat) {
+                            ret.push((id, message));
+                        }
+                    }
+                    None => {
+                        // logged by decode with its contents, kept it would stop the queue
+                        ::redis::pipe()
+                            .atomic()
+                            .hdel(OUTBOX_KEY, id)
+                            .zrem(OUTBOX_IDS_KEY, id)
+                            .zrem(OUTBOX_BULK_IDS_KEY, id)
+                            .query_async::<()>(&mut conn)
+                            .await?;
+                        offset -= 1;
+                    }
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    async fn ack_message(&self, id: u64) -> anyhow::Result<()> {
+        let mut conn = self.conn.clone();
+        ::redis::pipe()
+            .atomic()
+            .hdel(OUTBOX_KEY, id)
+            .zrem(OUTBOX_IDS_KEY, id)
+            .zrem(OUTBOX_BULK_IDS_KEY, id)
+            .query_async::<()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn bury_message(&self, letter: &DeadLetter) -> anyhow::Result<()> {
+        let mut conn = self.conn.clone();
+        ::redis::pipe()
+            .atomic()
+            .hdel(OUTBOX_KEY, letter.id)
+            .zrem(OUTBOX_IDS_KEY, letter.id)
+            .zrem(OUTBOX_BULK_IDS_KEY, letter.id)
+            .rpush(DEAD_LETTERS_KEY, serde_json::to_vec(letter)?)
+            .query_async::<()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn dead_letters(&self, count: usize) -> anyhow::Result<Vec<DeadLetter>> {
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        let mut conn = self.conn.clone();
+        let entries = conn
+            .lrange::<&str, Vec<Vec<u8>>>(DEAD_LETTERS_KEY, -(count as isize), -1)
+            .await?;
+        let mut ret = Vec::new();
+        for (i, entry) in entries.iter().enumerate() {
+            ret.push(decode(
+                &format!("{DEAD_LETTERS_KEY}[-{}]", entries.len() - i),
+                entry,
+            )?);
+        }
+        Ok(ret)
+    }
+
+    async fn unreachable_chats(&self) -> anyhow::Result<Vec<i64>> {
+        let mut conn = self.conn.clone();
+        Ok(conn.smembers::<&str, Vec<i64>>(UNREACHABLE_KEY).await?)
+    }
+
+    async fn set_unreachable(&self, chat: i64, unreachable: bool) -> anyhow::Result<()> {
+        let mut conn = self.conn.clone();
+        if unreachable {
+            conn.sadd::<&str, i64, ()>(UNREACHABLE_KEY, chat).await?;
+        } else {
+            conn.srem::<&str, i64, ()>(UNREACHABLE_KEY, chat).await?;
+        }
+        Ok(())
+    }
+
+    async fn create_broadcast(&self, broadcast: &Broadcast, chats: &[i64]) -> anyhow::Result<u64> {
+        let mut conn = self.conn.clone();
+        let data = serde_json::to_vec(broadcast)?;
+        let pending = serde_json::to_vec(&Delivery::Pending)?;
+        let id = conn.incr::<&str, u64, u64>(BROADCAST_SEQ_KEY, 1).await?;
+        let (deliveries_key, pending_key) = Self::broadcast_keys(id);
+        let mut pipe = ::redis::pipe();
+        pipe.atomic().hset(BROADCASTS_KEY, id, data).ignore();
+        if !chats.is_empty() {
+            let deliveries: Vec<(i64, &[u8])> = chats.iter().map(|x| (*x, &pending[..])).collect();
+            pipe.hset_multiple(deliveries_key, &deliveries)
+                .ignore()
+                .sadd(pending_key, chats)
+                .ignore();
+        }
+        pipe.query_async::<()>(&mut conn).await?;
+        Ok(id)
+    }
+
+    async fn get_broadcast(&self, id: u64) -> anyhow::Result<Option<Broadcast>> {
+        let mut conn = self.conn.clone();
+        let Some(value) = conn
+            .hget::<&str, u64, Option<Vec<u8>>>(BROADCASTS_KEY, id)
+            .await?
+        else {
+            return Ok(None);
+        };
+        let mut broadcast = decode::<Broadcast>(&format!("{BROADCASTS_KEY}[{id}]"), &value)?;
+        broadcast.id = id;
+        Ok(Some(broadcast))
+    }
+
+    async fn update_broadcast(&self, broadcast: &Broadcast) -> anyhow::Result<()> {
+        let mut conn = self.conn.clone();
+        conn.hset::<&str, u64, Vec<u8>, ()>(
+            BROADCASTS_KEY,
+            broadcast.id,
+            serde_json::to_vec(broadcast)?,
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn broadcasts(&self, count: usize) -> anyhow::Result<Vec<Broadcast>> {
+        let mut conn = self.conn.clone();
+        let mut ids = conn.hkeys::<&str, Vec<u64>>(BROADCASTS_KEY).await?;
+        ids.sort_unstable();
+        let mut ret = Vec::new();
+        for id in ids.iter().skip(ids.len().saturating_sub(count)) {
+            if let Some(broadcast) = self.get_broadcast(*id).await? {
+                ret.push(broadcast);
+            }
+        }
+        Ok(ret)
+    }
+
+    async fn deliveries(&self, id: u64) -> anyhow::Result<HashMap<i64, Delivery>> {
+        let mut conn = self.conn.clone();
+        let (deliveries_key, _) = Self::broadcast_keys(id);
+        let entries = conn
+            .hgetall::<&str, HashMap<i64, Vec<u8>>>(&deliveries_key)
+            .await?;
+        let mut ret = HashMap::new();
+        for (chat, data) in entries {
+            ret.insert(chat, decode(&format!("{deliveries_key}[{chat}]"), &data)?);
+        }
+        Ok(ret)
+    }
+
+    async fn set_delivery(&self, id: u64, chat: i64, delivery: &Delivery) -> anyhow::Result<usize> {
+        let mut conn = self.conn.clone();
+        let (deliveries_key, pending_key) = Self::broadcast_keys(id);
+        let mut pipe = ::redis::pipe();
+        pipe.atomic()
+            .hset(deliveries_key, chat, serde_json::to_vec(delivery)?)
+            .ignore();
+        if *delivery == Delivery::Pending {
+            pipe.sadd(&pending_key, chat).ignore();
+        } else {
+            pipe.srem(&pending_key, chat).ignore();
+        }
+        let (left,) = pipe
+            .scard(&pending_key)
+            .query_async::<(usize,)>(&mut conn)
+            .await?;
+        Ok(left)
+    }
+
+    async fn add_schedule(&self, schedule: &Schedule) -> anyhow::Result<u64> {
+        let mut conn = self.conn.clone();
+        let data = serde_json::to_vec(schedule)?;
+        let id = conn.incr::<&str, u64, u64>(SCHEDULE_SEQ_KEY, 1).await?;
+        conn.hset::<&str, u64, Vec<u8>, ()>(SCHEDULES_KEY, id, data)
+            .await?;
+        Ok(id)
+    }
+
+    async fn schedules(&self) -> anyhow::Result<Vec<Schedule>> {
+        let mut conn = self.conn.clone();
+        let entries = conn
+            .hgetall::<&str, HashMap<u64, Vec<u8>>>(SCHEDULES_KEY)
+            .await?;
+        let mut ret = Vec::new();
+        for (id, data) in entries {
+            let mut schedule = decode::<Schedule>(&format!("{SCHEDULES_KEY}[{id}]"), &data)?;
+            schedule.id = id;
+            ret.push(schedule);
+        }
+        Ok(ret)
+    }
+
+    async fn remove_schedule(&self, id: u64) -> anyhow::Result<bool> {
+        let mut conn = self.conn.clone();
+        Ok(conn.hdel::<&str, u64, u64>(SCHEDULES_KEY, id).await? == 1)
+    }
+
+    async fn log_audit(&self, entry: &AuditEntry) -> anyhow::Result<()> {
+        let mut conn = self.conn.clone();
+        conn.rpush::<&str, Vec<u8>, ()>(AUDIT_KEY, serde_json::to_vec(entry)?)
+            .await?;
+        Ok(())
+    }
+
+    async fn audit(&self, count: usize) -> anyhow::Result<Vec<AuditEntry>> {
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        let mut conn = self.conn.clone();
+        let entries = conn
+            .lrange::<&str, Vec<Vec<u8>>>(AUDIT_KEY, -(count as isize), -1)
+            .await?;
+        let mut ret = Vec::new();
+        for (i, entry) in entries.iter().enumerate() {
+            ret.push(decode(
+                &format!("{AUDIT_KEY}[-{}]", entries.len() - i),
+                entry,
+            )?);
+        }
+        Ok(ret)
+    }
+
+    /** Related keys are read together in one MULTI, per-user and per-task keys in batches, so a write
+    during the export may land in some parts only. Solve sets are read before the task scores, so a
+    solve in the copy always has its time **/
+    async fn export(&self) -> anyhow::Result<Snapshot> {
+        let mut conn = self.conn.clone();
+        let (
+            events,
+            current,
+            user_ids,
+            registrations,
+            invites,
+            sanctions,
+            audit,
+            roles,
+            config_roles,
+        ) = ::redis::pipe()
+            .atomic()
+            .hgetall(EVENTS_KEY)
+            .get(CURRENT_EVENT_KEY)
+            .smembers(USERS_KEY)
+            .hgetall(REGISTRATIONS_KEY)
+            .hgetall(INVITES_KEY)
+            .hgetall(SANCTIONS_KEY)
+            .lrange(AUDIT_KEY, 0, -1)
+            .smembers(ROLES_KEY)
+            .smembers(CONFIG_ROLES_KEY)
+            .query_async::<(
+                Vec<String>,
+                Option<String>,
+                HashSet<u64>,
+                Vec<String>,
+                Vec<String>,
+                Vec<String>,
+                Vec<String>,
+                Vec<String>,
+                Vec<String>,
+            )>(&mut conn)
+            .await?;
+        let (outbox, dead_letters, unreachable, broadcasts, schedules) = ::redis::pipe()
+            .atomic()
+            .hgetall(OUTBOX_KEY)
+            .lrange(DEAD_LETTERS_KEY, 0, -1)
+            .smembers(UNREACHABLE_KEY)
+            .hgetall(BROADCASTS_KEY)
+            .hgetall(SCHEDULES_KEY)
+            .query_async::<(Vec<String>, Vec<String>, Vec<i64>, Vec<String>, Vec<String>)>(
+                &mut conn,
+            )
+            .await?;
+        let mut names = vec![String::from(DEFAULT_EVENT)];
+        names.extend(
+            events
+                .iter()
+                .step_by(2)
+                .filter(|x| x.as_str() != DEFAULT_EVENT)
+                .cloned(),
+        );
+        // unranked solves leave no participant, so every known user is looked up in every event
+        let mut ids = user_ids;
+        let mut data = Vec::new();
+        for name in names {
+            let prefix = ns(&name);
+            let (task_ids, participants, attempts) = ::redis::pipe()
+                .atomic()
+                .smembers(format!("{prefix}{TASKS_KEY}"))
+                .smembers(format!("{prefix}{PARTICIPANTS_KEY}"))
+                .lrange(format!("{prefix}{ATTEMPTS_KEY}"), 0, -1)
+                .query_async::<(Vec<String>, Vec<u64>, Vec<String>)>(&mut conn)
+                .await?;
+            ids.extend(participants);
+            data.push((name, task_ids, attempts));
+        }
+        let mut ids = ids.into_iter().collect::<Vec<_>>();
+        ids.sort();
+        let keys = |prefix: &str| {
+            ids.iter()
+                .map(|x| format!("{prefix}{x}"))
+                .collect::<Vec<_>>()
+        };
+        let users = self
+            .read_keys::<Option<String>>("GET", &keys("user:"), &[])
+            .await?;
+        let states = self
+            .read_keys::<Option<String>>("GET", &keys("user_state:"), &[])
+            .await?;
+        let contacts = self
+            .read_keys::<Option<String>>("GET", &keys("contact:"), &[])
+            .await?;
+        let mut snapshot = Snapshot::new();
+        let mut skipped = 0;
+        snapshot.current_event = current.filter(|x| !x.is_empty());
+        for (id, task_ids, attempts) in data {
+            let prefix = ns(&id);
+            let mut event = EventSnapshot::default();
+            let task_keys = task_ids
+                .iter()
+                .map(|x| format!("{prefix}task:{x}"))
+                .collect::<Vec<_>>();
+            let bodies = self
+                .read_keys::<Option<String>>("GET", &task_keys, &[])
+                .await?;
+            for (task_id, body) in task_ids.into_iter().zip(bodies) {
+                let Some(body) = body else {
+                    continue;
+                };
+                let task = decode::<Task>(&format!("task:{task_id}"), body.as_bytes());
+                if let Some(task) = or_skip(task, &mut skipped) {
+                    event.tasks.insert(task_id, task);
+                }
+            }
+            let solved = self
+                .read_keys::<Vec<String>>("SMEMBERS", &keys(&format!("{prefix}solves:")), &[])
+                .await?;
+            let solved = ids.iter().zip(solved).collect::<Vec<_>>();
+            let mut solved_tasks = solved
+                .iter()
+                .flat_map(|x| x.1.iter().cloned())
+                .collect::<Vec<_>>();
+            solved_tasks.sort();
+            solved_tasks.dedup();
+            let score_keys = solved_tasks
+                .iter()
+                .map(|x| format!("{prefix}task_solves:{x}"))
+                .collect::<Vec<_>>();
+            let scores = self
+                .read_keys::<Vec<(u64, f64)>>("ZRANGE", &score_keys, &["0", "-1", "WITHSCORES"])
+                .await?;
+            let times = solved_tasks
+                .into_iter()
+                .zip(scores)
+                .flat_map(|(task_id, scores)| {
+                    scores
+                        .into_iter()
+                        .map(move |(user_id, time)| ((user_id, task_id.clone()), time as u64))
+                })
+                .collect::<HashMap<_, _>>();
+            for (user_id, task_ids) in solved {
+                for task_id in task_ids {
+                    let time = times.get(&(*user_id, task_id.clone())).copied();
+                    event.solves.push(SolveRecord {
+                        user_id: *user_id,
+                        task_id,
+                        time: time.unwrap_or(0),
+                        ranked: time.is_some(),
+                    });
+                }
+            }
+            for (i, attempt) in attempts.iter().enumerate() {
+                let attempt = decode(&format!("{ATTEMPTS_KEY}[{i}]"), attempt.as_bytes());
+                event.attempts.extend(or_skip(attempt, &mut skipped));
+            }
+            snapshot.events.insert(id, event);
+        }
+        let broadcast_ids = broadcasts
+            .iter()
+            .step_by(2)
+            .filter_map(|x| x.parse::<u64>().ok())
+            .collect::<Vec<_>>();
+        let delivery_keys = broadcast_ids
+            .iter()
+            .map(|x| Self::broadcast_keys(*x).0)
+            .collect::<Vec<_>>();
+        let deliveries = broadcast_ids
+            .into_iter()
+            .zip(
+                self.read_keys::<Vec<String>>("HGETALL", &delivery_keys, &[])
+                    .await?,
+            )
+            .collect::<Vec<_>>();
+        for pair in events.chunks_exact(2) {
+            let event = decode::<Event>(&format!("{EVENTS_KEY}[{}]", pair[0]), pair[1].as_bytes());
+            let event = or_skip(event, &mut skipped);
+            snapshot.events.entry(pair[0].clone()).or_default().event = event;
+        }
+        for (user_id, user) in ids.iter().zip(users) {
+            let Some(user) = user else {
+                continue;
+            };
+            let user = decode::<Vas3kUser>(&format!("user:{user_id}"), user.as_bytes());
+            if let Some(user) = or_skip(user, &mut skipped) {
+                snapshot.users.insert(*user_id, user);
+            }
+        }
+        for (user_id, state) in ids.iter().zip(states) {
+            let Some(state) = state else {
+                continue;
+            };
+            let state = decode::<String>(&format!("user_state:{user_id}"), state.as_bytes());
+            if let Some(state) = or_skip(state, &mut skipped) {
+                snapshot.states.insert(*user_id, state);
+            }
+        }
+        for (user_id, contact) in ids.iter().zip(contacts) {
+            if let Some(contact) = contact {
+                snapshot.contacts.insert(*user_id, contact);
+            }
+        }
+        for pair in registrations.chunks_exact(2) {
+            snapshot
+                .registrations
+                .insert(pair[0].parse()?, pair[1].clone());
+        }
+        for pair in invites.chunks_exact(2) {
+            let invite =
+                decode::<Invite>(&format!("{INVITES_KEY}[{}]", pair[0]), pair[1].as_bytes());
+            if let Some(invite) = or_skip(invite, &mut skipped) {
+                snapshot.invites.insert(pair[0].clone(), invite);
+            }
+        }
+        for sanction in Self::decode_sanctions(sanctions) {
+            if let Some((user_id, sanction)) = or_skip(sanction, &mut skipped) {
+                snapshot
+                    .sanctions
+                    .entry(user_id)
+                    .or_default()
+                    .push(sanction);
+            }
+        }
+        for (i, entry) in audit.iter().enumerate() {
+            let entry = decode(&format!("{AUDIT_KEY}[{i}]"), entry.as_bytes());
+            snapshot.audit.extend(or_skip(entry, &mut skipped));
+        }
+        snapshot.roles = Self::decode_roles(roles);
+        snapshot.config_roles = Self::decode_roles(config_roles);
+        for pair in outbox.chunks_exact(2) {
+            let message =
+                decode::<Message>(&format!("{OUTBOX_KEY}[{}]", pair[0]), pair[1].as_bytes());
+            if let Some(message) = or_skip(message, &mut skipped) {
+                snapshot.outbox.insert(pair[0].parse()?, message);
+            }
+        }
+        for (i, letter) in dead_letters.iter().enumerate() {
+            let letter = decode(&format!("{DEAD_LETTERS_KEY}[{i}]"), letter.as_bytes());
+            snapshot.dead_letters.extend(or_skip(letter, &mut skipped));
+        }
+        snapshot.unreachable = unreachable;
+        snapshot.unreachable.sort();
+        for pair in broadcasts.chunks_exact(2) {
+            let broadcast = decode::<Broadcast>(
+                &format!("{BROADCASTS_KEY}[{}]", pair[0]),
+                pair[1].as_bytes(),
+            );
+            let Some(mut broadcast) = or_skip(broadcast, &mut skipped) else {
+                continue;
+            };
+            broadcast.id = pair[0].parse()?;
+            let data = BroadcastSnapshot {
+                broadcast,
+                deliveries: BTreeMap::new(),
+            };
+            snapshot.broadcasts.insert(data.broadcast.id, data);
+        }
+        for (id, pairs) in deliveries {
+            let (deliveries_key, _) = Self::broadcast_keys(id);
+            let Some(data) = snapshot.broadcasts.get_mut(&id) else {
+                continue;
+            };
+            for pair in pairs.chunks_exact(2) {
+                let delivery = decode::<Delivery>(
+                    &format!("{deliveries_key}[{}]", pair[0]),
+                    pair[1].as_bytes(),
+                );
+                if let Some(delivery) = or_skip(delivery, &mut skipped) {
+                    data.deliveries.insert(pair[0].parse()?, delivery);
+                }
+            }
+        }
+        for pair in schedules.chunks_exact(2) {
+            let schedule =
+                decode::<Schedule>(&format!("{SCHEDULES_KEY}[{}]", pair[0]), pair[1].as_bytes());
+            if let Some(mut schedule) = or_skip(schedule, &mut skipped) {
+                schedule.id = pair[0].parse()?;
+                snapshot.schedules.insert(schedule.id, schedule);
+            }
+        }
+        snapshot.skipped = skipped;
+        Ok(snapshot)
+    }
+}
@@ -0,0 +1,1568 @@
+/**
+Copyright 2025 Ivan Agarkov
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+**/
+use crate::api::{
+    Attempt, AttemptKind, AuditEntry, Broadcast, Delivery, Event, Invite, Role, Sanction,
+    SanctionKind, Schedule, Task, Vas3kUser,
+};
+use crate::sender::{DeadLetter, Message, Priority};
+use crate::snapshot::{BroadcastSnapshot, EventSnapshot, Snapshot, SolveRecord};
+use crate::storage::{
+    AttemptCounters, InviteRedeem, Storage, TaskWrite, check_invite, decode, or_skip,
+};
+use anyhow::bail;
+use async_trait::async_trait;
+use log::{error, info};
+use rusqlite::{Connection, OptionalExtension, params};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+/** Each entry upgrades the schema by one version, the current one is in `PRAGMA user_version` **/
+const MIGRATIONS: &[&str] = &[
+    SCHEMA_V1, SCHEMA_V2, SCHEMA_V3, SCHEMA_V4, SCHEMA_V5, SCHEMA_V6, SCHEMA_V7, SCHEMA_V8,
+    SCHEMA_V9, SCHEMA_V10, SCHEMA_V11, SCHEMA_V12, SCHEMA_V13,
+];
+
+const SCHEMA_V1: &str = r"
+CREATE TABLE IF NOT EXISTS tasks (id TEXT PRIMARY KEY, data TEXT NOT NULL);
+CREATE TABLE IF NOT EXISTS flags (flag TEXT PRIMARY KEY, task_id TEXT NOT NULL);
+CREATE TABLE IF NOT EXISTS solves (
+    user_id INTEGER NOT NULL,
+    task_id TEXT NOT NULL,
+    time INTEGER NOT NULL,
+    ranked INTEGER NOT NULL,
+    PRIMARY KEY (user_id, task_id)
+);
+CREATE TABLE IF NOT EXISTS attempts (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    data TEXT NOT NULL,
+    wrong INTEGER NOT NULL,
+    counted INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS states (user_id INTEGER PRIMARY KEY, state TEXT NOT NULL);
+CREATE TABLE IF NOT EXISTS contacts (user_id INTEGER PRIMARY KEY, message TEXT NOT NULL);
+CREATE TABLE IF NOT EXISTS users (user_id INTEGER PRIMARY KEY, data TEXT NOT NULL);
+";
+
+/** Events: tasks, flags, solves and attempts get an event ID, existing rows go to the default event **/
+const SCHEMA_V2: &str = r"
+CREATE TABLE events (id TEXT PRIMARY KEY, data TEXT NOT NULL);
+CREATE TABLE settings (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+ALTER TABLE tasks RENAME TO tasks_v1;
+CREATE TABLE tasks (
+    event_id TEXT NOT NULL,
+    id TEXT NOT NULL,
+    data TEXT NOT NULL,
+    PRIMARY KEY (event_id, id)
+);
+INSERT INTO tasks SELECT 'main', id, data FROM tasks_v1;
+DROP TABLE tasks_v1;
+ALTER TABLE flags RENAME TO flags_v1;
+CREATE TABLE flags (
+    event_id TEXT NOT NULL,
+    flag TEXT NOT NULL,
+    task_id TEXT NOT NULL,
+    PRIMARY KEY (event_id, flag)
+);
+INSERT INTO flags SELECT 'main', flag, task_id FROM flags_v1;
+DROP TABLE flags_v1;
+ALTER TABLE solves RENAME TO solves_v1;
+CREATE TABLE solves (
+    event_id TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    task_id TEXT NOT NULL,
+    time INTEGER NOT NULL,
+    ranked INTEGER NOT NULL,
+    PRIMARY KEY (event_id, user_id, task_id)
+);
+INSERT INTO solves SELECT 'main', user_id, task_id, time, ranked FROM solves_v1;
+DROP TABLE solves_v1;
+ALTER TABLE attempts ADD COLUMN event_id TEXT NOT NULL DEFAULT 'main';
+";
+
+/** Invite codes users joined with **/
+const SCHEMA_V3: &str = r"
+CREATE TABLE registrations (user_id INTEGER PRIMARY KEY, code TEXT NOT NULL);
+";
+
+/** Invite codes managed by admins **/
+const SCHEMA_V4: &str = r"
+CREATE TABLE invites (code TEXT PRIMARY KEY, data TEXT NOT NULL);
+";
+
+/** Sanctions and the audit log of admin actions **/
+const SCHEMA_V5: &str = r"
+CREATE TABLE sanctions (
+    user_id INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    data TEXT NOT NULL,
+    PRIMARY KEY (user_id, kind)
+);
+CREATE TABLE audit (id INTEGER PRIMARY KEY AUTOINCREMENT, data TEXT NOT NULL);
+";
+
+/** Roles managed at runtime, seeded from the config **/
+const SCHEMA_V6: &str = r"
+CREATE TABLE roles (id INTEGER NOT NULL, role TEXT NOT NULL, PRIMARY KEY (id, role));
+";
+
+/** Outgoing Telegram messages, delivered in id order **/
+const SCHEMA_V7: &str = r"
+CREATE TABLE outbox (id INTEGER PRIMARY KEY AUTOINCREMENT, data TEXT NOT NULL);
+";
+
+/** Messages Telegram refused for good, and chats that cannot be written to **/
+const SCHEMA_V8: &str = r"
+CREATE TABLE dead_letters (id INTEGER PRIMARY KEY, data TEXT NOT NULL);
+CREATE TABLE unreachable (chat INTEGER PRIMARY KEY);
+";
+
+/** Broadcasts and how each of their messages went **/
+const SCHEMA_V9: &str = r"
+CREATE TABLE broadcasts (id INTEGER PRIMARY KEY AUTOINCREMENT, data TEXT NOT NULL);
+CREATE TABLE deliveries (
+    broadcast INTEGER NOT NULL,
+    chat INTEGER NOT NULL,
+    pending INTEGER NOT NULL,
+    data TEXT NOT NULL,
+    PRIMARY KEY (broadcast, chat)
+);
+";
+
+/** Broadcasts waiting for their time **/
+const SCHEMA_V10: &str = r"
+CREATE TABLE schedules (id INTEGER PRIMARY KEY AUTOINCREMENT, data TEXT NOT NULL);
+";
+
+/** Priority lanes of the outbox, queued messages become interactive **/
+const SCHEMA_V11: &str = r"
+ALTER TABLE outbox ADD COLUMN priority INTEGER NOT NULL DEFAULT 0;
+CREATE INDEX outbox_priority ON outbox (priority, id);
+";
+
+/** Chat of each queued message, so the next one of every chat is found without decoding **/
+const SCHEMA_V12: &str = r"
+ALTER TABLE outbox ADD COLUMN chat INTEGER NOT NULL DEFAULT 0;
+UPDATE outbox SET chat = coalesce(json_extract(data, '$.chat'), 0) WHERE json_valid(data);
+CREATE INDEX outbox_chat ON outbox (chat, priority, id);
+";
+
+/** Roles seeded from the config groups, older ones are treated as granted with /grant **/
+const SCHEMA_V13: &str = r"
+ALTER TABLE roles ADD COLUMN config INTEGER NOT NULL DEFAULT 0;
+";
+
+const CURRENT_EVENT: &str = "current_event";
+
+/** Single-file storage for small deployments **/
+pub struct SqliteStorage {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStorage {
+    pub async fn new(path: &str) -> anyhow::Result<Self> {
+        let path = String::from(path);
+        let conn = tokio::task::spawn_blocking(move || -> anyhow::Result<Connection> {
+            let mut conn = Connection::open(path)?;
+            Self::migrate(&mut conn)?;
+            Ok(conn)
+        })
+        .await??;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    fn migrate(conn: &mut Connection) -> anyhow::Result<()> {
+        let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version > MIGRATIONS.len() {
+            bail!(
+                "Storage schema {version} is newer than supported {}",
+                MIGRATIONS.len()
+            );
+        }
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            info!("Migrating storage schema {} -> {}", i, i + 1);
+            let tx = conn.transaction()?;
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", i + 1)?;
+            tx.commit()?;
+        }
+        Ok(())
+    }
+
+    /** Runs a blocking closure on the connection outside of the async runtime **/
+    async fn call<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> anyhow::Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().unwrap_or_else(|e| e.into_inner());
+            f(&mut conn)
+        })
+        .await?
+    }
+
+    fn scores(conn: &Connection, event: &str) -> anyhow::Result<Vec<(u64, u64)>> {
+        let mut stmt = conn.prepare(
+            "SELECT user_id, COUNT(*) AS score FROM solves WHERE event_id = ?1 AND ranked = 1 \
+             GROUP BY user_id ORDER BY score DESC, user_id ASC",
+        )?;
+        let rows = stmt.query_map(params![event], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<Result<Vec<(u64, u64)>, _>>()?)
+    }
+
+    fn remove_flags(conn: &Connection, event: &str, id: &str) -> anyhow::Result<()> {
+        conn.execute(
+            "DELETE FROM flags WHERE event_id = ?1 AND task_id = ?2",
+            params![event, id],
+        )?;
+        Ok(())
+    }
+
+    async fn write_task(
+        &self,
+        event: &str,
+        task: &Task,
+        create: bool,
+    ) -> anyhow::Result<TaskWrite> {
+        let event = String::from(event);
+        let id = task.id.clone();
+        let data = serde_json::to_string(task)?;
+        let flags = task.flag.iter().cloned().collect::<Vec<String>>();
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            if create
+                && tx
+                    .query_row(
+                        "SELECT 1 FROM tasks WHERE event_id = ?1 AND id = ?2",
+                        params![event, id],
+                        |_| Ok(()),
+                    )
+                    .optional()?
+                    .is_some()
+            {
+                return Ok(TaskWrite::IdTaken);
+            }
+            for flag in flags.iter() {
+                if let Some(owner) = tx
+                    .query_row(
+                        "SELECT task_id FROM flags WHERE event_id = ?1 AND flag = ?2 AND task_id != ?3",
+                        params![event, flag, id],
+                        |row| row.get::<_, String>(0),
+                    )
+                    .optional()?
+                {
+                    return Ok(TaskWrite::FlagTaken(flag.clone(), owner));
+                }
+            }
+            Self::remove_flags(&tx, &event, &id)?;
+            tx.execute(
+                "INSERT OR REPLACE INTO tasks (event_id, id, data) VALUES (?1, ?2, ?3)",
+                params![event, id, data],
+            )?;
+            for flag in flags {
+                tx.execute(
+                    "INSERT INTO flags (event_id, flag, task_id) VALUES (?1, ?2, ?3)",
+                    params![event, flag, id],
+                )?;
+            }
+            tx.commit()?;
+            Ok(TaskWrite::Done)
+        })
+        .await
+    }
+
+    /** Unknown roles are logged and skipped, a newer version may have written them **/
+    fn read_roles(conn: &Connection, config: bool) -> anyhow::Result<Vec<(i64, Role)>> {
+        let query = match config {
+            true => "SELECT id, role FROM roles WHERE config = 1 ORDER BY id, role",
+            false => "SELECT id, role FROM roles ORDER BY id, role",
+        };
+        let mut stmt = conn.prepare(query)?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get::<_, String>(1)?)))?;
+        let mut ret = Vec::new();
+        for row in rows {
+            let (id, role) = row?;
+            match Role::parse(&role) {
+                Some(role) => ret.push((id, role)),
+                None => error!("Unknown role {role} of {id}"),
+            }
+        }
+        Ok(ret)
+    }
+
+    /** Each sanction decoded on its own, so an export can leave a broken one out **/
+    fn read_sanctions(conn: &Connection) -> anyhow::Result<Vec<anyhow::Result<(u64, Sanction)>>> {
+        let mut stmt = conn.prepare("SELECT user_id, data FROM sanctions")?;
+        let mut rows = stmt.query([])?;
+        let mut ret = Vec::new();
+        while let Some(row) = rows.next()? {
+            let user_id: u64 = row.get(0)?;
+            let key = format!("sanction:{user_id}");
+            ret.push(decode(&key, row.get_ref(1)?.as_bytes()?).map(|x| (user_id, x)));
+        }
+        Ok(ret)
+    }
+
+    /** Rows of (id, data) from the audit table, each decoded on its own **/
+    fn read_audit(mut rows: rusqlite::Rows<'_>) -> anyhow::Result<Vec<anyhow::Result<AuditEntry>>> {
+        let mut ret = Vec::new();
+        while let Some(row) = rows.next()? {
+            let id: u64 = row.get(0)?;
+            ret.push(decode(&format!("audit:{id}"), row.get_ref(1)?.as_bytes()?));
+        }
+        Ok(ret)
+    }
+
+    fn read_invite(conn: &Connection, code: &str) -> anyhow::Result<Option<Invite>> {
+        let data = conn
+            .query_row(
+                "SELECT data FROM invites WHERE code = ?1",
+                params![code],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?;
+        let Some(data) = data else {
+            return Ok(None);
+        };
+        let mut invite = decode::<Invite>(&format!("invite:{code}"), data.as_bytes())?;
+        invite.code = String::from(code);
+        Ok(Some(invite))
+    }
+
+    fn export_event(
+        conn: &Connection,
+        id: &str,
+        skipped: &mut usize,
+    ) -> anyhow::Result<EventSnapshot> {
+        let mut snapshot = EventSnapshot::default();
+        let mut stmt = conn.prepare("SELECT id, data FROM tasks WHERE event_id = ?1")?;
+        let mut rows = stmt.query(params![id])?;
+        while let Some(row) = rows.next()? {
+            let task_id: String = row.get(0)?;
+            let task = decode::<Task>(&format!("task:{task_id}"), row.get_ref(1)?.as_bytes()?);
+            if let Some(task) = or_skip(task, skipped) {
+                snapshot.tasks.insert(task_id, task);
+            }
+        }
+        let mut stmt = conn.prepare(
+            "SELECT user_id, task_id, time, ranked FROM solves WHERE event_id = ?1 ORDER BY time",
+        )?;
+        snapshot.solves = stmt
+            .query_map(params![id], |row| {
+                Ok(SolveRecord {
+                    user_id: row.get(0)?,
+                    task_id: row.get(1)?,
+                    time: row.get(2)?,
+                    ranked: row.get(3)?,
+                })
+            })?
+            .collect::<Result<Vec<SolveRecord>, _>>()?;
+        let mut stmt =
+            conn.prepare("SELECT id, data, counted FROM attempts WHERE event_id = ?1 ORDER BY id")?;
+        let mut rows = stmt.query(params![id])?;
+        while let Some(row) = rows.next()? {
+            let attempt_id: u64 = row.get(0)?;
+            let attempt = decode::<Attempt>(
+                &format!("attempt:{attempt_id}"),
+                row.get_ref(1)?.as_bytes()?,
+            );
+            if let Some(mut attempt) = or_skip(attempt, skipped) {
+                attempt.counted = row.get(2)?;
+                snapshot.attempts.push(attempt);
+            }
+        }
+        Ok(snapshot)
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn get_event(&self, id: &str) -> anyhow::Result<Option<Event>> {
+        let id = String::from(id);
+        self.call(move |conn| {
+            let data = conn
+                .query_row(
+                    "SELECT data FROM events WHERE id = ?1",
+                    params![id],
+                    |row| row.get::<_, String>(0),
+                )
+                .optional()?;
+            let Some(data) = data else {
+                return Ok(None);
+            };
+            let mut event = decode::<Event>(&format!("event:{id}"), data.as_bytes())?;
+            event.id = id;
+            Ok(Some(event))
+        })
+        .await
+    }
+
+    async fn event_ids(&self) -> anyhow::Result<Vec<String>> {
+        self.call(|conn| {
+            let mut stmt = conn.prepare("SELECT id FROM events")?;
+            let rows = stmt.query_map([], |row| row.get(0))?;
+            Ok(rows.collect::<Result<Vec<String>, _>>()?)
+        })
+        .await
+    }
+
+    async fn create_event(&self, event: &Event) -> anyhow::Result<bool> {
+        let id = event.id.clone();
+        let data = serde_json::to_string(event)?;
+        self.call(move |conn| {
+            let added = conn.execute(
+                "INSERT OR IGNORE INTO events (id, data) VALUES (?1, ?2)",
+                params![id, data],
+            )?;
+            Ok(added == 1)
+        })
+        .await
+    }
+
+    async fn update_event(&self, event: &Event) -> anyhow::Result<()> {
+        let id = event.id.clone();
+        let data = serde_json::to_string(event)?;
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO events (id, data) VALUES (?1, ?2)",
+                params![id, data],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn current_event(&self) -> anyhow::Result<Option<String>> {
+        self.call(|conn| {
+            Ok(conn
+                .query_row(
+                    "SELECT value FROM settings WHERE key = ?1",
+                    params![CURRENT_EVENT],
+                    |row| row.get(0),
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn set_current_event(&self, id: &str) -> anyhow::Result<()> {
+        let id = String::from(id);
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO settings (key, value) VALUES (?1, ?2)",
+                params![CURRENT_EVENT, id],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_task(&self, event: &str, id: &str) -> anyhow::Result<Option<Task>> {
+        let event = String::from(event);
+        let id = String::from(id);
+        self.call(move |conn| {
+            let data = conn
+                .query_row(
+                    "SELECT data FROM tasks WHERE event_id = ?1 AND id = ?2",
+                    params![event, id],
+                    |row| row.get::<_, String>(0),
+                )
+                .optional()?;
+            let Some(data) = data else {
+                return Ok(None);
+            };
+            let mut task = decode::<Task>(&format!("task:{id}"), data.as_bytes())?;
+            task.id = id;
+            Ok(Some(task))
+        })
+        .await
+    }
+
+    async fn task_ids(&self, event: &str) -> anyhow::Result<Vec<String>> {
+        let event = String::from(event);
+        self.call(move |conn| {
+            let mut stmt = conn.prepare("SELECT id FROM tasks WHERE event_id = ?1")?;
+            let rows = stmt.query_map(params![event], |row| row.get(0))?;
+            Ok(rows.collect::<Result<Vec<String>, _>>()?)
+        })
+        .await
+    }
+
+    async fn task_by_flag(&self, event: &str, flag: &str) -> anyhow::Result<Option<String>> {
+        let event = String::from(event);
+        let flag = String::from(flag);
+        self.call(move |conn| {
+            Ok(conn
+                .query_row(
+                    "SELECT task_id FROM flags WHERE event_id = ?1 AND flag = ?2",
+                    params![event, flag],
+                    |row| row.get(0),
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn create_task(&self, event: &str, task: &Task) -> anyhow::Result<TaskWrite> {
+        self.write_task(event, task, true).await
+    }
+
+    async fn update_task(&self, event: &str, task: &Task) -> anyhow::Result<TaskWrite> {
+        self.write_task(event, task, false).await
+    }
+
+    async fn delete_task(&self, event: &str, id: &str) -> anyhow::Result<()> {
+        let event = String::from(event);
+        let id = String::from(id);
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            Self::remove_flags(&tx, &event, &id)?;
+            tx.execute(
+                "DELETE FROM tasks WHERE event_id = ?1 AND id = ?2",
+                params![event, id],
+            )?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn is_solved(&self, event: &str, user_id: u64, task_id: &str) -> anyhow::Result<bool> {
+        let event = String::from(event);
+        let task_id = String::from(task_id);
+        self.call(move |conn| {
+            Ok(conn
+                .query_row(
+                    "SELECT 1 FROM solves WHERE event_id = ?1 AND user_id = ?2 AND task_id = ?3",
+                    params![event, user_id, task_id],
+                    |_| Ok(()),
+                )
+                .optional()?
+                .is_some())
+        })
+        .await
+    }
+
+    async fn add_solve(
+        &self,
+        event: &str,
+        user_id: u64,
+        task_id: &str,
+        time: u64,
+        ranked: bool,
+    ) -> anyhow::Result<bool> {
+        let event = String::from(event);
+        let task_id = String::from(task_id);
+        self.call(move |conn| {
+            let added = conn.execute(
+                "INSERT OR IGNORE INTO solves (event_id, user_id, task_id, time, ranked) \
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![event, user_id, task_id, time, ranked],
+            )?;
+            Ok(added == 1)
+        })
+        .await
+    }
+
+    async fn solves_count(&self, event: &str, user_id: u64) -> anyhow::Result<u64> {
+        let event = String::from(event);
+        self.call(move |conn| {
+            Ok(conn.query_row(
+                "SELECT COUNT(*) FROM solves WHERE event_id = ?1 AND user_id = ?2",
+                params![event, user_id],
+                |row| row.get(0),
+            )?)
+        })
+        .await
+    }
+
+    async fn scores(&self, event: &str) -> anyhow::Result<Vec<(u64, u64)>> {
+        let event = String::from(event);
+        self.call(move |conn| Self::scores(conn, &event)).await
+    }
+
+    async fn task_solves(
+        &self,
+        event: &str,
+        task_id: &str,
+        skip: &HashSet<u64>,
+    ) -> anyhow::Result<(u64, Option<(u64, u64)>)> {
+        let event = String::from(event);
+        let task_id = String::from(task_id);
+        let skip = serde_json::to_string(skip)?;
+        self.call(move |conn| {
+            let solves = conn.query_row(
+                "SELECT COUNT(*) FROM solves WHERE event_id = ?1 AND task_id = ?2 AND ranked = 1 \
+                 AND user_id NOT IN (SELECT value FROM json_each(?3))",
+                params![event, task_id, skip],
+                |row| row.get(0),
+            )?;
+            let first = conn
+                .query_row(
+                    "SELECT user_id, time FROM solves \
+                     WHERE event_id = ?1 AND task_id = ?2 AND ranked = 1 AND time > 0 \
+                     AND user_id NOT IN (SELECT value FROM json_each(?3)) \
+                     ORDER BY time ASC LIMIT 1",
+                    params![event, task_id, skip],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()?;
+            Ok((solves, first))
+        })
+        .await
+    }
+
+    async fn log_attempt(&self, event: &str, attempt: &Attempt) -> anyhow::Result<()> {
+        let event = String::from(event);
+        let user_id = attempt.user_id;
+        let wrong = attempt.kind == AttemptKind::Wrong;
+        let counted = attempt.counted;
+        let data = serde_json::to_string(attempt)?;
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT INTO attempts (event_id, user_id, data, wrong, counted) \
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![event, user_id, data, wrong, counted],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn attempt_counters(&self, event: &str) -> anyhow::Result<AttemptCounters> {
+        let event = String::from(event);
+        self.call(move |conn| {
+            let participants = conn.query_row(
+                "SELECT COUNT(*) FROM (\
+                 SELECT user_id FROM attempts WHERE event_id = ?1 AND counted = 1 \
+                 UNION SELECT user_id FROM solves WHERE event_id = ?1 AND ranked = 1)",
+                params![event],
+                |row| row.get(0),
+            )?;
+            let attempts = conn.query_row(
+                "SELECT COUNT(*) FROM attempts WHERE event_id = ?1 AND counted = 1",
+                params![event],
+                |row| row.get(0),
+            )?;
+            let mut stmt = conn.prepare(
+                "SELECT user_id, COUNT(*) AS wrong FROM attempts \
+                 WHERE event_id = ?1 AND counted = 1 AND wrong = 1 \
+                 GROUP BY user_id ORDER BY wrong DESC, user_id ASC",
+            )?;
+            let wrong = stmt
+                .query_map(params![event], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<Vec<(u64, u64)>, _>>()?;
+            Ok(AttemptCounters {
+                participants,
+                attempts,
+                wrong,
+            })
+        })
+        .await
+    }
+
+    async fn get_state(&self, user_id: u64) -> anyhow::Result<Option<String>> {
+        self.call(move |conn| {
+            Ok(conn
+                .query_row(
+                    "SELECT state FROM states WHERE user_id = ?1",
+                    params![user_id],
+                    |row| row.get(0),
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn set_state(&self, user_id: u64, state: &str) -> anyhow::Result<()> {
+        let state = String::from(state);
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO states (user_id, state) VALUES (?1, ?2)",
+                params![user_id, state],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn append_contact(&self, user_id: u64, text: &str) -> anyhow::Result<()> {
+        let text = String::from(text);
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT INTO contacts (user_id, message) VALUES (?1, ?2) \
+                 ON CONFLICT (user_id) DO UPDATE SET message = \
+                 CASE WHEN message = '' THEN excluded.message ELSE message || char(10) || excluded.message END",
+                params![user_id, text],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn take_contact(&self, user_id: u64) -> anyhow::Result<String> {
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            let message = tx
+                .query_row(
+                    "SELECT message FROM contacts WHERE user_id = ?1",
+                    params![user_id],
+                    |row| row.get::<_, String>(0),
+                )
+                .optional()?
+                .unwrap_or_default();
+            tx.execute("DELETE FROM contacts WHERE user_id = ?1", params![user_id])?;
+            tx.commit()?;
+            Ok(message)
+        })
+        .await
+    }
+
+    async fn get_contact(&self, user_id: u64) -> anyhow::Result<String> {
+        self.call(move |conn| {
+            Ok(conn
+                .query_row(
+                    "SELECT message FROM contacts WHERE user_id = ?1",
+                    params![user_id],
+                    |row| row.get::<_, String>(0),
+                )
+                .optional()?
+                .unwrap_or_default())
+        })
+        .await
+    }
+
+    async fn get_user(&self, user_id: u64) -> anyhow::Result<Option<Vas3kUser>> {
+        self.call(move |conn| {
+            let data = conn
+                .query_row(
+                    "SELECT data FROM users WHERE user_id = ?1",
+                    params![user_id],
+                    |row| row.get::<_, String>(0),
+                )
+                .optional()?;
+            let Some(data) = data else {
+                return Ok(None);
+            };
+            let mut user = decode::<Vas3kUser>(&format!("user:{user_id}"), data.as_bytes())?;
+            user.telegram_id = user_id as i64;
+            Ok(Some(user))
+        })
+        .await
+    }
+
+    async fn put_user(&self, user_id: u64, user: &Vas3kUser) -> anyhow::Result<()> {
+        let data = serde_json::to_string(user)?;
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO users (user_id, data) VALUES (?1, ?2)",
+                params![user_id, data],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn user_ids(&self) -> anyhow::Result<Vec<u64>> {
+        self.call(|conn| {
+            let mut stmt = conn.prepare("SELECT user_id FROM users")?;
+            let rows = stmt.query_map([], |row| row.get(0))?;
+            Ok(rows.collect::<Result<Vec<u64>, _>>()?)
+        })
+        .await
+    }
+
+    async fn register(&self, user_id: u64, code: &str) -> anyhow::Result<()> {
+        let code = String::from(code);
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO registrations (user_id, code) VALUES (?1, ?2)",
+                params![user_id, code],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn registration(&self, user_id: u64) -> anyhow::Result<Option<String>> {
+        self.call(move |conn| {
+            Ok(conn
+                .query_row(
+                    "SELECT code FROM registrations WHERE user_id = ?1",
+                    params![user_id],
+                    |row| row.get(0),
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn code_users(&self, code: &str) -> anyhow::Result<Vec<u64>> {
+        let code = String::from(code);
+        self.call(move |conn| {
+            let mut stmt = conn.prepare("SELECT user_id FROM registrations WHERE code = ?1")?;
+            let rows = stmt.query_map(params![code], |row| row.get(0))?;
+            Ok(rows.collect::<Result<Vec<u64>, _>>()?)
+        })
+        .await
+    }
+
+    async fn get_invite(&self, code: &str) -> anyhow::Result<Option<Invite>> {
+        let code = String::from(code);
+        self.call(move |conn| Self::read_invite(conn, &code)).await
+    }
+
+    async fn invite_codes(&self) -> anyhow::Result<Vec<String>> {
+        self.call(|conn| {
+            let mut stmt = conn.prepare("SELECT code FROM invites")?;
+            let rows = stmt.query_map([], |row| row.get(0))?;
+            Ok(rows.collect::<Result<Vec<String>, _>>()?)
+        })
+        .await
+    }
+
+    async fn create_invite(&self, invite: &Invite) -> anyhow::Result<bool> {
+        let code = invite.code.clone();
+        let data = serde_json::to_string(invite)?;
+        self.call(move |conn| {
+            let added = conn.execute(
+                "INSERT OR IGNORE INTO invites (code, data) VALUES (?1, ?2)",
+                params![code, data],
+            )?;
+            Ok(added == 1)
+        })
+        .await
+    }
+
+    async fn update_invite(&self, invite: &Invite) -> anyhow::Result<()> {
+        let code = invite.code.clone();
+        let data = serde_json::to_string(invite)?;
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO invites (code, data) VALUES (?1, ?2)",
+                params![code, data],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn redeem_invite(
+        &self,
+        code: &str,
+        user_id: u64,
+        now: u64,
+    ) -> anyhow::Result<InviteRedeem> {
+        let code = String::from(code);
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            let registered = tx
+                .query_row(
+                    "SELECT 1 FROM registrations WHERE user_id = ?1",
+                    params![user_id],
+                    |_| Ok(()),
+                )
+                .optional()?;
+            if registered.is_some() {
+                return Ok(InviteRedeem::Done);
+            }
+            let Some(mut invite) = Self::read_invite(&tx, &code)? else {
+                return Ok(InviteRedeem::Unknown);
+            };
+            let ret = check_invite(&invite, now);
+            if matches!(ret, InviteRedeem::Done) {
+                invite.uses += 1;
+                tx.execute(
+                    "UPDATE invites SET data = ?2 WHERE code = ?1",
+                    params![code, serde_json::to_string(&invite)?],
+                )?;
+                tx.execute(
+                    "INSERT INTO registrations (user_id, code) VALUES (?1, ?2)",
+                    params![user_id, code],
+                )?;
+                tx.commit()?;
+            }
+            Ok(ret)
+        })
+        .await
+    }
+
+    async fn sanctions(&self, user_id: u64) -> anyhow::Result<Vec<Sanction>> {
+        self.call(move |conn| {
+            let mut stmt = conn.prepare("SELECT data FROM sanctions WHERE user_id = ?1")?;
+            let mut rows = stmt.query(params![user_id])?;
+            let mut ret = Vec::new();
+            while let Some(row) = rows.next()? {
+                let key = format!("sanction:{user_id}");
+                ret.push(decode::<Sanction>(&key, row.get_ref(0)?.as_bytes()?)?);
+            }
+            Ok(ret)
+        })
+        .await
+    }
+
+    async fn all_sanctions(&self) -> anyhow::Result<Vec<(u64, Sanction)>> {
+        self.call(|conn| Self::read_sanctions(conn)?.into_iter().collect())
+            .await
+    }
+
+    async fn put_sanction(&self, user_id: u64, sanction: &Sanction) -> anyhow::Result<()> {
+        let kind = sanction.kind.as_str();
+        let data = serde_json::to_string(sanction)?;
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO sanctions (user_id, kind, data) VALUES (?1, ?2, ?3)",
+                params![user_id, kind, data],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn remove_sanction(&self, user_id: u64, kind: SanctionKind) -> anyhow::Result<bool> {
+        self.call(move |conn| {
+            let removed = conn.execute(
+                "DELETE FROM sanctions WHERE user_id = ?1 AND kind = ?2",
+                params![user_id, kind.as_str()],
+            )?;
+            Ok(removed > 0)
+        })
+        .await
+    }
+
+    async fn roles(&self) -> anyhow::Result<Vec<(i64, Role)>> {
+        self.call(|conn| Self::read_roles(conn, false)).await
+    }
+
+    async fn grant_role(&self, id: i64, role: Role) -> anyhow::Result<bool> {
+        self.call(move |conn| {
+            let added = conn.execute(
+                "INSERT OR IGNORE INTO roles (id, role) VALUES (?1, ?2)",
+                params![id, role.as_str()],
+            )?;
+            Ok(added == 1)
+        })
+        .await
+    }
+
+    async fn revoke_role(&self, id: i64, role: Role) -> anyhow::Result<bool> {
+        self.call(move |conn| {
+            let removed = conn.execute(
+                "DELETE FROM roles WHERE id = ?1 AND role = ?2",
+                params![id, role.as_str()],
+            )?;
+            Ok(removed > 0)
+        })
+        .await
+    }
+
+    async fn config_roles(&self) -> anyhow::Result<Vec<(i64, Role)>> {
+        self.call(|conn| Self::read_roles(conn, true)).await
+    }
+
+    async fn set_config_role(&self, id: i64, role: Role, config: bool) -> anyhow::Result<()> {
+        self.call(move |conn| {
+            conn.execute(
+                "UPDATE roles SET config = ?3 WHERE id = ?1 AND role = ?2",
+                params![id, role.as_str(), config],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn enqueue_message(&self, message: &Message) -> anyhow::Result<u64> {
+        let data = serde_json::to_string(message)?;
+        let priority = message.priority as i64;
+        let chat = message.chat;
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT INTO outbox (data, priority, chat) VALUES (?1, ?2, ?3)",
+                params![data, priority, chat],
+            )?;
+            Ok(conn.last_insert_rowid() as u64)
+        })
+        .await
+    }
+
+    async fn pending_messages(
+        &self,
+        lane: Priority,
+        count: usize,
+        skip: &HashSet<i64>,
+    ) -> anyhow::Result<Vec<(u64, Message)>> {
+        let lane = lane as i64;
+        let skip = serde_json::to_string(skip)?;
+        self.call(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, data FROM outbox o \
+                 WHERE priority = ?3 AND chat NOT IN (SELECT value FROM json_each(?2)) \
+                 AND NOT EXISTS (SELECT 1 FROM outbox p WHERE p.chat = o.chat \
+                 AND p.priority = o.priority AND p.id < o.id) \
+                 ORDER BY id LIMIT ?1",
+            )?;
+            let mut rows = stmt.query(params![count as i64, skip, lane])?;
+            let mut ret = Vec::new();
+            let mut broken = Vec::new();
+            while let Some(row) = rows.next()? {
+                let id: i64 = row.get(0)?;
+                let data: String = row.get(1)?;
+                match decode(&format!("outbox[{id}]"), data.as_bytes()) {
+                    Ok(message) => ret.push((id as u64, message)),
+                    Err(_) => broken.push(id),
+                }
+            }
+            drop(rows);
+            drop(stmt);
+            // logged by decode with its contents, kept it would stop the queue
+            for id in broken {
+                conn.execute("DELETE FROM outbox WHERE id = ?1", params![id])?;
+            }
+            Ok(ret)
+        })
+        .await
+    }
+
+    async fn ack_message(&self, id: u64) -> anyhow::Result<()> {
+        self.call(move |conn| {
+            conn.execute("DELETE FROM outbox WHERE id = ?1", params![id as i64])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn bury_message(&self, letter: &DeadLetter) -> anyhow::Result<()> {
+        let id = letter.id as i64;
+        let data = serde_json::to_string(letter)?;
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute("DELETE FROM outbox WHERE id = ?1", params![id])?;
+            // a row ID of its own, queue IDs start over after a restore
+            tx.execute("INSERT INTO dead_letters (data) VALUES (?1)", params![data])?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn dead_letters(&self, count: usize) -> anyhow::Result<Vec<DeadLetter>> {
+        self.call(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, data FROM (SELECT id, data FROM dead_letters ORDER BY id DESC LIMIT ?1) \
+                 ORDER BY id",
+            )?;
+            let mut rows = stmt.query(params![count as i64])?;
+            let mut ret = Vec::new();
+            while let Some(row) = rows.next()? {
+                let id: i64 = row.get(0)?;
+                let data: String = row.get(1)?;
+                ret.push(decode(&format!("dead_letters[{id}]"), data.as_bytes())?);
+            }
+            Ok(ret)
+        })
+        .await
+    }
+
+    async fn unreachable_chats(&self) -> anyhow::Result<Vec<i64>> {
+        self.call(|conn| {
+            let mut stmt = conn.prepare("SELECT chat FROM unreachable")?;
+            let rows = stmt.query_map([], |row| row.get(0))?;
+            Ok(rows.collect::<Result<Vec<i64>, _>>()?)
+        })
+        .await
+    }
+
+    async fn set_unreachable(&self, chat: i64, unreachable: bool) -> anyhow::Result<()> {
+        self.call(move |conn| {
+            if unreachable {
+                conn.execute(
+                    "INSERT OR IGNORE INTO unreachable (chat) VALUES (?1)",
+                    params![chat],
+                )?;
+            } else {
+                conn.execute("DELETE FROM unreachable WHERE chat = ?1", params![chat])?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn create_broadcast(&self, broadcast: &Broadcast, chats: &[i64]) -> anyhow::Result<u64> {
+        let data = serde_json::to_string(broadcast)?;
+        let pending = serde_json::to_string(&Delivery::Pending)?;
+        let chats = chats.to_vec();
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute("INSERT INTO broadcasts (data) VALUES (?1)", params![data])?;
+            let id = tx.last_insert_rowid();
+            {
+                let mut stmt = tx.prepare(
+                    "INSERT OR IGNORE INTO deliveries (broadcast, chat, pending, data) \
+                     VALUES (?1, ?2, 1, ?3)",
+                )?;
+                for chat in chats {
+                    stmt.execute(params![id, chat, pending])?;
+                }
+            }
+            tx.commit()?;
+            Ok(id as u64)
+        })
+        .await
+    }
+
+    async fn get_broadcast(&self, id: u64) -> anyhow::Result<Option<Broadcast>> {
+        self.call(move |conn| {
+            let data = conn
+                .query_row(
+                    "SELECT data FROM broadcasts WHERE id = ?1",
+                    params![id as i64],
+                    |row| row.get::<_, String>(0),
+                )
+                .optional()?;
+            let Some(data) = data else {
+                return Ok(None);
+            };
+            let mut broadcast = decode::<Broadcast>(&format!("broadcasts[{id}]"), data.as_bytes())?;
+            broadcast.id = id;
+            Ok(Some(broadcast))
+        })
+        .await
+    }
+
+    async fn update_broadcast(&self, broadcast: &Broadcast) -> anyhow::Result<()> {
+        let id = broadcast.id as i64;
+        let data = serde_json::to_string(broadcast)?;
+        self.call(move |conn| {
+            conn.execute(
+                "UPDATE broadcasts SET data = ?2 WHERE id = ?1",
+                params![id, data],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn broadcasts(&self, count: usize) -> anyhow::Result<Vec<Broadcast>> {
+        self.call(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, data FROM (SELECT id, data FROM broadcasts ORDER BY id DESC LIMIT ?1) \
+                 ORDER BY id",
+            )?;
+            let mut rows = stmt.query(params![count as i64])?;
+            let mut ret = Vec::new();
+            while let Some(row) = rows.next()? {
+                let id: i64 = row.get(0)?;
+                let data: String = row.get(1)?;
+                let mut broadcast =
+                    decode::<Broadcast>(&format!("broadcasts[{id}]"), data.as_bytes())?;
+                broadcast.id = id as u64;
+                ret.push(broadcast);
+            }
+            Ok(ret)
+        })
+        .await
+    }
+
+    async fn deliveries(&self, id: u64) -> anyhow::Result<HashMap<i64, Delivery>> {
+        self.call(move |conn| {
+            let mut stmt =
+                conn.prepare("SELECT chat, data FROM deliveries WHERE broadcast = ?1")?;
+            let mut rows = stmt.query(params![id as i64])?;
+            let mut ret = HashMap::new();
+            while let Some(row) = rows.next()? {
+                let chat: i64 = row.get(0)?;
+                let data: String = row.get(1)?;
+                ret.insert(
+                    chat,
+                    decode(&format!("deliveries[{id}:{chat}]"), data.as_bytes())?,
+                );
+            }
+            Ok(ret)
+        })
+        .await
+    }
+
+    async fn set_delivery(&self, id: u64, chat: i64, delivery: &Delivery) -> anyhow::Result<usize> {
+        let pending = *delivery == Delivery::Pending;
+        let data = serde_json::to_string(delivery)?;
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT OR REPLACE INTO deliveries (broadcast, chat, pending, data) \
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![id as i64, chat, pending, data],
+            )?;
+            let left = tx.query_row(
+                "SELECT COUNT(*) FROM deliveries WHERE broadcast = ?1 AND pending = 1",
+                params![id as i64],
+                |row| row.get::<_, i64>(0),
+            )?;
+            tx.commit()?;
+            Ok(left as usize)
+        })
+        .await
+    }
+
+    async fn add_schedule(&self, schedule: &Schedule) -> anyhow::Result<u64> {
+        let data = serde_json::to_string(schedule)?;
+        self.call(move |conn| {
+            conn.execute("INSERT INTO schedules (data) VALUES (?1)", params![data])?;
+            Ok(conn.last_insert_rowid() as u64)
+        })
+        .await
+    }
+
+    async fn schedules(&self) -> anyhow::Result<Vec<Schedule>> {
+        self.call(|conn| {
+            let mut stmt = conn.prepare("SELECT id, data FROM schedules")?;
+            let mut rows = stmt.query([])?;
+            let mut ret = Vec::new();
+            while let Some(row) = rows.next()? {
+                let id: i64 = row.get(0)?;
+                let data: String = row.get(1)?;
+                let mut schedule =
+                    decode::<Schedule>(&format!("schedules[{id}]"), data.as_bytes())?;
+                schedule.id = id as u64;
+                ret.push(schedule);
+            }
+            Ok(ret)
+        })
+        .await
+    }
+
+    async fn remove_schedule(&self, id: u64) -> anyhow::Result<bool> {
+        self.call(move |conn| {
+            Ok(conn.execute("DELETE FROM schedules WHERE id = ?1", params![id as i64])? > 0)
+        })
+        .await
+    }
+
+    async fn log_audit(&self, entry: &AuditEntry) -> anyhow::Result<()> {
+        let data = serde_json::to_string(entry)?;
+        self.call(move |conn| {
+            conn.execute("INSERT INTO audit (data) VALUES (?1)", params![data])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn audit(&self, count: usize) -> anyhow::Result<Vec<AuditEntry>> {
+        self.call(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, data FROM (SELECT id, data FROM audit ORDER BY id DESC LIMIT ?1) \
+                 ORDER BY id",
+            )?;
+            Self::read_audit(stmt.query(params![count as u64])?)?
+                .into_iter()
+                .collect()
+        })
+        .await
+    }
+
+    async fn export(&self) -> anyhow::Result<Snapshot> {
+        self.call(|conn| {
+            let tx = conn.transaction()?;
+            let mut snapshot = Snapshot::new();
+            let mut skipped = 0;
+            snapshot.current_event = tx
+                .query_row(
+                    "SELECT value FROM settings WHERE key = ?1",
+                    params![CURRENT_EVENT],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            let mut stmt = tx.prepare(
+                "SELECT event_id FROM tasks UNION SELECT event_id FROM solves \
+                 UNION SELECT event_id FROM attempts UNION SELECT id FROM events",
+            )?;
+            let ids = stmt
+                .query_map([], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<String>, _>>()?;
+            for id in ids {
+                let mut data = Self::export_event(&tx, &id, &mut skipped)?;
+                data.event = tx
+                    .query_row(
+                        "SELECT data FROM events WHERE id = ?1",
+                        params![id],
+                        |row| row.get::<_, String>(0),
+                    )
+                    .optional()?
+                    .and_then(|x| {
+                        let event = decode::<Event>(&format!("event:{id}"), x.as_bytes());
+                        or_skip(event, &mut skipped)
+                    });
+                snapshot.events.insert(id, data);
+            }
+            let mut stmt = tx.prepare("SELECT user_id, data FROM users")?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let user_id: u64 = row.get(0)?;
+                let user =
+                    decode::<Vas3kUser>(&format!("user:{user_id}"), row.get_ref(1)?.as_bytes()?);
+                if let Some(user) = or_skip(user, &mut skipped) {
+                    snapshot.users.insert(user_id, user);
+                }
+            }
+            let mut stmt = tx.prepare("SELECT user_id, state FROM states")?;
+            snapshot.states = stmt
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<_, _>>()?;
+            let mut stmt = tx.prepare("SELECT user_id, message FROM contacts")?;
+            snapshot.contacts = stmt
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<_, _>>()?;
+            let mut stmt = tx.prepare("SELECT user_id, code FROM registrations")?;
+            snapshot.registrations = stmt
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<_, _>>()?;
+            for sanction in Self::read_sanctions(&tx)? {
+                let Some((user_id, sanction)) = or_skip(sanction, &mut skipped) else {
+                    continue;
+                };
+                snapshot
+                    .sanctions
+                    .entry(user_id)
+                    .or_default()
+                    .push(sanction);
+            }
+            let mut stmt = tx.prepare("SELECT id, data FROM audit ORDER BY id")?;
+            snapshot.audit = Self::read_audit(stmt.query([])?)?
+                .into_iter()
+                .filter_map(|x| or_skip(x, &mut skipped))
+                .collect();
+            snapshot.roles = Self::read_roles(&tx, false)?;
+            snapshot.config_roles = Self::read_roles(&tx, true)?;
+            let mut stmt = tx.prepare("SELECT code, data FROM invites")?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let code: String = row.get(0)?;
+                let invite =
+                    decode::<Invite>(&format!("invite:{code}"), row.get_ref(1)?.as_bytes()?);
+                if let Some(invite) = or_skip(invite, &mut skipped) {
+                    snapshot.invites.insert(code, invite);
+                }
+            }
+            let mut stmt = tx.prepare("SELECT id, data FROM outbox ORDER BY id")?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let id: u64 = row.get(0)?;
+                let message =
+                    decode::<Message>(&format!("outbox[{id}]"), row.get_ref(1)?.as_bytes()?);
+                if let Some(message) = or_skip(message, &mut skipped) {
+                    snapshot.outbox.insert(id, message);
+                }
+            }
+            let mut stmt = tx.prepare("SELECT id, data FROM dead_letters ORDER BY id")?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let id: u64 = row.get(0)?;
+                let letter = decode(&format!("dead_letters[{id}]"), row.get_ref(1)?.as_bytes()?);
+                snapshot.dead_letters.extend(or_skip(letter, &mut skipped));
+            }
+            let mut stmt = tx.prepare("SELECT chat FROM unreachable ORDER BY chat")?;
+            snapshot.unreachable = stmt
+                .query_map([], |row| row.get(0))?
+                .collect::<Result<_, _>>()?;
+            let mut stmt = tx.prepare("SELECT id, data FROM broadcasts")?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let id: u64 = row.get(0)?;
+                let broadcast =
+                    decode::<Broadcast>(&format!("broadcasts[{id}]"), row.get_ref(1)?.as_bytes()?);
+                let Some(mut broadcast) = or_skip(broadcast, &mut skipped) else {
+                    continue;
+                };
+                broadcast.id = id;
+                snapshot.broadcasts.insert(
+                    id,
+                    BroadcastSnapshot {
+                        broadcast,
+                        deliveries: BTreeMap::new(),
+                    },
+                );
+            }
+            let mut stmt = tx.prepare("SELECT broadcast, chat, data FROM deliveries")?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let id: u64 = row.get(0)?;
+                let chat: i64 = row.get(1)?;
+                let delivery = decode::<Delivery>(
+                    &format!("deliveries[{id}:{chat}]"),
+                    row.get_ref(2)?.as_bytes()?,
+                );
+                let Some(delivery) = or_skip(delivery, &mut skipped) else {
+                    continue;
+                };
+                if let Some(data) = snapshot.broadcasts.get_mut(&id) {
+                    data.deliveries.insert(chat, delivery);
+                }
+            }
+            let mut stmt = tx.prepare("SELECT id, data FROM schedules")?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let id: u64 = row.get(0)?;
+                let schedule =
+                    decode::<Schedule>(&format!("schedules[{id}]"), row.get_ref(1)?.as_bytes()?);
+                if let Some(mut schedule) = or_skip(schedule, &mut skipped) {
+                    schedule.id = id;
+                    snapshot.schedules.insert(id, schedule);
+                }
+            }
+            snapshot.skipped = skipped;
+            Ok(snapshot)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::FlagType;
+    use crate::storage::DEFAULT_EVENT;
+
+    /** File of its own per test, an in-memory database would be gone before the storage opens it **/
+    struct TempDb(String);
+
+    impl TempDb {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("v3k-ctf-bot-{}-{name}.sqlite", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            Self(path.to_string_lossy().into_owned())
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn unversioned_database_is_migrated() {
+        let db = TempDb::new("v0");
+        let task = Task {
+            name: String::from("A"),
+            flag: FlagType::Single(String::from("f1")),
+            hint: String::new(),
+            id: String::from("a"),
+            hidden: false,
+            hashed: false,
+        };
+        let attempt = Attempt {
+            user_id: 2,
+            time: 5,
+            kind: AttemptKind::Wrong,
+            task: None,
+            counted: true,
+        };
+        {
+            // tables of the first release, which set no user_version
+            let conn = Connection::open(&db.0).unwrap();
+            conn.execute_batch(SCHEMA_V1).unwrap();
+            conn.execute(
+                "INSERT INTO tasks (id, data) VALUES ('a', ?1)",
+                params![serde_json::to_string(&task).unwrap()],
+            )
+            .unwrap();
+            conn.execute("INSERT INTO flags (flag, task_id) VALUES ('f1', 'a')", [])
+                .unwrap();
+            conn.execute(
+                "INSERT INTO solves (user_id, task_id, time, ranked) VALUES (1, 'a', 10, 1)",
+                [],
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO attempts (user_id, data, wrong, counted) VALUES (2, ?1, 1, 1)",
+                params![serde_json::to_string(&attempt).unwrap()],
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO states (user_id, state) VALUES (1, 'contact')",
+                [],
+            )
+            .unwrap();
+        }
+        let storage = SqliteStorage::new(&db.0).await.unwrap();
+        let version: usize = storage
+            .call(|conn| Ok(conn.query_row("PRAGMA user_version", [], |row| row.get(0))?))
+            .await
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+        // everything from before events belongs to the default one
+        let stored = storage.get_task(DEFAULT_EVENT, "a").await.unwrap().unwrap();
+        assert_eq!(stored.name, "A");
+        assert_eq!(
+            storage
+                .task_by_flag(DEFAULT_EVENT, "f1")
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("a")
+        );
+        assert_eq!(storage.scores(DEFAULT_EVENT).await.unwrap(), [(1, 1)]);
+        let counters = storage.attempt_counters(DEFAULT_EVENT).await.unwrap();
+        assert_eq!((counters.attempts, counters.wrong), (1, vec![(2, 1)]));
+        assert_eq!(
+            storage.get_state(1).await.unwrap().as_deref(),
+            Some("contact")
+        );
+        // tables of later versions start empty and work
+        assert!(storage.roles().await.unwrap().is_empty());
+        storage
+            .enqueue_message(&Message::from((1, "a")))
+            .await
+            .unwrap();
+        // opening again finds nothing to do
+        drop(storage);
+        SqliteStorage::new(&db.0).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn queued_messages_get_their_chat() {
+        let db = TempDb::new("v11");
+        {
+            let mut conn = Connection::open(&db.0).unwrap();
+            for (i, migration) in MIGRATIONS.iter().enumerate().take(11) {
+                let tx = conn.transaction().unwrap();
+                tx.execute_batch(migration).unwrap();
+                tx.pragma_update(None, "user_version", i + 1).unwrap();
+                tx.commit().unwrap();
+            }
+            for chat in [1, 2, 1] {
+                conn.execute(
+                    "INSERT INTO outbox (data) VALUES (?1)",
+                    params![serde_json::to_string(&Message::from((chat, "a"))).unwrap()],
+                )
+                .unwrap();
+            }
+        }
+        let storage = SqliteStorage::new(&db.0).await.unwrap();
+        let pending = storage
+            .pending_messages(Priority::Interactive, 10, &HashSet::new())
+            .await
+            .unwrap();
+        assert_eq!(
+            pending.iter().map(|x| (x.0, x.1.chat)).collect::<Vec<_>>(),
+            [(1, 1), (2, 2)]
+        );
+    }
+}
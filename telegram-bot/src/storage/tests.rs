@@ -0,0 +1,415 @@
+/**
+Copyright 2025 Ivan Agarkov
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+**/
+use super::*;
+use crate::api::{AttemptKind, FlagType, SanctionKind, salt_fingerprint};
+use crate::snapshot;
+
+const EVENT: &str = "ctf";
+
+fn task(id: &str, flags: &[&str]) -> Task {
+    Task {
+        name: id.to_uppercase(),
+        flag: FlagType::Multi(flags.iter().map(|x| x.to_string()).collect()),
+        hint: String::new(),
+        id: String::from(id),
+        hidden: false,
+        hashed: false,
+    }
+}
+
+fn invite(code: &str, max_uses: u64, expires: u64) -> Invite {
+    Invite {
+        code: String::from(code),
+        max_uses,
+        uses: 0,
+        expires,
+        created: 1,
+        revoked: false,
+    }
+}
+
+async fn solves_rank_by_score_then_user(storage: &dyn Storage) {
+    for (user, task, time) in [(3, "a", 10), (2, "a", 20), (2, "b", 30), (1, "a", 5)] {
+        assert!(
+            storage
+                .add_solve(EVENT, user, task, time, true)
+                .await
+                .unwrap()
+        );
+    }
+    assert!(!storage.add_solve(EVENT, 1, "a", 50, true).await.unwrap());
+    // unranked solves count as solved but stay off the board
+    assert!(storage.add_solve(EVENT, 4, "a", 0, false).await.unwrap());
+    assert!(storage.is_solved(EVENT, 4, "a").await.unwrap());
+
+    assert_eq!(
+        storage.scores(EVENT).await.unwrap(),
+        [(2, 2), (1, 1), (3, 1)]
+    );
+    let none = HashSet::new();
+    assert_eq!(
+        storage.task_solves(EVENT, "a", &none).await.unwrap(),
+        (3, Some((1, 5)))
+    );
+    // first blood and the count pass over disqualified users
+    let skip = HashSet::from([1]);
+    assert_eq!(
+        storage.task_solves(EVENT, "a", &skip).await.unwrap(),
+        (2, Some((3, 10)))
+    );
+    assert_eq!(storage.scores("other").await.unwrap(), []);
+}
+
+async fn flags_follow_task_updates(storage: &dyn Storage) {
+    assert!(matches!(
+        storage
+            .create_task(EVENT, &task("a", &["f1", "f2"]))
+            .await
+            .unwrap(),
+        TaskWrite::Done
+    ));
+    assert!(matches!(
+        storage
+            .create_task(EVENT, &task("a", &["f3"]))
+            .await
+            .unwrap(),
+        TaskWrite::IdTaken
+    ));
+    assert!(matches!(
+        storage.create_task(EVENT, &task("b", &["f2"])).await.unwrap(),
+        TaskWrite::FlagTaken(flag, owner) if flag == "f2" && owner == "a"
+    ));
+    assert_eq!(
+        storage.task_by_flag(EVENT, "f1").await.unwrap().as_deref(),
+        Some("a")
+    );
+    assert_eq!(storage.task_by_flag("other", "f1").await.unwrap(), None);
+
+    storage
+        .update_task(EVENT, &task("a", &["f3"]))
+        .await
+        .unwrap();
+    assert_eq!(storage.task_by_flag(EVENT, "f1").await.unwrap(), None);
+    assert_eq!(
+        storage.task_by_flag(EVENT, "f3").await.unwrap().as_deref(),
+        Some("a")
+    );
+    storage.delete_task(EVENT, "a").await.unwrap();
+    assert_eq!(storage.task_by_flag(EVENT, "f3").await.unwrap(), None);
+}
+
+async fn invites_stop_at_their_limits(storage: &dyn Storage) {
+    assert!(storage.create_invite(&invite("two", 2, 0)).await.unwrap());
+    assert!(!storage.create_invite(&invite("two", 5, 0)).await.unwrap());
+    assert!(storage.create_invite(&invite("old", 0, 100)).await.unwrap());
+    let mut revoked = invite("revoked", 0, 0);
+    revoked.revoked = true;
+    assert!(storage.create_invite(&revoked).await.unwrap());
+
+    let redeem = |code: &'static str, user: u64| async move {
+        storage.redeem_invite(code, user, 50).await.unwrap()
+    };
+    assert!(matches!(redeem("two", 1).await, InviteRedeem::Done));
+    // joining again takes no use
+    assert!(matches!(redeem("two", 1).await, InviteRedeem::Done));
+    assert!(matches!(redeem("two", 2).await, InviteRedeem::Done));
+    assert!(matches!(redeem("two", 3).await, InviteRedeem::Exhausted));
+    assert!(matches!(redeem("nope", 3).await, InviteRedeem::Unknown));
+    assert!(matches!(redeem("revoked", 3).await, InviteRedeem::Revoked));
+    assert!(matches!(
+        storage.redeem_invite("old", 3, 100).await.unwrap(),
+        InviteRedeem::Expired
+    ));
+    assert!(matches!(redeem("old", 3).await, InviteRedeem::Done));
+
+    assert_eq!(storage.get_invite("two").await.unwrap().unwrap().uses, 2);
+    assert_eq!(
+        storage.registration(3).await.unwrap().as_deref(),
+        Some("old")
+    );
+    let mut users = storage.code_users("two").await.unwrap();
+    users.sort();
+    assert_eq!(users, [1, 2]);
+}
+
+async fn outbox_keeps_order_until_acked_or_buried(storage: &dyn Storage) {
+    let first = storage
+        .enqueue_message(&Message::from((1, "a")))
+        .await
+        .unwrap();
+    let second = storage
+        .enqueue_message(&Message::from((1, "b")))
+        .await
+        .unwrap();
+    let other = storage
+        .enqueue_message(&Message::from((2, "c")))
+        .await
+        .unwrap();
+    assert!(first < second && second < other);
+
+    let pending = |skip: &[i64]| {
+        let skip: HashSet<i64> = skip.iter().copied().collect();
+        async move {
+            storage
+                .pending_messages(Priority::Interactive, 10, &skip)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect::<Vec<u64>>()
+        }
+    };
+    assert_eq!(pending(&[]).await, [first, other]);
+    assert_eq!(pending(&[1]).await, [other]);
+    assert_eq!(
+        storage
+            .pending_messages(Priority::Bulk, 10, &HashSet::new())
+            .await
+            .unwrap()
+            .len(),
+        0
+    );
+
+    storage.ack_message(first).await.unwrap();
+    assert_eq!(pending(&[]).await, [second, other]);
+    let letter = DeadLetter {
+        id: second,
+        message: Message::from((1, "b")),
+        reason: String::from("rejected"),
+        time: 1,
+    };
+    storage.bury_message(&letter).await.unwrap();
+    assert_eq!(pending(&[]).await, [other]);
+    let letters = storage.dead_letters(10).await.unwrap();
+    assert_eq!(letters.len(), 1);
+    assert_eq!(
+        (letters[0].id, letters[0].message.text.as_str()),
+        (second, "b")
+    );
+    // IDs are not reused after the queue empties
+    storage.ack_message(other).await.unwrap();
+    assert!(
+        storage
+            .enqueue_message(&Message::from((1, "d")))
+            .await
+            .unwrap()
+            > other
+    );
+}
+
+/** Snapshot with the parts that have no fixed order sorted **/
+async fn export(storage: &dyn Storage) -> serde_json::Value {
+    let mut snapshot = storage.export().await.unwrap();
+    snapshot.created = 0;
+    for data in snapshot.events.values_mut() {
+        data.solves
+            .sort_by(|x, y| (x.user_id, &x.task_id).cmp(&(y.user_id, &y.task_id)));
+    }
+    serde_json::to_value(&snapshot).unwrap()
+}
+
+async fn export_import_round_trip(storage: &dyn Storage, copy: &dyn Storage) {
+    let event = Event {
+        id: String::from(EVENT),
+        name: String::from("CTF"),
+        start: 1,
+        end: 2,
+        ..Default::default()
+    };
+    storage.create_event(&event).await.unwrap();
+    storage.set_current_event(EVENT).await.unwrap();
+    storage
+        .create_task(EVENT, &task("a", &["f1"]))
+        .await
+        .unwrap();
+    storage.add_solve(EVENT, 1, "a", 10, true).await.unwrap();
+    storage.add_solve(EVENT, 2, "a", 0, false).await.unwrap();
+    storage
+        .log_attempt(
+            EVENT,
+            &Attempt {
+                user_id: 3,
+                time: 11,
+                kind: AttemptKind::Wrong,
+                task: None,
+                counted: true,
+            },
+        )
+        .await
+        .unwrap();
+    storage.set_state(1, "contact").await.unwrap();
+    storage.append_contact(1, "hello").await.unwrap();
+    storage.create_invite(&invite("code", 3, 0)).await.unwrap();
+    storage.redeem_invite("code", 1, 5).await.unwrap();
+    storage
+        .put_sanction(
+            3,
+            &Sanction {
+                kind: SanctionKind::Mute,
+                reason: String::from("spam"),
+                until: 0,
+                admin: 1,
+                time: 12,
+            },
+        )
+        .await
+        .unwrap();
+    storage.grant_role(-5, Role::Notify).await.unwrap();
+    storage.grant_role(4, Role::Tester).await.unwrap();
+    storage
+        .set_config_role(4, Role::Tester, true)
+        .await
+        .unwrap();
+    // only a granted role can be marked
+    storage.set_config_role(6, Role::Admin, true).await.unwrap();
+    let broadcast = Broadcast {
+        id: 0,
+        admin: 1,
+        text: String::from("news"),
+        time: 20,
+        segment: String::from("all"),
+        reported: 0,
+    };
+    let id = storage.create_broadcast(&broadcast, &[1, 2]).await.unwrap();
+    storage.set_delivery(id, 1, &Delivery::Sent).await.unwrap();
+    let mut message = Message::from((2, "news"));
+    message.broadcast = Some(id);
+    message.priority = Priority::Bulk;
+    storage.enqueue_message(&message).await.unwrap();
+    storage
+        .enqueue_message(&Message::from((1, "reply")))
+        .await
+        .unwrap();
+    let letter = DeadLetter {
+        id: 99,
+        message: Message::from((3, "lost")),
+        reason: String::from("blocked"),
+        time: 21,
+    };
+    storage.bury_message(&letter).await.unwrap();
+    storage.set_unreachable(3, true).await.unwrap();
+    let schedule = Schedule {
+        id: 0,
+        admin: 1,
+        time: 100,
+        segment: String::from("zero"),
+        text: String::from("later"),
+        created: 22,
+    };
+    storage.add_schedule(&schedule).await.unwrap();
+
+    let snapshot = storage.export().await.unwrap();
+    copy.import(&snapshot).await.unwrap();
+    assert_eq!(export(copy).await, export(storage).await);
+    assert_eq!(copy.scores(EVENT).await.unwrap(), [(1, 1)]);
+    assert_eq!(copy.config_roles().await.unwrap(), [(4, Role::Tester)]);
+    copy.revoke_role(4, Role::Tester).await.unwrap();
+    assert!(copy.config_roles().await.unwrap().is_empty());
+    assert_eq!(copy.attempt_counters(EVENT).await.unwrap().attempts, 1);
+    assert_eq!(copy.get_invite("code").await.unwrap().unwrap().uses, 1);
+    let pending = copy
+        .pending_messages(Priority::Bulk, 10, &HashSet::new())
+        .await
+        .unwrap();
+    assert_eq!(pending[0].1.broadcast, Some(id));
+    assert_eq!(copy.set_delivery(id, 2, &Delivery::Sent).await.unwrap(), 0);
+    assert_eq!(copy.schedules().await.unwrap()[0].text, "later");
+}
+
+async fn restore_needs_the_same_salt(storage: &dyn Storage) {
+    let mut snapshot = Snapshot::new();
+    snapshot.salt = Some(salt_fingerprint("salt"));
+    assert!(
+        snapshot::restore(storage, &snapshot, "pepper")
+            .await
+            .is_err()
+    );
+    snapshot::restore(storage, &snapshot, "salt").await.unwrap();
+}
+
+/** One test per check, each on a store of its own **/
+macro_rules! contract_tests {
+    ($backend:ident, $open:expr) => {
+        mod $backend {
+            use super::*;
+
+            #[tokio::test]
+            async fn solves_rank_by_score_then_user() {
+                super::solves_rank_by_score_then_user(&$open).await;
+            }
+
+            #[tokio::test]
+            async fn flags_follow_task_updates() {
+                super::flags_follow_task_updates(&$open).await;
+            }
+
+            #[tokio::test]
+            async fn invites_stop_at_their_limits() {
+                super::invites_stop_at_their_limits(&$open).await;
+            }
+
+            #[tokio::test]
+            async fn outbox_keeps_order_until_acked_or_buried() {
+                super::outbox_keeps_order_until_acked_or_buried(&$open).await;
+            }
+
+            #[tokio::test]
+            async fn export_import_round_trip() {
+                super::export_import_round_trip(&$open, &$open).await;
+            }
+
+            #[tokio::test]
+            async fn restore_needs_the_same_salt() {
+                super::restore_needs_the_same_salt(&$open).await;
+            }
+        }
+    };
+}
+
+contract_tests!(memory, MemoryStorage::default());
+contract_tests!(sqlite, SqliteStorage::new(":memory:").await.unwrap());
+
+/** Empties the database behind `url` and opens the storage on it **/
+async fn open_redis(url: &str) -> RedisStorage {
+    let client = ::redis::Client::open(url).unwrap();
+    let mut conn = client.get_multiplexed_async_connection().await.unwrap();
+    ::redis::cmd("FLUSHDB")
+        .query_async::<()>(&mut conn)
+        .await
+        .unwrap();
+    let config = RedisConfig {
+        url: String::from(url),
+        ..Default::default()
+    };
+    RedisStorage::new(&config).await.unwrap()
+}
+
+/** The checks share one database, so they run one after another in a single test **/
+#[tokio::test]
+#[ignore = "wipes the Redis database in REDIS_TEST_URL"]
+async fn redis() {
+    let url = std::env::var("REDIS_TEST_URL").expect("REDIS_TEST_URL is not set");
+    solves_rank_by_score_then_user(&open_redis(&url).await).await;
+    flags_follow_task_updates(&open_redis(&url).await).await;
+    invites_stop_at_their_limits(&open_redis(&url).await).await;
+    outbox_keeps_order_until_acked_or_buried(&open_redis(&url).await).await;
+    restore_needs_the_same_salt(&open_redis(&url).await).await;
+    // a second empty store is at hand only in another backend, so both ways go through one
+    let copy = MemoryStorage::default();
+    export_import_round_trip(&open_redis(&url).await, &copy).await;
+    let storage = MemoryStorage::default();
+    export_import_round_trip(&storage, &open_redis(&url).await).await;
+}
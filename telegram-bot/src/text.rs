@@ -13,15 +13,21 @@ WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 See the License for the specific language governing permissions and
 limitations under the License.
 **/
-use crate::api::{FlagType, Task, Vas3kUser};
+use crate::api::{
+    AuditEntry, Broadcast, DeliveryStats, Event, FlagType, Invite, Role, Sanction, SanctionKind,
+    Schedule, Segment, Stats, Task, Vas3kUser, unix_now,
+};
+use crate::sender::DeadLetter;
+use chrono::DateTime;
 use std::fmt::Display;
+use teloxide::utils::html;
 
 pub const HELP_TEXT: &str = r"
 Привет!
 
 Это бот для Вастрик.Кемпа в Либерленде!
 
-Он показывает задания (/tasks), правила (/rules) и твой счёт (/score).
+Он показывает задания (/tasks), правила (/rules), твой счёт (/score) и статистику (/stats).
 Ты всегда можешь написать (/contact) оргам и что-то спросить.
 
 Ответом на каждое задания является флаг: ключевое слово, набор букв и цифр или чего-то ещё.
@@ -45,8 +51,30 @@ pub const CONTACT_TEXT: &str = r"Напиши своё сообщение. Ил
 Всё, что ты напишешь, будет отправлено организаторам AS IS. Допускается только текст.
 Когда закончишь писать - поставь точку (.) отдельным сообщением";
 
-pub const MESSAGE_TEXT: &str = r"Напиши своё сообщение. Всё, что ты напишешь, будет отправлено всем участникам (кто ходил в бота) AS IS.
-Когда закончишь писать - поставь точку (.) отдельным сообщением";
+pub const SEGMENT_TEXT: &str = r"Кому отправить? Напиши один из сегментов:
+all - все, кто ходил в бота
+zero - без решений в текущем событии
+unsolved <ID задания> - не решившие задание в текущем событии
+top <N> - первые N в таблице с хотя бы одним решением
+testers - только тестеры";
+
+pub const MESSAGE_TEXT: &str = r"Напиши своё сообщение. Всё, что ты напишешь, будет отправлено получателям AS IS.
+Когда закончишь писать - поставь точку (.) отдельным сообщением, перед отправкой будет предпросмотр";
+
+pub const EMPTY_BROADCAST: &str = r"Сообщение пустое, рассылка отменена";
+
+pub const BROADCAST_SEND: &str = r"Отправить";
+
+pub const BROADCAST_CANCEL: &str = r"Отмена";
+
+pub const SCHEDULE_TEXT: &str = r"Отправь в 3+ строки одним сообщением:
+1. Время отправки: unixtime, +N минут, ЧЧ:ММ или ГГГГ-ММ-ДД ЧЧ:ММ по UTC
+2. Сегмент: all, zero, unsolved <ID задания>, top <N> или testers
+3+. Сообщение";
+
+pub const NO_SCHEDULES: &str = r"Запланированных рассылок нет";
+
+pub const BROADCAST_CANCELLED: &str = r"Рассылка отменена, ничего не отправлено";
 
 pub const CODE_TEXT: &str = r"К сожалению, код бота сейчас недоступен";
 
@@ -64,7 +92,64 @@ pub const MESSAGE_SENT: &str = r"Ваше сообщение было отпра
 pub const CREATE_TASK: &str = r"Отправь задание в 3+ строки одним сообщением:
 1. Название
 2. Флаг
-3. Описание";
+3. Описание
+Флаги хранятся только в виде хэшей, при редактировании [прежние] вместо флага оставит прежние";
+
+/** Flag line of an edit that keeps the stored hashes, refused as a flag of its own **/
+pub const KEEP_FLAGS: &str = "[прежние]";
+
+pub const REVEAL_FLAG: &str = r"Отправь флаг, бот скажет, к какому заданию он подходит";
+
+pub const REFRESH_USER: &str =
+    r"Отправь Telegram ID участника, его профиль будет заново загружен с vas3k.club";
+
+pub const NOT_A_USER_ID: &str = r"Это не Telegram ID";
+
+pub const INVALID_CODE: &str = r"Такого кода приглашения нет";
+
+pub const JOINED: &str = r"Добро пожаловать! Теперь можно решать задания, начни с /help";
+
+pub const CREATE_INVITE: &str = r"Отправь приглашение в 2-3 строки одним сообщением:
+1. Сколько раз можно использовать, 0 - без ограничений
+2. До какого времени (unixtime), 0 - бессрочно
+3. Код, необязательно";
+
+pub const SANCTION_TEXT: &str = r"Отправь наказание в 2-3 строки одним сообщением:
+1. Telegram ID участника
+2. Причина
+3. До какого времени (unixtime), необязательно - навсегда";
+
+pub const LIFT_TEXT: &str = r"Отправь в 3 строки одним сообщением:
+1. Telegram ID участника
+2. ban, mute или disqualify
+3. Причина";
+
+pub const NO_SANCTIONS: &str = r"Действующих наказаний нет";
+
+pub const NO_AUDIT: &str = r"Журнал действий пуст";
+
+pub const ROLE_TEXT: &str = r"Отправь в 2 строки одним сообщением:
+1. Telegram ID пользователя или чата (отрицательный)
+2. admin, moderator, tester или notify";
+
+pub const NO_ROLES: &str = r"Ролей нет";
+
+/** Characters of a message shown by /undelivered and /broadcasts **/
+const MESSAGE_PREVIEW: usize = 100;
+
+pub const NO_DEAD_LETTERS: &str = r"Недоставленных сообщений нет";
+
+pub const NO_BROADCASTS: &str = r"Рассылок ещё не было";
+
+pub const REPLY_TEXT: &str = r"Отправь ответ в 2+ строки одним сообщением:
+1. Telegram ID участника, он есть в его сообщении
+2. Ответ";
+
+pub const CHOOSE_INVITE: &str = r"Выбери код приглашения:";
+
+pub const NO_INVITES: &str = r"Подходящих кодов приглашения нет";
+
+pub const FLAG_NOT_FOUND: &str = r"Этот флаг не подходит ни к одному заданию";
 
 pub const ALREADY_SOLVED: &str = r"Это задание уже решено!";
 
@@ -73,9 +158,37 @@ pub const ALL_SOLVED: &str =
 
 pub const CHOOSE: &str = r"Выбери задание:";
 
+pub const NO_STATS: &str = r"Статистики пока нет";
+
+pub const CREATE_EVENT: &str = r"Отправь событие в 4 строки одним сообщением:
+1. ID (a-z, 0-9 и -)
+2. Название
+3. Начало (unixtime)
+4. Конец (unixtime)";
+
+pub const EVENT_END_TEXT: &str =
+    r"Отправь новый конец текущего события: unixtime, или +N / -N минут от нынешнего";
+
+pub const PAUSED: &str =
+    r"Игра на паузе, флаги сейчас не принимаются. Задания по-прежнему в /tasks";
+
+pub const EVENT_PAUSED: &str =
+    r"<b>Игра на паузе!</b> Флаги пока не принимаются, задания по-прежнему в /tasks";
+
+pub const EVENT_RESUMED: &str = r"<b>Пауза закончилась!</b> Флаги снова принимаются";
+
+pub const UPSTREAM_RECOVERED: &str = r"vas3k.club снова отвечает";
+
+pub const CHOOSE_EVENT: &str = r"Выбери событие:";
+
+pub const NO_EVENTS: &str = r"Подходящих событий нет";
+
 pub const CONFIG_NAME: &str = r"config.json";
 
 pub const VAR_NAME: &str = r"BOTFLAG";
+
+/** What /code sends **/
+pub const CODE_PATH: &str = r"src/main.rs";
 pub struct Format(());
 
 impl Format {
@@ -113,14 +226,16 @@ impl Format {
         )
     }
 
-    pub fn format_task_admin(task: &Task) -> String {
-        let flag = match task.flag {
-            FlagType::Single(ref s) => s.clone(),
-            FlagType::Multi(ref vs) => vs.join(","),
+    /** Flags are known only right after the admin typed them, otherwise `KEEP_FLAGS` keeps the stored ones **/
+    pub fn format_task_admin(task: &Task, flag: Option<&FlagType>) -> String {
+        let flag = match flag {
+            Some(FlagType::Single(s)) => s.clone(),
+            Some(FlagType::Multi(vs)) => vs.join(","),
+            None => String::from(KEEP_FLAGS),
         };
         let prefix = if task.hidden { "hidden:" } else { "" };
         format!(
-            r"Старые поля задания:
+            r"Поля задания:
 <code>
 {prefix}{}
 {}
@@ -143,6 +258,322 @@ impl Format {
         format!(r"Задание <b>{name}</b> было изменено")
     }
 
+    pub fn format_user_refreshed(user: &Vas3kUser) -> String {
+        let status = if user.is_member(unix_now()) {
+            "членство активно"
+        } else {
+            "членство неактивно"
+        };
+        format!(r"Профиль {user} обновлён: {status}")
+    }
+
+    pub fn format_invite(invite: &Invite, users: &[String]) -> String {
+        let limit = if invite.max_uses == 0 {
+            String::from("∞")
+        } else {
+            invite.max_uses.to_string()
+        };
+        let expires = if invite.expires == 0 {
+            String::from("бессрочно")
+        } else {
+            format!("до {}", invite.expires)
+        };
+        let status = if invite.revoked {
+            " <b>отозван</b>"
+        } else {
+            ""
+        };
+        let users = if users.is_empty() {
+            String::from("никто")
+        } else {
+            users.join(", ")
+        };
+        format!(
+            "<code>{}</code>{status}: {}/{limit}, {expires}\nВоспользовались: {users}\n",
+            invite.code, invite.uses
+        )
+    }
+
+    pub fn format_invite_created(invite: &Invite) -> String {
+        format!(
+            r"Код приглашения создан: <code>/join {}</code>, или ссылка на бота с ?start={}",
+            invite.code, invite.code
+        )
+    }
+
+    pub fn format_invite_revoked(invite: &Invite) -> String {
+        format!(r"Код приглашения <code>{}</code> отозван", invite.code)
+    }
+
+    fn format_until(until: u64) -> String {
+        if until == 0 {
+            String::from("навсегда")
+        } else {
+            format!("до {until}")
+        }
+    }
+
+    fn format_sanction_kind(kind: SanctionKind) -> &'static str {
+        match kind {
+            SanctionKind::Ban => "блокировка",
+            SanctionKind::Mute => "запрет на сообщения",
+            SanctionKind::Disqualify => "дисквалификация",
+        }
+    }
+
+    pub fn format_sanction(user: &str, sanction: &Sanction) -> String {
+        format!(
+            "{user}: <b>{}</b> {}, {}\n",
+            Self::format_sanction_kind(sanction.kind),
+            Self::format_until(sanction.until),
+            sanction.reason
+        )
+    }
+
+    pub fn format_sanctioned(user_id: u64, sanction: &Sanction) -> String {
+        format!(
+            r"Участнику {user_id} назначено: <b>{}</b> {}",
+            Self::format_sanction_kind(sanction.kind),
+            Self::format_until(sanction.until)
+        )
+    }
+
+    pub fn format_lifted(user_id: u64, kind: SanctionKind) -> String {
+        format!(
+            r"С участника {user_id} снято: <b>{}</b>",
+            Self::format_sanction_kind(kind)
+        )
+    }
+
+    /** What the user is told about a sanction put on them **/
+    pub fn format_sanction_user(sanction: &Sanction) -> String {
+        format!(
+            r"Администратор назначил тебе: <b>{}</b> {}. Причина: {}",
+            Self::format_sanction_kind(sanction.kind),
+            Self::format_until(sanction.until),
+            sanction.reason
+        )
+    }
+
+    pub fn format_muted(sanction: &Sanction) -> String {
+        format!(
+            r"Отправка сообщений тебе запрещена {}. Причина: {}",
+            Self::format_until(sanction.until),
+            sanction.reason
+        )
+    }
+
+    pub fn format_role(role: Role, user: &str) -> String {
+        format!("{}: {user}\n", role.as_str())
+    }
+
+    pub fn format_role_granted(id: i64, role: Role) -> String {
+        format!(r"Роль <b>{}</b> выдана {id}", role.as_str())
+    }
+
+    pub fn format_role_revoked(id: i64, role: Role) -> String {
+        format!(r"Роль <b>{}</b> отозвана у {id}", role.as_str())
+    }
+
+    pub fn format_reply(text: &str) -> String {
+        format!(
+            r"<b>Ответ организаторов</b>:
+{text}"
+        )
+    }
+
+    pub fn format_replied(user_id: u64) -> String {
+        format!(r"Ответ отправлен участнику {user_id}")
+    }
+
+    pub fn format_config_change(name: &str, old: &str, new: &str, restart: bool) -> String {
+        if restart {
+            format!(r"<b>{name}</b>: {old} → {new}, вступит в силу после перезапуска")
+        } else {
+            format!(r"<b>{name}</b>: {old} → {new}")
+        }
+    }
+
+    pub fn format_event_times_kept(event: &Event) -> String {
+        format!(
+            r"Время события <b>{}</b> меняли вручную, новые event_start и event_end к нему не применены",
+            event.id
+        )
+    }
+
+    pub fn format_config_hidden(name: &str, restart: bool) -> String {
+        if restart {
+            format!(r"<b>{name}</b>: изменено, вступит в силу после перезапуска")
+        } else {
+            format!(r"<b>{name}</b>: изменено")
+        }
+    }
+
+    pub fn format_config_reloaded(changes: &[String]) -> String {
+        if changes.is_empty() {
+            String::from(r"Настройки перечитаны, изменений нет")
+        } else {
+            format!(
+                r"Настройки перечитаны:
+{}",
+                changes.join("\n")
+            )
+        }
+    }
+
+    pub fn format_message_prompt(segment: &Segment, count: usize) -> String {
+        format!(
+            r"Сегмент {segment}, получателей сейчас: {count}.
+{MESSAGE_TEXT}"
+        )
+    }
+
+    pub fn format_broadcast_confirm(segment: &Segment, count: usize) -> String {
+        format!(
+            r"Выше сообщение так, как его увидят получатели. Сегмент {segment}, получателей: {count}. Отправить?"
+        )
+    }
+
+    pub fn format_scheduled(schedule: &Schedule) -> String {
+        format!(
+            r"Рассылка запланирована на {} (#{}), сегмент {}. Отменить: /unschedule_{}",
+            Self::format_time(schedule.time),
+            schedule.id,
+            schedule.segment,
+            schedule.id
+        )
+    }
+
+    pub fn format_schedule(schedule: &Schedule) -> String {
+        let text: String = schedule.text.chars().take(MESSAGE_PREVIEW).collect();
+        format!(
+            "#{} {} [{}] {} /unschedule_{}\n<i>{}</i>\n",
+            schedule.id,
+            Self::format_time(schedule.time),
+            schedule.admin,
+            schedule.segment,
+            schedule.id,
+            html::escape(&text)
+        )
+    }
+
+    pub fn format_unscheduled(id: u64) -> String {
+        format!(r"Запланированная рассылка #{id} отменена")
+    }
+
+    pub fn format_schedule_sent(
+        schedule: &Schedule,
+        broadcast: &Broadcast,
+        total: usize,
+    ) -> String {
+        format!(
+            r"Запланированная рассылка #{} отправлена как рассылка #{}, получателей: {total}",
+            schedule.id, broadcast.id
+        )
+    }
+
+    pub fn format_schedule_failed<E: Display>(schedule: &Schedule, error: E) -> String {
+        format!(
+            r"Запланированная рассылка #{} не отправлена: {error}",
+            schedule.id
+        )
+    }
+
+    pub fn format_broadcast_queued(broadcast: &Broadcast, total: usize) -> String {
+        format!(
+            r"Рассылка #{} поставлена в очередь, получателей: {total}. Отчёт придёт, когда все сообщения будут доставлены или отклонены",
+            broadcast.id
+        )
+    }
+
+    fn format_delivery_stats(stats: &DeliveryStats) -> String {
+        format!(
+            r"доставлено {}, в очереди {}, не доставлено {}",
+            stats.sent,
+            stats.pending,
+            stats.failed_total()
+        )
+    }
+
+    pub fn format_broadcast_report(broadcast: &Broadcast, stats: &DeliveryStats) -> String {
+        let mut ret = format!(
+            r"<b>Рассылка #{} завершена</b>: {}",
+            broadcast.id,
+            Self::format_delivery_stats(stats)
+        );
+        for (reason, count) in stats.failed.iter() {
+            ret.push_str(&format!("\n{count} - {}", html::escape(reason)));
+        }
+        if !stats.failed.is_empty() {
+            ret.push_str(&format!(
+                "\n\nОтправить недоставленные ещё раз: /retry_{}",
+                broadcast.id
+            ));
+        }
+        ret
+    }
+
+    pub fn format_broadcast(broadcast: &Broadcast, stats: &DeliveryStats) -> String {
+        let text: String = broadcast.text.chars().take(MESSAGE_PREVIEW).collect();
+        let retry = if stats.failed.is_empty() {
+            String::new()
+        } else {
+            format!(" /retry_{}", broadcast.id)
+        };
+        format!(
+            "#{} {} [{}] {}: {}{retry}\n<i>{}</i>\n",
+            broadcast.id,
+            broadcast.time,
+            broadcast.admin,
+            if broadcast.segment.is_empty() {
+                "all"
+            } else {
+                &broadcast.segment
+            },
+            Self::format_delivery_stats(stats),
+            html::escape(&text)
+        )
+    }
+
+    pub fn format_broadcast_retried(id: u64, count: usize, skipped: usize) -> String {
+        let mut ret = format!(
+            r"Рассылка #{id}: {count} недоставленных сообщений снова в очереди, отчёт придёт по готовности"
+        );
+        if skipped > 0 {
+            ret.push_str(&format!(r", пропущено недоступных чатов: {skipped}"));
+        }
+        ret
+    }
+
+    pub fn format_dead_letter(chat: &str, letter: &DeadLetter) -> String {
+        let text: String = letter.message.text.chars().take(MESSAGE_PREVIEW).collect();
+        format!(
+            "{} {chat}: {}\n<i>{}</i>\n",
+            letter.time,
+            html::escape(&letter.reason),
+            html::escape(&text)
+        )
+    }
+
+    pub fn format_audit(user: &str, entry: &AuditEntry) -> String {
+        let until = if entry.until == 0 {
+            String::new()
+        } else {
+            format!(" до {}", entry.until)
+        };
+        format!(
+            "{} [{}] {} {user}{until}: {}\n",
+            entry.time, entry.admin, entry.action, entry.reason
+        )
+    }
+
+    pub fn format_flag_revealed(task: &Task) -> String {
+        format!(
+            r"Флаг подходит к заданию <b>{}</b> (task:{})",
+            task.name, task.id
+        )
+    }
+
     pub fn format_created(name: &str) -> String {
         format!(r"Задание <b>{name}</b> было создано")
     }
@@ -154,17 +585,17 @@ impl Format {
         )
     }
 
-    pub fn format_message(from: &str, message: &str, task: Option<&str>) -> String {
+    pub fn format_message(from: &str, user_id: u64, message: &str, task: Option<&str>) -> String {
         match task {
             None => format!(
-                r"<b>Сообщение от @{from}</b>:
+                r"<b>Сообщение от @{from}</b> [<code>{user_id}</code>]:
 
 {message}
 ",
             ),
             Some(task) => {
                 format!(
-                    r"<b>Сообщение от @{from} по поводу задания <i>{task}</i></b>:
+                    r"<b>Сообщение от @{from} по поводу задания <i>{task}</i></b> [<code>{user_id}</code>]:
 
 {message}
 ",
@@ -176,7 +607,157 @@ impl Format {
         format!(r"Возникла ошибка: {error}")
     }
 
+    pub fn format_storage_error<E: Display>(what: &str, error: E) -> String {
+        format!(r"Ошибка хранилища ({what}): {error}")
+    }
+
+    pub fn format_upstream_degraded<E: Display>(error: E, cooldown: u64) -> String {
+        format!(
+            r"vas3k.club не отвечает ({error}), следующая попытка через {cooldown} с. Пока пускаем по сохранённым профилям"
+        )
+    }
+
+    pub fn format_event(event: &Event, current: bool) -> String {
+        let mark = if current {
+            r" (текущее)"
+        } else if event.archived {
+            r" (архив)"
+        } else if event.paused {
+            r" (пауза)"
+        } else {
+            ""
+        };
+        format!(
+            "<b>{}</b> [{}]{mark}: {} - {}\n",
+            event.name, event.id, event.start, event.end
+        )
+    }
+
+    pub fn format_event_created(event: &Event) -> String {
+        format!(r"Событие {} ({}) создано", event.name, event.id)
+    }
+
+    pub fn format_event_switched(event: &Event) -> String {
+        format!(r"Текущее событие: {} ({})", event.name, event.id)
+    }
+
+    pub fn format_event_archived(event: &Event) -> String {
+        format!(r"Событие {} ({}) отправлено в архив", event.name, event.id)
+    }
+
+    /** Participants get UTC dates rather than unixtime **/
+    fn format_time(time: u64) -> String {
+        match DateTime::from_timestamp(time as i64, 0) {
+            Some(time) => time.format("%d.%m %H:%M UTC").to_string(),
+            None => time.to_string(),
+        }
+    }
+
+    pub fn format_event_started(event: &Event) -> String {
+        format!(
+            r"<b>Игра началась!</b> Задания в /tasks, конец {}",
+            Self::format_time(event.end)
+        )
+    }
+
+    pub fn format_event_end_changed(event: &Event, old: u64) -> String {
+        if event.end > old {
+            format!(r"<b>Игра продлена</b> до {}", Self::format_time(event.end))
+        } else {
+            format!(
+                r"<b>Игра закончится раньше</b>: {}",
+                Self::format_time(event.end)
+            )
+        }
+    }
+
+    pub fn format_event_board(event: &Event) -> String {
+        format!("<b>{}</b>\n", event.name)
+    }
+
+    pub fn format_snapshot<P: Display>(path: P, skipped: usize) -> String {
+        if skipped > 0 {
+            format!(
+                r"Снапшот сохранён в {path}, не прочитались и пропущены записей: {skipped}, подробности в логе"
+            )
+        } else {
+            format!(r"Снапшот сохранён в {path}")
+        }
+    }
+
     pub fn format_solved_admin<S1: Display, S2: Display>(user: S1, task: S2) -> String {
         format!(r"Пользователь {user} решил задачу {task}")
     }
+
+    fn since_start(start: u64, time: u64) -> String {
+        if time < start {
+            return String::from(r"до старта");
+        }
+        let minutes = (time - start) / 60;
+        format!(r"через {}ч {}м после старта", minutes / 60, minutes % 60)
+    }
+
+    pub fn format_stats(stats: &Stats) -> String {
+        let mut ret = format!(
+            r"<b>Статистика</b> (участников: {})
+",
+            stats.participants
+        );
+        for task in stats.tasks.iter().filter(|x| !x.task.hidden) {
+            ret.push_str(&format!(
+                r"
+<b>{}</b> - решили {} ({}%)",
+                task.task.name,
+                task.solves,
+                task.percent(stats.participants)
+            ));
+            if let Some(ref first) = task.first_blood {
+                ret.push_str(&format!(r", первая кровь: {}", first.user));
+            }
+        }
+        ret
+    }
+
+    pub fn format_stats_admin(stats: &Stats, event_start: u64) -> String {
+        let mut ret = format!(
+            r"<b>Статистика</b>
+Участников: {}
+Попыток: {}, неверных: {}
+",
+            stats.participants, stats.attempts, stats.wrong_attempts
+        );
+        for task in stats.tasks.iter() {
+            let prefix = if task.task.hidden { "hidden:" } else { "" };
+            ret.push_str(&format!(
+                r"
+<b>{prefix}{}</b> ({}) - решили {} ({}%)",
+                task.task.name,
+                task.task.id,
+                task.solves,
+                task.percent(stats.participants)
+            ));
+            if let Some(ref first) = task.first_blood {
+                ret.push_str(&format!(
+                    r"
+  первая кровь: {}, {}",
+                    first.user,
+                    Self::since_start(event_start, first.time)
+                ));
+            }
+        }
+        if !stats.top_wrong.is_empty() {
+            ret.push_str(
+                r"
+
+<b>Больше всего неверных флагов</b>:",
+            );
+            for (user, count) in stats.top_wrong.iter() {
+                ret.push_str(&format!(
+                    r"
+{user} - {count}"
+                ));
+            }
+        }
+        ret
+    }
 }
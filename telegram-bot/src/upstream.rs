@@ -0,0 +1,120 @@
+/**
+Copyright 2025 Ivan Agarkov
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+**/
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/** Entries are pruned once the cache grows past this **/
+const NEGATIVE_PRUNE: usize = 1024;
+
+/** What a breaker call changed, the notify group hears about both **/
+#[derive(Debug, PartialEq, Eq)]
+pub enum Transition {
+    None,
+    Opened,
+    Closed,
+}
+
+#[derive(Default)]
+struct BreakerState {
+    failures: u32,
+    /** Unixtime until no requests go out, 0 while closed **/
+    open_until: u64,
+}
+
+/** Stops calling an upstream for `cooldown` seconds after `threshold` failures in a row, one more failure reopens it **/
+#[derive(Default)]
+pub struct Breaker {
+    state: Mutex<BreakerState>,
+}
+
+impl Breaker {
+    pub fn allow(&self, now: u64) -> bool {
+        self.state
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .open_until
+            <= now
+    }
+
+    /** Seconds until requests go out again, 0 if they do **/
+    pub fn retry_in(&self, now: u64) -> u64 {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.open_until.saturating_sub(now)
+    }
+
+    pub fn success(&self) -> Transition {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let was_open = state.open_until != 0;
+        *state = BreakerState::default();
+        if was_open {
+            Transition::Closed
+        } else {
+            Transition::None
+        }
+    }
+
+    pub fn failure(&self, now: u64, threshold: u32, cooldown: u64) -> Transition {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.failures = state.failures.saturating_add(1);
+        if state.failures < threshold.max(1) {
+            return Transition::None;
+        }
+        let was_open = state.open_until != 0;
+        state.open_until = now.saturating_add(cooldown);
+        if was_open {
+            Transition::None
+        } else {
+            Transition::Opened
+        }
+    }
+}
+
+/** Remembers failed lookups for a while, so repeated messages do not hit the upstream **/
+pub struct NegativeCache<V> {
+    entries: Mutex<HashMap<u64, (u64, V)>>,
+}
+
+impl<V: Clone> NegativeCache<V> {
+    pub fn new() -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, key: u64, now: u64) -> Option<V> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.get(&key).filter(|x| x.0 > now).map(|x| x.1.clone())
+    }
+
+    /** A ttl of 0 keeps nothing **/
+    pub fn insert(&self, key: u64, value: V, now: u64, ttl: u64) {
+        if ttl == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.len() >= NEGATIVE_PRUNE {
+            entries.retain(|_, x| x.0 > now);
+        }
+        entries.insert(key, (now.saturating_add(ttl), value));
+    }
+
+    pub fn remove(&self, key: u64) {
+        self.entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&key);
+    }
+}